@@ -4,7 +4,7 @@ extern crate gym_rs;
 
 use cge::encoding::{Metadata, WithRecurrentState};
 use eant2::eant2::EANT2;
-use eant2::options::{EANT2Termination, Exploration};
+use eant2::options::{EANT2Termination, Exploration, FitnessSimilarity};
 use eant2::{Activation, FitnessFunction, Network, NetworkView};
 use gym_rs::{ActionType, CartPoleEnv, GifRender, GymEnv};
 
@@ -91,6 +91,8 @@ fn main() {
         .activation(Activation::Tanh)
         .exploration(
             Exploration::builder()
+                // The rewards go up to a thousand, so an absolute threshold does not fit
+                .similarity(FitnessSimilarity::Relative(0.05))
                 .terminate(EANT2Termination::builder().fitness(0.01).build())
                 .build(),
         )
@@ -5,7 +5,7 @@ extern crate eant2;
 
 use cge::encoding::{Metadata, WithRecurrentState};
 use eant2::eant2::EANT2;
-use eant2::options::{EANT2Termination, Exploration};
+use eant2::options::{Constraints, EANT2Termination, Exploration};
 use eant2::{Activation, FitnessFunction, NetworkView};
 
 #[derive(Clone)]
@@ -20,13 +20,14 @@ impl FitnessFunction for Xor {
             ([1.0, 1.0], 0.0),
         ];
 
+        // Recurrent connections are disabled, so the network cannot cheat by remembering previous
+        // evaluations
+        assert!(network.is_stateless());
+
         let mut fitness = 0.0;
 
         for (input, expected_output) in data {
             let result = network.evaluate(&input).unwrap()[0];
-            // Clear the state between each evaluation to prevent cheating using recurrent
-            // connections
-            network.clear_state();
             fitness += (result - expected_output).abs();
         }
 
@@ -44,6 +45,7 @@ fn main() {
         .exploration(
             Exploration::builder()
                 .terminate(EANT2Termination::builder().fitness(0.01).build())
+                .constraints(Constraints::builder().feedforward().build())
                 .build(),
         )
         .build();
@@ -0,0 +1,275 @@
+//! Canonical forms of CGE networks, used to detect structurally equivalent networks regardless of
+//! their neuron IDs and gene order.
+
+// The neurons of a CGE network form a forest whose roots are the outputs in a fixed order. Jumper
+// connections add further edges between neurons, and input and bias genes are leaves with fixed
+// labels (the input ID, or simply "bias").
+//
+// The canonical form of the neuron structure alone is computed with the classic tree canonization
+// algorithm: the form of each neuron is built from the sorted forms of its children. This is exact.
+//
+// For the full structure, each neuron is first labeled with the form of its subtree including
+// inputs and biases. The labels are then iteratively refined with the labels of the neurons each
+// neuron is connected to through jumpers (in either direction), as well as of its parent. The
+// children of each neuron are sorted by these labels, which yields a numbering of all neurons in
+// preorder, and the network is serialized with jumpers referring to these numbers. Because the
+// serialization describes the complete structure, equal forms always imply equivalent networks. If
+// sibling neurons cannot be told apart by their labels but are not interchangeable, their order is
+// arbitrary, so equivalent networks may rarely receive different forms. This only happens for
+// unusually symmetric structures, and it can only cause equivalent networks to be treated as
+// different, never the opposite.
+
+use cge::gene::{Gene, NeuronId};
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::hash::{Hash, Hasher};
+
+use crate::cge_utils::{output_ids, Network};
+use crate::utils;
+
+/// The canonical forms of a network.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CanonicalForm {
+    /// The form of the neuron structure, ignoring all connections other than between neurons and
+    /// their parents.
+    pub neurons: String,
+    /// The form of the complete structure.
+    pub structure: String,
+}
+
+/// The direct children of a neuron, with the neuron IDs and input IDs they refer to.
+#[derive(Default)]
+struct Children {
+    neurons: Vec<NeuronId>,
+    inputs: Vec<usize>,
+    biases: usize,
+    forward: Vec<NeuronId>,
+    recurrent: Vec<NeuronId>,
+}
+
+/// Returns the canonical forms of the network. If `ignore_below` is set, non-neuron genes whose
+/// weight has an absolute value of at most it are ignored.
+pub fn canonical_form(network: &Network, ignore_below: Option<f64>) -> CanonicalForm {
+    let outputs = output_ids(network);
+    let ignored = |weight: f64| ignore_below.is_some_and(|epsilon| weight.abs() <= epsilon);
+
+    let mut children: HashMap<NeuronId, Children> = HashMap::new();
+    let mut parents: HashMap<NeuronId, NeuronId> = HashMap::new();
+    for id in network.neuron_ids() {
+        let mut c = Children::default();
+        for g in utils::get_direct_children(network, id) {
+            match g {
+                Gene::Neuron(neuron) => {
+                    c.neurons.push(neuron.id());
+                    parents.insert(neuron.id(), id);
+                }
+                _ if ignored(g.weight()) => {}
+                Gene::Input(input) => c.inputs.push(input.id().as_usize()),
+                Gene::Bias(_) => c.biases += 1,
+                Gene::ForwardJumper(forward) => c.forward.push(forward.source_id()),
+                Gene::RecurrentJumper(recurrent) => c.recurrent.push(recurrent.source_id()),
+            }
+        }
+        c.inputs.sort_unstable();
+        children.insert(id, c);
+    }
+
+    // The canonical form of the neuron structure
+    let neurons = outputs
+        .iter()
+        .map(|id| neuron_tree(&children, *id))
+        .collect::<Vec<_>>()
+        .join("|");
+
+    // Initial labels from the subtrees, including inputs and biases
+    let mut labels = HashMap::new();
+    for (i, id) in outputs.iter().enumerate() {
+        subtree_labels(&children, *id, i, &mut labels);
+    }
+
+    // Refine the labels with the jumper connections and parents
+    let mut users: HashMap<NeuronId, (Vec<NeuronId>, Vec<NeuronId>)> = HashMap::new();
+    for (id, c) in &children {
+        for source in &c.forward {
+            users.entry(*source).or_default().0.push(*id);
+        }
+        for source in &c.recurrent {
+            users.entry(*source).or_default().1.push(*id);
+        }
+    }
+
+    let mut distinct = count_distinct(&labels);
+    for _ in 0..children.len() {
+        let sorted = |ids: &[NeuronId], labels: &HashMap<NeuronId, u64>| {
+            let mut sorted = ids.iter().map(|id| labels[id]).collect::<Vec<_>>();
+            sorted.sort_unstable();
+            sorted
+        };
+
+        let refined = labels
+            .iter()
+            .map(|(id, label)| {
+                let c = &children[id];
+                let (forward_users, recurrent_users) = users.get(id).cloned().unwrap_or_default();
+                let key = (
+                    label,
+                    sorted(&c.forward, &labels),
+                    sorted(&c.recurrent, &labels),
+                    sorted(&forward_users, &labels),
+                    sorted(&recurrent_users, &labels),
+                    parents.get(id).map(|parent| labels[parent]),
+                );
+                (*id, hash(&key))
+            })
+            .collect::<HashMap<_, _>>();
+
+        labels = refined;
+        let new_distinct = count_distinct(&labels);
+        if new_distinct == distinct {
+            break;
+        }
+        distinct = new_distinct;
+    }
+
+    // Number the neurons in preorder, visiting children in the order of their labels
+    let mut order = Vec::with_capacity(children.len());
+    let mut stack = outputs.iter().rev().copied().collect::<Vec<_>>();
+    while let Some(id) = stack.pop() {
+        order.push(id);
+        let mut sorted = children[&id].neurons.clone();
+        sorted.sort_by_key(|child| std::cmp::Reverse(labels[child]));
+        stack.extend(sorted);
+    }
+    let numbers = order
+        .iter()
+        .enumerate()
+        .map(|(i, id)| (*id, i))
+        .collect::<HashMap<_, _>>();
+
+    // Serialize the complete structure with the canonical numbers
+    let mut structure = String::new();
+    for id in &order {
+        let c = &children[id];
+        let numbered = |ids: &[NeuronId]| {
+            let mut numbered = ids.iter().map(|id| numbers[id]).collect::<Vec<_>>();
+            numbered.sort_unstable();
+            numbered
+        };
+
+        let parent = parents.get(id).map(|parent| numbers[parent]);
+        write!(
+            structure,
+            "{:?}:{:?}:{}:{:?}:{:?};",
+            parent,
+            c.inputs,
+            c.biases,
+            numbered(&c.forward),
+            numbered(&c.recurrent),
+        )
+        .unwrap();
+    }
+
+    CanonicalForm { neurons, structure }
+}
+
+/// Returns the canonical form of the neuron subtree rooted at `id`.
+fn neuron_tree(children: &HashMap<NeuronId, Children>, id: NeuronId) -> String {
+    let mut forms = children[&id]
+        .neurons
+        .iter()
+        .map(|child| neuron_tree(children, *child))
+        .collect::<Vec<_>>();
+    forms.sort_unstable();
+    format!("({})", forms.concat())
+}
+
+/// Labels each neuron in the subtree rooted at `id` with a hash of the subtree including its
+/// inputs and biases. Output neurons are also labeled with their position. Returns the label of
+/// `id`.
+fn subtree_labels(
+    children: &HashMap<NeuronId, Children>,
+    id: NeuronId,
+    position: usize,
+    labels: &mut HashMap<NeuronId, u64>,
+) -> u64 {
+    let c = &children[&id];
+    let mut child_labels = c
+        .neurons
+        .iter()
+        .map(|child| subtree_labels(children, *child, usize::MAX, labels))
+        .collect::<Vec<_>>();
+    child_labels.sort_unstable();
+
+    let label = hash(&(position, &c.inputs, c.biases, child_labels));
+    labels.insert(id, label);
+    label
+}
+
+fn count_distinct(labels: &HashMap<NeuronId, u64>) -> usize {
+    labels.values().collect::<HashSet<_>>().len()
+}
+
+fn hash<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use cge::gene::{ForwardJumper, Input, InputId, Neuron, RecurrentJumper};
+    use cge::Activation;
+
+    use super::*;
+
+    #[test]
+    fn test_canonical_form_ignores_ids_and_order() {
+        let a = vec![
+            Neuron::new(NeuronId::new(0), 3, 1.0).into(),
+            Input::new(InputId::new(0), 1.0).into(),
+            Neuron::new(NeuronId::new(1), 2, 1.0).into(),
+            Input::new(InputId::new(1), 1.0).into(),
+            RecurrentJumper::new(NeuronId::new(0), 1.0).into(),
+            Neuron::new(NeuronId::new(2), 1, 1.0).into(),
+            Input::new(InputId::new(0), 1.0).into(),
+            Neuron::new(NeuronId::new(3), 2, 1.0).into(),
+            ForwardJumper::new(NeuronId::new(2), 1.0).into(),
+            Input::new(InputId::new(1), 0.0).into(),
+        ];
+        // The same structure with different IDs and child order
+        let b = vec![
+            Neuron::new(NeuronId::new(0), 3, 1.0).into(),
+            Neuron::new(NeuronId::new(7), 1, 1.0).into(),
+            Input::new(InputId::new(0), 1.0).into(),
+            Neuron::new(NeuronId::new(5), 2, 1.0).into(),
+            RecurrentJumper::new(NeuronId::new(0), 1.0).into(),
+            Input::new(InputId::new(1), 1.0).into(),
+            Input::new(InputId::new(0), 1.0).into(),
+            Neuron::new(NeuronId::new(4), 1, 1.0).into(),
+            ForwardJumper::new(NeuronId::new(7), 1.0).into(),
+        ];
+        // The forward jumper points to a different neuron
+        let c = vec![
+            Neuron::new(NeuronId::new(0), 3, 1.0).into(),
+            Input::new(InputId::new(0), 1.0).into(),
+            Neuron::new(NeuronId::new(1), 2, 1.0).into(),
+            Input::new(InputId::new(1), 1.0).into(),
+            RecurrentJumper::new(NeuronId::new(0), 1.0).into(),
+            Neuron::new(NeuronId::new(2), 1, 1.0).into(),
+            Input::new(InputId::new(0), 1.0).into(),
+            Neuron::new(NeuronId::new(3), 1, 1.0).into(),
+            ForwardJumper::new(NeuronId::new(1), 1.0).into(),
+        ];
+        let [a, b, c] = [a, b, c].map(|genome| Network::new(genome, Activation::Tanh).unwrap());
+
+        assert_ne!(canonical_form(&a, None), canonical_form(&b, None));
+        assert_eq!(canonical_form(&a, Some(0.0)), canonical_form(&b, None));
+        assert_eq!(
+            canonical_form(&b, None).neurons,
+            canonical_form(&c, None).neurons
+        );
+        assert_ne!(canonical_form(&b, None), canonical_form(&c, None));
+    }
+}
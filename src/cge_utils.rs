@@ -1,8 +1,12 @@
 //! Utilities for working with CGE networks
 
+use cge::gene::{Bias, Gene, Neuron, NeuronId};
 use cge::network::{MismatchedLengthsError, NotEnoughInputsError};
+use rand::Rng;
 
-use std::ops::Deref;
+use std::ops::{Deref, Range};
+
+use crate::options::WeightInit;
 
 /// The initial value for the weight of any new gene in a network.
 pub const INITIAL_WEIGHT_VALUE: f64 = 1.0;
@@ -33,6 +37,12 @@ impl<'a> NetworkView<'a> {
         self.0.recurrent_state_len()
     }
 
+    /// Returns whether the network has no recurrent connections, in which case its outputs depend
+    /// only on the current inputs and its state never needs to be cleared.
+    pub fn is_stateless(&self) -> bool {
+        is_stateless(self.0)
+    }
+
     /// See [`Network::recurrent_state`][Network::recurrent_state].
     pub fn recurrent_state(&mut self) -> impl Iterator<Item = f64> + '_ {
         self.0.recurrent_state()
@@ -57,3 +67,172 @@ impl<'a> Deref for NetworkView<'a> {
         self.0
     }
 }
+
+/// Returns the IDs of the output neurons of the network in output order, which is the reverse of
+/// their order in the genome.
+pub fn output_ids(network: &Network) -> Vec<NeuronId> {
+    network
+        .genome()
+        .iter()
+        .zip(network.parents())
+        .filter(|(_, parent)| parent.is_none())
+        .map(|(gene, _)| gene.as_neuron().unwrap().id())
+        .rev()
+        .collect()
+}
+
+/// Initializes the weights of the genes in the given index range of the genome according to
+/// `init`. `mutation` is whether the genes were just added by a mutation.
+pub fn initialize_weights<R: Rng>(
+    network: &mut Network,
+    range: Range<usize>,
+    init: &WeightInit,
+    mutation: bool,
+    rng: &mut R,
+) {
+    let fan_ins = fan_ins(network, range.clone());
+    let weights = network.mut_weights().skip(range.start).take(range.len());
+    for (weight, fan_in) in weights.zip(fan_ins) {
+        *weight = init.sample(rng, fan_in, mutation);
+    }
+}
+
+/// Returns the fan-in of each gene in the given index range of the genome, which is the number of
+/// inputs of its parent neuron, or one for output neurons.
+fn fan_ins(network: &Network, range: Range<usize>) -> Vec<usize> {
+    range
+        .map(|i| match network.parents()[i] {
+            Some(parent) => network[network[parent].subgenome_range().start]
+                .as_neuron()
+                .unwrap()
+                .num_inputs(),
+            None => 1,
+        })
+        .collect()
+}
+
+/// Returns whether the network has no recurrent jumper genes.
+pub fn is_stateless(network: &Network) -> bool {
+    network.genome().iter().all(|g| !g.is_recurrent_jumper())
+}
+
+/// Rebuilds a sequence of complete subgenomes gene by gene. Each gene is passed through `map`
+/// together with its tag, which may return a modified gene or `None` to drop it. Only non-neuron
+/// genes may be dropped. The input counts of neurons are updated to account for dropped genes, and
+/// neurons left without any inputs receive a zero-weight bias so that the result stays valid.
+///
+/// `tags` holds a value for each gene (e.g., its age) that is carried along with it. `filler` is
+/// used as the tag of any inserted bias genes.
+pub fn map_genome<A: Clone>(
+    genome: &[Gene<f64>],
+    tags: &[A],
+    filler: A,
+    mut map: impl FnMut(&Gene<f64>, &A) -> Option<Gene<f64>>,
+) -> (Vec<Gene<f64>>, Vec<A>) {
+    // A neuron whose inputs are still being read
+    struct Frame {
+        // The index of the neuron in the output genome
+        index: usize,
+        // The number of inputs of the original neuron that have not been read yet
+        remaining: usize,
+        // The number of inputs that were kept
+        kept: usize,
+    }
+
+    let mut new_genome = Vec::with_capacity(genome.len());
+    let mut new_tags = Vec::with_capacity(genome.len());
+    let mut stack: Vec<Frame> = Vec::new();
+
+    for (gene, tag) in genome.iter().zip(tags) {
+        let mapped = map(gene, tag);
+
+        if let Gene::Neuron(neuron) = gene {
+            let mapped = mapped.expect("neuron genes cannot be dropped");
+            stack.push(Frame {
+                index: new_genome.len(),
+                remaining: neuron.num_inputs(),
+                kept: 0,
+            });
+            new_genome.push(mapped);
+            new_tags.push(tag.clone());
+            continue;
+        }
+
+        let kept = mapped.is_some();
+        if let Some(mapped) = mapped {
+            new_genome.push(mapped);
+            new_tags.push(tag.clone());
+        }
+
+        // Finish every subgenome that ends with this gene
+        let mut child_kept = kept;
+        while let Some(frame) = stack.last_mut() {
+            frame.remaining -= 1;
+            if child_kept {
+                frame.kept += 1;
+            }
+
+            if frame.remaining > 0 {
+                break;
+            }
+
+            let frame = stack.pop().unwrap();
+            let neuron = new_genome[frame.index].as_neuron().unwrap().clone();
+            let mut num_inputs = frame.kept;
+
+            if num_inputs == 0 {
+                new_genome.push(Bias::new(0.0).into());
+                new_tags.push(filler.clone());
+                num_inputs = 1;
+            }
+
+            new_genome[frame.index] = Neuron::new(neuron.id(), num_inputs, neuron.weight()).into();
+            // A finished neuron always counts as a kept input of its own parent
+            child_kept = true;
+        }
+    }
+
+    (new_genome, new_tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use cge::gene::{Input, InputId};
+    use cge::Activation;
+    use rand::thread_rng;
+
+    use super::*;
+
+    #[test]
+    fn test_fan_ins() {
+        let genome = vec![
+            Neuron::new(NeuronId::new(0), 3, 1.0).into(),
+            Neuron::new(NeuronId::new(1), 2, 1.0).into(),
+            Input::new(InputId::new(0), 1.0).into(),
+            Input::new(InputId::new(1), 1.0).into(),
+            Input::new(InputId::new(0), 1.0).into(),
+            Bias::new(1.0).into(),
+        ];
+        let mut network = Network::new(genome, Activation::Linear).unwrap();
+        assert_eq!(fan_ins(&network, 0..6), vec![1, 3, 2, 2, 3, 3]);
+
+        // New genes count towards the fan-in of their parent, as they are initialized after being
+        // added
+        network
+            .add_non_neuron(NeuronId::new(1), Bias::new(0.0))
+            .unwrap();
+        let index = network
+            .parents()
+            .iter()
+            .rposition(|p| *p == Some(NeuronId::new(1)));
+        assert_eq!(
+            fan_ins(&network, index.unwrap()..index.unwrap() + 1),
+            vec![3]
+        );
+
+        let len = network.len();
+        let init = WeightInit::Constant(0.5);
+        initialize_weights(&mut network, 0..len, &init, true, &mut thread_rng());
+        assert!(network.weights().all(|w| w == 0.5));
+    }
+}
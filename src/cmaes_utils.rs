@@ -1,86 +1,361 @@
+use crate::cge_utils::Network;
 use crate::eant2::EANT2;
+use crate::gradient;
+use crate::optimizer::Problem;
+use crate::options::{FineTuningMode, WarmStart};
 use crate::utils::Individual;
 use crate::FitnessFunction;
-use cmaes::objective_function::Scale;
-use cmaes::restart::{RestartOptions, Restarter};
 use cmaes::*;
+use nalgebra::DMatrix;
+
+/// The final search distribution of a CMA-ES run, which later runs can continue from.
+#[derive(Clone, Debug)]
+pub struct SearchDistribution {
+    /// The step size.
+    pub step_size: f64,
+    /// The covariance matrix of the weights, which is scaled by the square of the step size.
+    pub covariance: DMatrix<f64>,
+    /// For each gene of the genome, its row and column in `covariance`, or `None` for genes that
+    /// were added since.
+    pub genes: Vec<Option<usize>>,
+}
+
+impl SearchDistribution {
+    /// Returns the covariance matrix of the current genes. New genes are uncorrelated with the
+    /// others and have a variance of `new_gene_variance` after scaling by the step size.
+    fn covariance(&self, new_gene_variance: f64) -> DMatrix<f64> {
+        let n = self.genes.len();
+        DMatrix::from_fn(n, n, |i, j| match (self.genes[i], self.genes[j]) {
+            (Some(a), Some(b)) => self.covariance[(a, b)],
+            (None, None) if i == j => new_gene_variance / (self.step_size * self.step_size),
+            _ => 0.0,
+        })
+    }
+
+    /// Returns whether runs can continue from the distribution. Runs that diverged may leave a step
+    /// size or covariance matrix that is not finite.
+    fn is_valid(&self) -> bool {
+        self.step_size.is_finite()
+            && self.step_size > 0.0
+            && self.covariance.iter().all(|v| v.is_finite())
+    }
+}
 
 /// The structural __exploitation__ phase of the algorithm,
 /// where the parameters of each neural topology (created in the structural __exploration__ phase) are optimized.
+/// If parsimony pressure is enabled during optimization, `parsimony_coefficient` times the
+/// complexity of the network is added to the objective, but the fitness of the individual is always
+/// stored without it.
+/// `max_evaluations` further limits the number of fitness evaluations if set.
+/// If warm starting is enabled, a single CMA-ES run continues from the search distribution of the
+/// individual instead.
+/// If fine-tuning is enabled and the fitness function provides a dataset, the weights are then
+/// fine-tuned with gradients, or only fine-tuned if it replaces the optimizer.
 // TODO: amortize allocations!
 // TODO: Nice API design to specify termination conditions + restart strategies
-pub fn optimize_network<T>(individual: &mut Individual<T>, options: &EANT2)
-where
+pub fn optimize_network<T>(
+    individual: &mut Individual<T>,
+    options: &EANT2,
+    parsimony_coefficient: f64,
+    max_evaluations: Option<usize>,
+) where
     T: 'static + FitnessFunction + Clone + Send + Sync,
 {
-    // g' = 1 / (1 + (g^2))
     // used to restrict the search space weights as the gene ages (this is supposed to encourage better convergence)
     let gene_deviations: Vec<f64> = individual
-    .ages
-    .iter()
-    .map(|&g| g * g) // integer math more performant
-    .map(|g| 1 + g) // integer math more performant
-    .map(|g| 1.0 / g as f64) // now we cannot use integer math, use the floating point instructions
-    .collect();
+        .ages
+        .iter()
+        .map(|&age| options.exploitation.deviation.deviation(age))
+        .collect();
+
+    // the complexity penalty added to the objective (zero unless enabled)
+    let penalize = options
+        .exploration
+        .parsimony
+        .is_some_and(|p| p.during_optimization);
+    let penalty = |network: &Network| {
+        if penalize {
+            parsimony_coefficient * options.exploration.complexity.measure(network)
+        } else {
+            0.0
+        }
+    };
+    let previous_penalty = penalty(&individual.network);
 
     // TODO: amortize allocation
     let initial_mean = DVector::from(individual.network.weights().collect::<Vec<f64>>());
+    let max_evals = options
+        .exploitation
+        .terminate
+        .evaluations
+        .into_iter()
+        .chain(max_evaluations)
+        .min();
 
-    // TODO: find an ergonomic way to optionally specify termination conditions, restarter, etc. while also capturing (statically in the type system!)
-    //       that there is a minimum amount of information that must be provided to prevent infinite looping.
-    let best = {
-        // TODO: avoid these clones if possible.
-        let parameter_count = individual.network.len();
-        let scaled = Scale::new(
-            |x: &DVector<f64>| individual.evaluate(&(x + &initial_mean)),
-            gene_deviations.clone(),
-        );
+    // fine-tuning only applies if the fitness function provides a dataset
+    let object = individual.object.clone();
+    let fine_tuning = options
+        .exploitation
+        .fine_tuning
+        .as_ref()
+        .and_then(|fine_tuning| Some((fine_tuning, object.dataset()?)));
+    let replace =
+        fine_tuning.is_some_and(|(fine_tuning, _)| fine_tuning.mode == FineTuningMode::Replace);
 
-        let mut restart_options = RestartOptions::new(parameter_count, -1.0..=1.0, options.exploitation.restart.clone())
-          .mode(Mode::Minimize)                  // minimize the fitness function
-          .fun_target(options.exploration.terminate.fitness); // don't optimize beyond the EANT2 fitness
+    let (mut best_weights, mut best_value) = if replace {
+        (initial_mean.as_slice().to_vec(), f64::INFINITY)
+    } else if let Some(warm_start) = &options.exploitation.warm_start {
+        optimize_warm(
+            individual,
+            options,
+            warm_start,
+            &initial_mean,
+            &gene_deviations,
+            &penalty,
+            max_evals,
+        )
+    } else {
+        let problem = Problem {
+            initial: initial_mean.as_slice(),
+            deviations: &gene_deviations,
+            // don't optimize beyond the EANT2 fitness
+            target: options.exploration.terminate.fitness,
+            max_evaluations: max_evals,
+            exploitation: &options.exploitation,
+        };
+        options
+            .exploitation
+            .optimizer
+            .optimize(&problem, &mut |weights| {
+                let fitness = individual.eval(weights);
+                fitness + penalty(&individual.network)
+            })
+    };
 
-        if let Some(max_gens) = options.exploitation.terminate.generations {
-            restart_options = restart_options.max_generations_per_run(max_gens);
+    if let Some((fine_tuning, dataset)) = fine_tuning {
+        let tuned = gradient::fine_tune(
+            &individual.network,
+            dataset,
+            fine_tuning,
+            &best_weights,
+            &gene_deviations,
+        );
+        let value = individual.eval(&tuned) + penalty(&individual.network);
+        if value < best_value {
+            best_weights = tuned;
+            best_value = value;
         }
-        if let Some(max_evals) = options.exploitation.terminate.evaluations {
-            restart_options = restart_options.max_function_evals(max_evals);
+    }
+
+    commit(
+        individual,
+        options,
+        previous_penalty,
+        &initial_mean,
+        &best_weights,
+        best_value,
+        &penalty,
+    );
+}
+
+/// Runs a single CMA-ES run that continues from the search distribution of the individual, or
+/// starts from a distribution given by the gene ages if it has no valid one, and stores its final
+/// distribution in the individual. Returns the best weights found and their objective value.
+fn optimize_warm<T>(
+    individual: &mut Individual<T>,
+    options: &EANT2,
+    warm_start: &WarmStart,
+    initial_mean: &DVector<f64>,
+    gene_deviations: &[f64],
+    penalty: &dyn Fn(&Network) -> f64,
+    max_evals: Option<usize>,
+) -> (Vec<f64>, f64)
+where
+    T: 'static + FitnessFunction + Clone + Send + Sync,
+{
+    let n = initial_mean.len();
+    let (step_size, covariance) = match individual.search.as_ref().filter(|s| s.is_valid()) {
+        Some(search) => (
+            search.step_size,
+            search.covariance(warm_start.new_gene_variance),
+        ),
+        None => {
+            let variances = gene_deviations.iter().map(|d| d * d);
+            (
+                warm_start.step_size,
+                DMatrix::from_diagonal(&DVector::from_iterator(n, variances)),
+            )
         }
+    };
 
-        // run the CMA-ES optimization pass
-        Restarter::new(restart_options)
-          .unwrap()
-          .run_with_reuse(scaled)
-          .best
-          .expect("CMA-ES optimization failed, this is likely the result of FitnessFunction returning f64::NAN")
+    // CMA-ES searches in coordinates in which the distribution is isotropic, which are mapped to
+    // the weights by the Cholesky factor of the covariance matrix
+    let transform = match covariance.clone().cholesky() {
+        Some(cholesky) => cholesky.l(),
+        None => DMatrix::from_diagonal(&covariance.diagonal().map(|v| v.max(0.0).sqrt())),
     };
 
-    // extract the best parameters
-    let best_parameters = &best.point;
+    let mut cmaes_options = CMAESOptions::new(DVector::zeros(n), step_size)
+        .mode(Mode::Minimize)
+        .fun_target(options.exploration.terminate.fitness);
+    if let Some(max_gens) = options.exploitation.terminate.generations {
+        cmaes_options = cmaes_options.max_generations(max_gens);
+    }
+    if let Some(max_evals) = max_evals {
+        cmaes_options = cmaes_options.max_function_evals(max_evals);
+    }
 
-    // Commit to the new parameters if the fitness value improved or the individual has not been
-    // evaluated yet
-    let use_new_parameters = individual.fitness.is_none()
-        || best.value < individual.fitness.unwrap();
+    let (best, step_size, covariance) = {
+        let objective = |y: &DVector<f64>| {
+            let fitness = individual.evaluate(&(initial_mean + &transform * y));
+            fitness + penalty(&individual.network)
+        };
+        // The step size is positive and finite, as both the options and the reused distribution
+        // are validated
+        let mut cmaes = cmaes_options.build(objective).unwrap();
+        let best = cmaes
+            .run()
+            .overall_best
+            .expect("CMA-ES optimization failed, this is likely the result of FitnessFunction returning f64::NAN");
+        let covariance = &transform * cmaes.covariance_matrix() * transform.transpose();
+        (best, cmaes.sigma(), covariance)
+    };
+
+    individual.search = Some(SearchDistribution {
+        step_size,
+        covariance,
+        genes: (0..n).map(Some).collect(),
+    });
+
+    let best_weights = initial_mean + &transform * &best.point;
+    (best_weights.as_slice().to_vec(), best.value)
+}
+
+/// Commits to the best weights if their objective value improved on the fitness of the individual
+/// or the individual has not been evaluated yet, and restores the initial weights otherwise.
+/// The ages of genes whose weights changed by more than `Exploitation::age_reset` are reset.
+fn commit<T>(
+    individual: &mut Individual<T>,
+    options: &EANT2,
+    previous_penalty: f64,
+    initial_mean: &DVector<f64>,
+    best_weights: &[f64],
+    best_value: f64,
+    penalty: &dyn Fn(&Network) -> f64,
+) where
+    T: FitnessFunction + Clone,
+{
+    let use_new_parameters =
+        individual.fitness.is_none() || best_value < individual.fitness.unwrap() + previous_penalty;
 
     if use_new_parameters {
-        // the returned value does not have parameter scaling applied, so we do that here!
-        individual
-            .network
-            .mut_weights()
-            .zip(initial_mean.iter())
-            .zip(best_parameters.iter())
-            .zip(gene_deviations.iter())
-            .for_each(|(((weight, initial), &new_weight), &scale)| {
-                *weight = initial + (new_weight * scale)
-            });
-
-        // update the fitness of the network (with its new parameters)
-        individual.fitness = Some(best.value);
+        individual.network.set_weights(best_weights).unwrap();
+
+        if let Some(threshold) = options.exploitation.age_reset {
+            for ((age, initial), new_weight) in individual
+                .ages
+                .iter_mut()
+                .zip(initial_mean.iter())
+                .zip(best_weights)
+            {
+                if (new_weight - initial).abs() > threshold {
+                    *age = 0;
+                }
+            }
+        }
+
+        // update the fitness of the network (with its new parameters), without the penalty
+        individual.fitness = Some(best_value - penalty(&individual.network));
     } else {
         // Otherwise, go back to the original parameters
         // This is necessary because the network's parameters are modified during evaluation to
         // avoid allocations
-        individual.network.set_weights(initial_mean.as_slice()).unwrap();
+        individual
+            .network
+            .set_weights(initial_mean.as_slice())
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cge::gene::{Input, InputId, Neuron, NeuronId};
+    use cge::Activation;
+
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::cge_utils::NetworkView;
+    use crate::options::Exploitation;
+
+    /// The squared distance of the output for an input of one from `0.5`.
+    #[derive(Clone)]
+    struct Target;
+
+    impl FitnessFunction for Target {
+        fn fitness(&self, mut network: NetworkView) -> f64 {
+            (network.evaluate(&[1.0]).unwrap()[0] - 0.5).powi(2)
+        }
+    }
+
+    #[test]
+    fn test_covariance() {
+        let search = SearchDistribution {
+            step_size: 2.0,
+            covariance: DMatrix::from_row_slice(2, 2, &[1.0, 0.5, 0.5, 3.0]),
+            genes: vec![Some(1), None, Some(0)],
+        };
+        // The existing genes keep their covariances in the new order, and the new gene is
+        // uncorrelated with a variance of one after scaling by the step size
+        let expected =
+            DMatrix::from_row_slice(3, 3, &[3.0, 0.0, 0.5, 0.0, 0.25, 0.0, 0.5, 0.0, 1.0]);
+        assert_eq!(search.covariance(1.0), expected);
+    }
+
+    #[test]
+    fn test_optimize_warm() {
+        let genome = vec![
+            Neuron::new(NeuronId::new(0), 1, 1.0).into(),
+            Input::new(InputId::new(0), 1.0).into(),
+        ];
+        let network = Network::new(genome, Activation::Linear).unwrap();
+        let options = EANT2::builder()
+            .inputs(1)
+            .outputs(1)
+            .exploitation(
+                Exploitation::builder()
+                    .warm_start(WarmStart::builder().build())
+                    .build(),
+            )
+            .build();
+
+        // A diverged distribution is not reused, and a new one is stored for the current genes
+        let diverged = [f64::NAN, f64::INFINITY, 0.0].map(|step_size| SearchDistribution {
+            step_size,
+            covariance: DMatrix::identity(2, 2),
+            genes: vec![Some(0), Some(1)],
+        });
+        let nan_covariance = SearchDistribution {
+            step_size: 1.0,
+            covariance: DMatrix::from_element(2, 2, f64::NAN),
+            genes: vec![Some(0), Some(1)],
+        };
+        for search in diverged.into_iter().chain([nan_covariance]) {
+            let mut individual = Individual::new(1, 1, network.clone(), Arc::new(Target));
+            individual.search = Some(search);
+            optimize_network(&mut individual, &options, 0.0, Some(200));
+
+            assert!(individual.fitness.unwrap() < 0.25);
+            let search = individual.search.unwrap();
+            assert!(search.is_valid());
+            assert_eq!(search.covariance.shape(), (2, 2));
+            assert_eq!(search.genes, vec![Some(0), Some(1)]);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "step_size")]
+    fn test_invalid_step_size() {
+        WarmStart::builder().step_size(0.0).build();
     }
 }
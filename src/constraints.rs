@@ -0,0 +1,279 @@
+//! Checking networks and mutations against the structural constraints in `Constraints`.
+
+use cge::gene::{Gene, NeuronId};
+
+use std::collections::{HashMap, HashSet};
+
+use crate::cge_utils::{is_stateless, output_ids, Network};
+use crate::options::Constraints;
+use crate::utils;
+
+/// The structural constraints applied to a specific network, used to check whether mutations of it
+/// are valid.
+pub struct Checker<'a> {
+    constraints: &'a Constraints,
+    network: &'a Network,
+    /// For each neuron, the indices of the outputs whose values depend on it. Only calculated if a
+    /// connection mask is set.
+    owners: HashMap<NeuronId, HashSet<usize>>,
+    /// For each neuron, the IDs of the network inputs its value depends on. Only calculated if a
+    /// connection mask is set.
+    reach: HashMap<NeuronId, HashSet<usize>>,
+}
+
+impl<'a> Checker<'a> {
+    pub fn new(constraints: &'a Constraints, network: &'a Network) -> Self {
+        let mut owners: HashMap<NeuronId, HashSet<usize>> = HashMap::new();
+        let mut reach = HashMap::new();
+
+        if constraints.connections.is_some() {
+            for id in network.neuron_ids() {
+                let dependencies = dependencies(network, id);
+                let inputs = dependencies
+                    .iter()
+                    .flat_map(|id| utils::get_direct_children(network, *id))
+                    .filter_map(|g| g.as_input().map(|input| input.id().as_usize()))
+                    .collect();
+                reach.insert(id, inputs);
+            }
+
+            for (output, id) in output_ids(network).into_iter().enumerate() {
+                for dependency in dependencies(network, id) {
+                    owners.entry(dependency).or_default().insert(output);
+                }
+            }
+        }
+
+        Self {
+            constraints,
+            network,
+            owners,
+            reach,
+        }
+    }
+
+    /// Returns the number of genes that may still be added to the network.
+    pub fn gene_budget(&self) -> usize {
+        self.constraints
+            .max_genes
+            .map_or(usize::MAX, |max| max.saturating_sub(self.network.len()))
+    }
+
+    /// Returns whether a new hidden neuron may be added as an input to `parent`.
+    pub fn allows_neuron(&self, parent: NeuronId) -> bool {
+        let hidden_neurons = self.network.num_neurons() - self.network.num_outputs();
+        let depth = self.network[parent].depth() + 1;
+
+        self.gene_budget() >= 2
+            && self
+                .constraints
+                .max_hidden_neurons
+                .is_none_or(|max| hidden_neurons < max)
+            && self.constraints.max_depth.is_none_or(|max| depth <= max)
+    }
+
+    /// Returns whether recurrent jumpers may be added.
+    pub fn allows_recurrent(&self) -> bool {
+        !self.constraints.feedforward
+    }
+
+    /// Returns whether the network input may be connected to `parent`.
+    pub fn allows_input(&self, parent: NeuronId, input: usize) -> bool {
+        self.allows_inputs(parent, [input])
+    }
+
+    /// Returns whether the output of `source` may be connected to `parent` (with either kind of
+    /// jumper).
+    pub fn allows_source(&self, parent: NeuronId, source: NeuronId) -> bool {
+        match self.reach.get(&source) {
+            Some(inputs) => self.allows_inputs(parent, inputs.iter().copied()),
+            None => true,
+        }
+    }
+
+    /// Returns whether all of the network inputs may reach `parent`.
+    pub fn allows_inputs<I: IntoIterator<Item = usize>>(
+        &self,
+        parent: NeuronId,
+        inputs: I,
+    ) -> bool {
+        let mask = match &self.constraints.connections {
+            Some(mask) => mask,
+            None => return true,
+        };
+
+        match self.owners.get(&parent) {
+            Some(outputs) => inputs
+                .into_iter()
+                .all(|input| outputs.iter().all(|output| mask.allows(input, *output))),
+            None => true,
+        }
+    }
+
+    /// Returns the IDs of the network inputs the output of `source` depends on, if they were
+    /// calculated.
+    pub fn reach(&self, source: NeuronId) -> impl Iterator<Item = usize> + '_ {
+        self.reach.get(&source).into_iter().flatten().copied()
+    }
+
+    /// Returns whether the whole network satisfies the constraints.
+    pub fn is_satisfied(&self) -> bool {
+        let hidden_neurons = self.network.num_neurons() - self.network.num_outputs();
+        let depth = self
+            .network
+            .neuron_info_map()
+            .values()
+            .map(|info| info.depth())
+            .max()
+            .unwrap_or(0);
+
+        self.constraints
+            .max_genes
+            .is_none_or(|max| self.network.len() <= max)
+            && self
+                .constraints
+                .max_hidden_neurons
+                .is_none_or(|max| hidden_neurons <= max)
+            && self.constraints.max_depth.is_none_or(|max| depth <= max)
+            && (self.allows_recurrent() || is_stateless(self.network))
+            && self
+                .network
+                .neuron_ids()
+                .all(|id| self.allows_inputs(id, self.reach(id)))
+    }
+}
+
+/// Returns the IDs of all neurons whose values the value of the neuron depends on (including
+/// itself), either directly or through jumpers.
+fn dependencies(network: &Network, id: NeuronId) -> HashSet<NeuronId> {
+    let mut visited = HashSet::new();
+    let mut stack = vec![id];
+
+    while let Some(id) = stack.pop() {
+        if !visited.insert(id) {
+            continue;
+        }
+
+        for g in utils::get_direct_children(network, id) {
+            match g {
+                Gene::Neuron(neuron) => stack.push(neuron.id()),
+                Gene::ForwardJumper(forward) => stack.push(forward.source_id()),
+                Gene::RecurrentJumper(recurrent) => stack.push(recurrent.source_id()),
+                _ => {}
+            }
+        }
+    }
+
+    visited
+}
+
+#[cfg(test)]
+mod tests {
+    use cge::gene::{Input, InputId, Neuron, RecurrentJumper};
+    use cge::Activation;
+
+    use super::*;
+    use crate::options::ConnectionMask;
+
+    /// A network whose second output depends only on input 1, and whose first output depends on
+    /// both inputs through a hidden neuron. Outputs are in the reverse order of the genome.
+    fn network() -> Network {
+        let genome = vec![
+            Neuron::new(NeuronId::new(0), 1, 1.0).into(),
+            Input::new(InputId::new(1), 1.0).into(),
+            Neuron::new(NeuronId::new(2), 2, 1.0).into(),
+            Neuron::new(NeuronId::new(1), 1, 1.0).into(),
+            Input::new(InputId::new(0), 1.0).into(),
+            Input::new(InputId::new(1), 1.0).into(),
+        ];
+        Network::new(genome, Activation::Linear).unwrap()
+    }
+
+    #[test]
+    fn test_gene_budget() {
+        let network = network();
+        let unlimited = Constraints::builder().build();
+        assert_eq!(Checker::new(&unlimited, &network).gene_budget(), usize::MAX);
+
+        let limited = Constraints::builder().max_genes(8).build();
+        assert_eq!(Checker::new(&limited, &network).gene_budget(), 2);
+
+        let exceeded = Constraints::builder().max_genes(4).build();
+        let checker = Checker::new(&exceeded, &network);
+        assert_eq!(checker.gene_budget(), 0);
+        assert!(!checker.is_satisfied());
+    }
+
+    #[test]
+    fn test_allows_neuron() {
+        let network = network();
+        let (output, hidden) = (NeuronId::new(0), NeuronId::new(1));
+
+        let unlimited = Constraints::builder().build();
+        assert!(Checker::new(&unlimited, &network).allows_neuron(hidden));
+
+        let hidden_neurons = Constraints::builder().max_hidden_neurons(1).build();
+        let checker = Checker::new(&hidden_neurons, &network);
+        assert!(checker.is_satisfied());
+        assert!(!checker.allows_neuron(output));
+
+        let depth = Constraints::builder().max_depth(1).build();
+        let checker = Checker::new(&depth, &network);
+        assert!(checker.allows_neuron(output));
+        assert!(!checker.allows_neuron(hidden));
+
+        // A new neuron needs at least one input gene as well
+        let genes = Constraints::builder().max_genes(7).build();
+        assert!(!Checker::new(&genes, &network).allows_neuron(output));
+    }
+
+    #[test]
+    fn test_connection_mask() {
+        let network = network();
+        let outputs = [NeuronId::new(2), NeuronId::new(0)];
+        let hidden = NeuronId::new(1);
+
+        // The second output may not depend on input 0
+        let constraints = Constraints::builder()
+            .connections(ConnectionMask::allow_all(2, 2).forbid(0, 1))
+            .build();
+        let checker = Checker::new(&constraints, &network);
+        assert!(checker.is_satisfied());
+
+        assert_eq!(checker.reach(hidden).collect::<Vec<_>>(), vec![0]);
+        assert!(checker.allows_input(outputs[0], 0));
+        assert!(checker.allows_input(hidden, 0));
+        assert!(checker.allows_input(outputs[1], 1));
+        assert!(!checker.allows_input(outputs[1], 0));
+
+        // Sources are restricted by the inputs they reach
+        assert!(!checker.allows_source(outputs[1], hidden));
+        assert!(checker.allows_source(hidden, outputs[1]));
+
+        // The same mask with the outputs swapped is violated by the network
+        let swapped = Constraints::builder()
+            .connections(ConnectionMask::allow_all(2, 2).forbid(0, 0))
+            .build();
+        assert!(!Checker::new(&swapped, &network).is_satisfied());
+    }
+
+    #[test]
+    fn test_mask_follows_jumpers() {
+        let mut network = network();
+        let constraints = Constraints::builder()
+            .connections(ConnectionMask::allow_all(2, 2).forbid(0, 1))
+            .build();
+
+        // A recurrent jumper from the hidden neuron makes the second output depend on input 0
+        network
+            .add_non_neuron(
+                NeuronId::new(0),
+                RecurrentJumper::new(NeuronId::new(1), 1.0),
+            )
+            .unwrap();
+        assert!(!Checker::new(&constraints, &network).is_satisfied());
+
+        let feedforward = Constraints::builder().feedforward().build();
+        assert!(!Checker::new(&feedforward, &network).is_satisfied());
+    }
+}
@@ -0,0 +1,272 @@
+//! EANT2's structural crossover operators.
+
+// The original paper does not describe a crossover operator, so these are an extension of the
+// algorithm. Both operators copy a complete subgenome from one parent into the other:
+//
+// - Output swap: the subgenome of a random network output in the first parent is replaced by the
+//   subgenome of the same output in the second parent.
+// - Subnetwork merge: the subgenome of a random hidden neuron in the second parent is added as a new
+//   input to a random neuron in the first parent.
+//
+// The copied neurons receive new IDs that are unused in the first parent. Jumper connections that
+// point outside of the copied subgenome cannot be carried over in general, so they are dropped,
+// except for recurrent jumpers to output neurons, which exist in both parents. Forward jumpers
+// inside the copied subgenome remain valid because the relative depths of its neurons do not
+// change. The gene ages of both parents are carried over with their genes.
+
+use cge::gene::{ForwardJumper, Gene, Neuron, NeuronId, RecurrentJumper};
+use rand::seq::IteratorRandom;
+use rand::Rng;
+
+use std::collections::{HashMap, HashSet};
+
+use crate::cge_utils::{map_genome, output_ids, Network};
+use crate::constraints::Checker;
+use crate::options::Constraints;
+use crate::utils::Individual;
+use crate::FitnessFunction;
+
+/// Tries to combine the two parents into a new offspring, which is structurally based on `a`.
+/// Returns `None` if no valid offspring could be produced, or if it would violate `constraints`.
+pub fn crossover<T: FitnessFunction + Clone, R: Rng>(
+    a: &Individual<T>,
+    b: &Individual<T>,
+    constraints: &Constraints,
+    rng: &mut R,
+) -> Option<Individual<T>> {
+    let (genome, ages) = if rng.gen() {
+        swap_output(a, b, rng)?
+    } else {
+        merge_subnetwork(a, b, rng)?
+    };
+
+    let network = Network::new(genome, a.network.activation()).ok()?;
+    if !Checker::new(constraints, &network).is_satisfied() {
+        return None;
+    }
+    let mut offspring = a.offspring();
+    offspring.network = network;
+    offspring.ages = ages;
+    offspring.fitness = None;
+    // The search distribution of `a` does not match the recombined genome
+    offspring.search = None;
+
+    Some(offspring)
+}
+
+/// Replaces the subgenome of a random output of `a` with that of the same output of `b`.
+fn swap_output<T: FitnessFunction + Clone, R: Rng>(
+    a: &Individual<T>,
+    b: &Individual<T>,
+    rng: &mut R,
+) -> Option<(Vec<Gene<f64>>, Vec<usize>)> {
+    let outputs_a = output_ids(&a.network);
+    let outputs_b = output_ids(&b.network);
+    if outputs_a.len() != outputs_b.len() {
+        return None;
+    }
+
+    let output = rng.gen_range(0..outputs_a.len());
+    let range_a = a.network[outputs_a[output]].subgenome_range();
+    let range_b = b.network[outputs_b[output]].subgenome_range();
+
+    // The neurons of `a` that are replaced, and to which no jumpers may point anymore
+    let removed = neurons_in(&a.network, range_a.clone())
+        .filter(|id| *id != outputs_a[output])
+        .collect::<HashSet<_>>();
+
+    // The root neuron keeps its ID, and the rest are assigned new ones
+    let mut ids = new_ids(&b.network, range_b.clone(), a.network.next_neuron_id());
+    ids.insert(outputs_b[output], outputs_a[output]);
+
+    let (before, before_ages) = map_genome(
+        &a.network.genome()[..range_a.start],
+        &a.ages[..range_a.start],
+        0,
+        |g, _| without_sources(g, &removed),
+    );
+    let (swapped, swapped_ages) = map_genome(
+        &b.network.genome()[range_b.clone()],
+        &b.ages[range_b],
+        0,
+        |g, _| remap(g, &ids, &outputs_b, &outputs_a),
+    );
+    let (after, after_ages) = map_genome(
+        &a.network.genome()[range_a.end..],
+        &a.ages[range_a.end..],
+        0,
+        |g, _| without_sources(g, &removed),
+    );
+
+    let genome = before.into_iter().chain(swapped).chain(after).collect();
+    let ages = before_ages
+        .into_iter()
+        .chain(swapped_ages)
+        .chain(after_ages)
+        .collect();
+
+    Some((genome, ages))
+}
+
+/// Adds the subgenome of a random hidden neuron of `b` as an input to a random neuron of `a`.
+fn merge_subnetwork<T: FitnessFunction + Clone, R: Rng>(
+    a: &Individual<T>,
+    b: &Individual<T>,
+    rng: &mut R,
+) -> Option<(Vec<Gene<f64>>, Vec<usize>)> {
+    let outputs_a = output_ids(&a.network);
+    let outputs_b = output_ids(&b.network);
+    if outputs_a.len() != outputs_b.len() {
+        return None;
+    }
+
+    let hidden = b
+        .network
+        .neuron_ids()
+        .filter(|id| b.network[*id].depth() > 0)
+        .choose(rng)?;
+    let range_b = b.network[hidden].subgenome_range();
+    let ids = new_ids(&b.network, range_b.clone(), a.network.next_neuron_id());
+
+    let (merged, mut merged_ages) = map_genome(
+        &b.network.genome()[range_b.clone()],
+        &b.ages[range_b],
+        0,
+        |g, _| remap(g, &ids, &outputs_b, &outputs_a),
+    );
+
+    // The weight of the subnetwork's root neuron is a new connection in `a`, so its age starts over
+    merged_ages[0] = 0;
+
+    // Insert the subnetwork directly after its new parent
+    let parent = a.network.neuron_ids().choose(rng)?;
+    let parent_index = a.network[parent].subgenome_range().start;
+    let mut genome = a.network.genome().to_vec();
+    let mut ages = a.ages.clone();
+
+    let parent_neuron = genome[parent_index].as_neuron().unwrap().clone();
+    genome[parent_index] = Neuron::new(
+        parent_neuron.id(),
+        parent_neuron.num_inputs() + 1,
+        parent_neuron.weight(),
+    )
+    .into();
+    genome.splice(parent_index + 1..parent_index + 1, merged);
+    ages.splice(parent_index + 1..parent_index + 1, merged_ages);
+
+    Some((genome, ages))
+}
+
+/// Returns the IDs of all neurons in the given index range of the network.
+fn neurons_in(
+    network: &Network,
+    range: std::ops::Range<usize>,
+) -> impl Iterator<Item = NeuronId> + '_ {
+    network.genome()[range]
+        .iter()
+        .filter_map(|g| g.as_neuron().map(|n| n.id()))
+}
+
+/// Assigns new, sequential IDs starting at `next` to the neurons in the given index range.
+fn new_ids(
+    network: &Network,
+    range: std::ops::Range<usize>,
+    next: NeuronId,
+) -> HashMap<NeuronId, NeuronId> {
+    neurons_in(network, range)
+        .enumerate()
+        .map(|(i, id)| (id, NeuronId::new(next.as_usize() + i)))
+        .collect()
+}
+
+/// Drops jumpers whose source is in `removed`.
+fn without_sources(gene: &Gene<f64>, removed: &HashSet<NeuronId>) -> Option<Gene<f64>> {
+    match gene {
+        Gene::ForwardJumper(forward) if removed.contains(&forward.source_id()) => None,
+        Gene::RecurrentJumper(recurrent) if removed.contains(&recurrent.source_id()) => None,
+        _ => Some(gene.clone()),
+    }
+}
+
+/// Renames neurons and jumper sources according to `ids`. Recurrent jumpers to outputs of the
+/// source network are redirected to the same outputs in the target network. All other jumpers to
+/// neurons missing from `ids` are dropped.
+fn remap(
+    gene: &Gene<f64>,
+    ids: &HashMap<NeuronId, NeuronId>,
+    source_outputs: &[NeuronId],
+    target_outputs: &[NeuronId],
+) -> Option<Gene<f64>> {
+    match gene {
+        Gene::Neuron(neuron) => {
+            Some(Neuron::new(ids[&neuron.id()], neuron.num_inputs(), neuron.weight()).into())
+        }
+        Gene::ForwardJumper(forward) => ids
+            .get(&forward.source_id())
+            .map(|id| ForwardJumper::new(*id, forward.weight()).into()),
+        Gene::RecurrentJumper(recurrent) => {
+            let source = recurrent.source_id();
+            ids.get(&source)
+                .copied()
+                .or_else(|| {
+                    source_outputs
+                        .iter()
+                        .position(|id| *id == source)
+                        .map(|i| target_outputs[i])
+                })
+                .map(|id| RecurrentJumper::new(id, recurrent.weight()).into())
+        }
+        _ => Some(gene.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cge::gene::{Bias, Input, InputId};
+
+    use super::*;
+    use crate::mutation::mutate;
+    use crate::mutation_probabilities::MutationSampler;
+    use crate::options::DEFAULT_WEIGHT_INIT;
+    use crate::utils::Zero;
+
+    fn individual() -> Individual<Zero> {
+        let genome = vec![
+            Neuron::new(NeuronId::new(0), 2, 1.0).into(),
+            Input::new(InputId::new(0), 1.0).into(),
+            Bias::new(1.0).into(),
+            Neuron::new(NeuronId::new(1), 1, 1.0).into(),
+            Input::new(InputId::new(1), 1.0).into(),
+        ];
+        Individual::for_test(2, genome)
+    }
+
+    #[test]
+    fn test_crossover_produces_valid_offspring() {
+        let mut rng = rand::thread_rng();
+        let sampler = MutationSampler::default();
+        let mut a = individual();
+        let mut b = individual();
+
+        for _ in 0..200 {
+            mutate(
+                &mut a,
+                &sampler,
+                &Constraints::default(),
+                &DEFAULT_WEIGHT_INIT,
+            );
+            mutate(
+                &mut b,
+                &sampler,
+                &Constraints::default(),
+                &DEFAULT_WEIGHT_INIT,
+            );
+
+            if let Some(offspring) = crossover(&a, &b, &Constraints::default(), &mut rng) {
+                assert_eq!(offspring.ages.len(), offspring.network.len());
+                assert_eq!(offspring.network.num_outputs(), 2);
+                a = offspring;
+            }
+        }
+    }
+}
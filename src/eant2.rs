@@ -1,10 +1,19 @@
 use cge::Activation;
+use rand::seq::IteratorRandom;
+use rand::{thread_rng, Rng};
 use std::sync::Arc;
 use typed_builder::TypedBuilder;
 
 use crate::cge_utils::Network;
+use crate::mutation_probabilities::{MutationAdaptation, MutationSampler};
 use crate::options::*;
-use crate::{generation::Generation, mutation::mutate, select, FitnessFunction};
+use crate::speciation::Speciator;
+use crate::statistics::{GenerationStatistics, Statistics};
+use crate::topology::{InitialTopology, Minimal};
+use crate::{
+    crossover::crossover, generation::Generation, mutation::mutate, select, selection,
+    FitnessFunction,
+};
 
 /// The EANT2 algorithm.
 ///
@@ -27,8 +36,14 @@ use crate::{generation::Generation, mutation::mutate, select, FitnessFunction};
 ///
 /// # Minimal Example
 ///
-/// ```rust
-/// use eant2::*;
+/// ```no_run
+/// # use eant2::{FitnessFunction, NetworkView};
+/// use eant2::eant2::EANT2;
+/// # #[derive(Clone)]
+/// # struct MyFitnessFunction;
+/// # impl FitnessFunction for MyFitnessFunction {
+/// #     fn fitness(&self, _: NetworkView) -> f64 { 0.0 }
+/// # }
 /// let train = EANT2::builder()
 ///   .inputs(10)
 ///   .outputs(3)
@@ -42,7 +57,11 @@ use crate::{generation::Generation, mutation::mutate, select, FitnessFunction};
 /// - Most options have good default values, and exist only for flexibility.
 ///
 /// ```rust
-/// use eant2::*;
+/// use cmaes::restart::RestartStrategy;
+/// use eant2::eant2::EANT2;
+/// use eant2::mutation_probabilities::MutationProbabilities;
+/// use eant2::options::*;
+/// use eant2::Activation;
 ///
 /// let eant = EANT2::builder()
 ///   .inputs(10)
@@ -58,6 +77,7 @@ use crate::{generation::Generation, mutation::mutate, select, FitnessFunction};
 ///         EANT2Termination::builder()
 ///           .fitness(0.15)        // either terminate EANT2 when best fitness hits 0.15,
 ///           .generations(12)      // or terminate after 12 generations
+///           .build()
 ///       )
 ///       .mutation_probabilities(  // describe relative mutation probabilities
 ///         MutationProbabilities::zeros()
@@ -67,6 +87,7 @@ use crate::{generation::Generation, mutation::mutate, select, FitnessFunction};
 ///           .add_bias(1.)         
 ///           .build().unwrap()
 ///       )
+///       .mutations(MutationCount::poisson(0.5).unwrap()) // apply 1 + Poisson(0.5) mutations per offspring
 ///       .build()
 ///    )
 ///    .exploitation(               // CMA-ES options (parameter optimization)
@@ -76,6 +97,7 @@ use crate::{generation::Generation, mutation::mutate, select, FitnessFunction};
 ///          CMAESTermination::builder()
 ///            .evaluations(60) // force terminate CMA-ES if fitness function is evaluated 60 times (default no limit)
 ///            .generations(40) // force terminate CMA-ES after 40 CMA-ES generations (default no limit)
+///            .build()
 ///        )
 ///        .build()
 ///    )
@@ -97,10 +119,56 @@ pub struct EANT2 {
     #[builder(default = DEFAULT_ACTIVATION, setter(doc = "Activation function the network uses."))]
     pub activation: Activation,
 
+    /// How the weights of new genes are initialized
+    #[builder(
+        default = DEFAULT_WEIGHT_INIT,
+        setter(doc = "How the weights of new genes are initialized. Default: constant `1.0`.")
+    )]
+    pub weight_init: WeightInit,
+
+    /// Generator for the topologies of the initial networks
+    #[builder(
+        default_code = "Arc::new(Minimal)",
+        setter(
+            transform = |topology: impl InitialTopology + 'static| Arc::new(topology) as Arc<dyn InitialTopology>,
+            doc = "Generator for the topologies of the initial networks. Default: `Minimal`."
+        )
+    )]
+    pub initial_topology: Arc<dyn InitialTopology>,
+
     /// Initial network
-    #[builder(default = None, setter(strip_option, doc = "Initial network."))]
+    #[builder(
+        default = None,
+        setter(
+            strip_option,
+            doc = "Initial network. It must have the same activation function and number of outputs as
+                  configured here, may not use more inputs than configured, and must satisfy the
+                  structural constraints. Otherwise, running the algorithm panics. Default: none."
+        )
+    )]
     pub seed: Option<Network>,
 
+    /// How the initial population is created from the seed network
+    #[builder(
+        default = DEFAULT_SEEDING,
+        setter(
+            transform = |seeding: Seeding| seeding.checked(),
+            doc = "How the initial population is created from the seed network. Has no effect if no
+                  seed is set. The fraction of random networks of `Seeding::Variants` must be in
+                  `[0, 1]`. Default: `Seeding::Copies`."
+        )
+    )]
+    pub seeding: Seeding,
+
+    /// The initial age of the genes of the seed network
+    #[builder(
+        default = DEFAULT_SEED_AGE,
+        setter(doc = "The initial age of the genes of the seed network. Older genes are searched more
+                  locally by CMA-ES, which preserves the tuned weights of the seed. Genes added by
+                  mutations to variants of the seed start at age zero. Default: `1`.")
+    )]
+    pub seed_age: usize,
+
     /// Structural exploration (EANT2: mutation) options
     #[builder(
         default_code = "Exploration::builder().build()",
@@ -126,13 +194,40 @@ pub struct EANT2 {
 impl EANT2 {
     /// Run the optimization algorithm until termination conditions are met, yielding the best network and its fitness.
     pub fn run<T>(&self, object: &T) -> (Network, f64)
+    where
+        T: 'static + FitnessFunction + Clone + Send + Sync,
+    {
+        let (network, fitness, _) = self.run_with_statistics(object);
+        (network, fitness)
+    }
+
+    /// Like [`run`][Self::run], but also yields statistics about each generation of the run.
+    pub fn run_with_statistics<T>(&self, object: &T) -> (Network, f64, Statistics)
     where
         T: 'static + FitnessFunction + Clone + Send + Sync,
     {
         let object = Arc::new(object.clone());
+        let mut statistics = Statistics::default();
+        let mut sampler = self.exploration.mutation_probabilities.clone();
+        let mut adaptation = self
+            .exploration
+            .adaptive_mutation
+            .clone()
+            .map(|options| MutationAdaptation::new(options, &sampler));
+        let mut rng = thread_rng();
         let mut g = 0;
+        // The best fitness found so far, and the number of consecutive generations in which it has
+        // not improved
+        let mut best_so_far = f64::INFINITY;
+        let mut stagnation = 0;
         // Initialize a set of minimal networks
-        let mut generation = Generation::initialize(&self, object);
+        let mut generation = Generation::initialize(self, object);
+        // The ID of the next individual to be produced
+        let mut next_id = generation.individuals.len();
+        // The coefficient of the parsimony penalty used in the latest selection
+        let mut parsimony_coefficient = 0.0;
+        // The species of the population, if speciation is enabled
+        let mut speciator = Speciator::default();
 
         loop {
             if self.print {
@@ -143,20 +238,93 @@ impl EANT2 {
             // TODO: consider parallelizing this step
             let mut new_individuals =
                 Vec::with_capacity((self.exploration.offspring + 1) * self.exploration.population);
-            for mut individual in generation.individuals {
-                // Increment gene ages
+
+            // Increment gene ages
+            for individual in &mut generation.individuals {
                 for age in &mut individual.ages {
                     *age += 1;
                 }
 
+                // Survivors are parents now, so their mutations are no longer credited
+                individual.mutations.clear();
+            }
+
+            // Divide the offspring among the individuals by rank. The initial population has not
+            // been evaluated yet, so its individuals all get the same number of offspring.
+            let reproduction = if generation.individuals.iter().any(|i| i.fitness.is_none()) {
+                &Reproduction::Uniform
+            } else {
+                &self.exploration.reproduction
+            };
+            let mut ranked = generation
+                .individuals
+                .iter()
+                .enumerate()
+                .map(|(i, individual)| {
+                    let complexity = self.exploration.complexity.measure(&individual.network);
+                    let fitness = individual.fitness.unwrap_or(0.0);
+                    (i, fitness + parsimony_coefficient * complexity, complexity)
+                })
+                .collect::<Vec<_>>();
+            ranked.sort_by(|(_, a, a_complexity), (_, b, b_complexity)| {
+                a.total_cmp(b).then(a_complexity.total_cmp(b_complexity))
+            });
+            let fitnesses = ranked.iter().map(|(_, f, _)| *f).collect::<Vec<_>>();
+            let total = self.exploration.offspring * generation.individuals.len();
+            let mut offspring_counts = vec![0; generation.individuals.len()];
+            for ((i, _, _), count) in ranked.iter().zip(reproduction.offspring(&fitnesses, total)) {
+                offspring_counts[*i] = count;
+            }
+
+            for (individual, &count) in generation.individuals.iter().zip(&offspring_counts) {
                 // Carry over each individual to the next generation unchanged
                 new_individuals.push(individual.clone());
 
                 // Also mutate it to produce offspring
-                for _ in 0..self.exploration.offspring {
-                    let mut offspring = individual.clone();
-                    if mutate(&mut offspring, &self.exploration.mutation_probabilities) {
-                        // If the offspring was mutated, its fitness is now invalid and must be
+                for _ in 0..count {
+                    let mut offspring = individual.offspring();
+                    let mut changed = false;
+
+                    // Optionally combine it with another random individual first (which is not
+                    // possible in a population of one)
+                    let mate = if rng.gen::<f64>() < self.exploration.crossover {
+                        generation
+                            .individuals
+                            .iter()
+                            .filter(|other| other.id != individual.id)
+                            .choose(&mut rng)
+                    } else {
+                        None
+                    };
+                    if let Some(mate) = mate {
+                        let constraints = &self.exploration.constraints;
+                        if let Some(child) = crossover(individual, mate, constraints, &mut rng) {
+                            offspring = child;
+                            offspring.mate_id = Some(mate.id);
+                            changed = true;
+                        }
+                    }
+
+                    offspring.id = next_id;
+                    offspring.birth_generation = g + 1;
+                    next_id += 1;
+
+                    let mutation_count = self.exploration.mutations.sample(&mut rng, stagnation);
+
+                    // Each mutation keeps the gene ages in sync with the genome, so they can
+                    // simply be chained
+                    for _ in 0..mutation_count {
+                        changed |= mutate(
+                            &mut offspring,
+                            &sampler,
+                            &self.exploration.constraints,
+                            &self.weight_init,
+                        );
+                    }
+                    debug_assert_eq!(offspring.ages.len(), offspring.network.len());
+
+                    if changed {
+                        // If the offspring was changed, its fitness is now invalid and must be
                         // reset
                         offspring.fitness = None;
                     }
@@ -164,51 +332,164 @@ impl EANT2 {
                     new_individuals.push(offspring);
                 }
             }
+
+            // Discard offspring with too many duplicates before spending CMA-ES runs on them
+            let selection_options = &self.exploration.selection;
+            if selection_options.deduplicate_offspring {
+                let networks = |offspring: bool| {
+                    new_individuals
+                        .iter()
+                        .filter(|individual| (individual.birth_generation > g) == offspring)
+                        .map(|individual| &individual.network)
+                        .collect::<Vec<_>>()
+                };
+                let mut excess = select::excess_offspring(
+                    &networks(false),
+                    &networks(true),
+                    selection_options.max_copies.max(1),
+                    selection_options.similarity_check,
+                )
+                .into_iter();
+
+                // Parents are always kept
+                new_individuals.retain(|individual| {
+                    individual.birth_generation <= g || !excess.next().unwrap()
+                });
+            }
             generation.individuals = new_individuals;
 
             // 2. Get fitness of network topologies by optimizing the parameters of each individual
             // with CMA-ES to get their maximum potential.
             //    - This stage makes up for nearly all the running time of the algorithm, sometimes
             //    taking hours or days.
-            generation.update_generation(&self);
+            generation.update_generation(self, parsimony_coefficient);
+
+            for individual in &generation.individuals {
+                statistics.genealogy.record(individual);
+            }
+
+            // Count how often each mutation type was applied, and how often it improved on the
+            // parent's fitness
+            let mut applied = [0; 4];
+            let mut succeeded = [0; 4];
+            for individual in &generation.individuals {
+                let improved = individual.fitness < individual.parent_fitness;
+                for mutation in individual.mutations.iter().filter(|m| m.is_primary()) {
+                    applied[MutationSampler::index(mutation.kind)] += 1;
+                    if improved {
+                        succeeded[MutationSampler::index(mutation.kind)] += 1;
+                    }
+                }
+            }
 
             // 3. Select individuals to go on to the next generation
-            // TODO: Should this be customizable? `false` seems more in line with the paper
-            //       because the constraints are met exactly (and it wastes less CMA-ES runs on
-            //       similar/duplicate networks), but maybe `true` is better in some cases.
-            let force_meet_population_size = false;
-            generation = select::select(
+            let selection_report;
+            (generation, parsimony_coefficient, selection_report) = selection::next_generation(
                 generation.individuals,
-                self.exploration.population,
-                self.exploration.similarity,
-                force_meet_population_size,
+                g,
+                &self.exploration,
+                &mut speciator,
+                &mut rng,
             );
 
-            let best = generation
+            // The best individual has the best penalized fitness, and among individuals with equal
+            // fitness, the least complex one is the best
+            let complexity = &self.exploration.complexity;
+            let ranked = generation
                 .individuals
                 .iter()
-                .min_by(|a, b| a.fitness.partial_cmp(&b.fitness).unwrap())
-                .unwrap();
+                .map(|individual| {
+                    let complexity = complexity.measure(&individual.network);
+                    let penalized =
+                        individual.fitness.unwrap() + parsimony_coefficient * complexity;
+                    (individual, complexity, penalized)
+                })
+                .collect::<Vec<_>>();
+            let compare = |a: &&(_, f64, f64), b: &&(_, f64, f64)| {
+                a.2.partial_cmp(&b.2).unwrap().then(a.1.total_cmp(&b.1))
+            };
+            let &(best, best_complexity, best_penalized_fitness) =
+                ranked.iter().min_by(compare).unwrap();
             let best_fitness = best.fitness.unwrap();
 
+            // The penalty may keep the individuals that reach the target fitness from being the
+            // best, so the best of those is the solution if there are any
+            let solution = ranked
+                .iter()
+                .filter(|(individual, _, _)| {
+                    individual.fitness.unwrap() <= self.exploration.terminate.fitness
+                })
+                .min_by(compare);
+
+            statistics.generations.push(GenerationStatistics {
+                generation: g + 1,
+                best_fitness,
+                best_id: best.id,
+                best_complexity,
+                best_penalized_fitness,
+                parsimony_coefficient,
+                species: speciator.species().len(),
+                population: generation.individuals.len(),
+                mutation_probabilities: sampler.probabilities(),
+                selection: selection_report,
+            });
+
+            // Adapt the mutation probabilities for the next generation
+            if let Some(adaptation) = &mut adaptation {
+                if adaptation.credit() == MutationCredit::Survival {
+                    succeeded = [0; 4];
+                    for individual in &generation.individuals {
+                        for mutation in individual.mutations.iter().filter(|m| m.is_primary()) {
+                            succeeded[MutationSampler::index(mutation.kind)] += 1;
+                        }
+                    }
+                }
+
+                let mut rewards = [None; 4];
+                for i in 0..rewards.len() {
+                    if applied[i] > 0 {
+                        rewards[i] = Some(succeeded[i] as f64 / applied[i] as f64);
+                    }
+                }
+
+                adaptation.update(rewards);
+                sampler = adaptation.sampler();
+
+                if self.print {
+                    println!("Mutation probabilities: {:?}", sampler.probabilities());
+                }
+            }
+
+            // Track stagnation for the adaptive mutation count
+            if best_fitness < best_so_far {
+                best_so_far = best_fitness;
+                stagnation = 0;
+            } else {
+                stagnation += 1;
+            }
+
             // 4. Check EANT2 termination conditions
-            if best_fitness <= self.exploration.terminate.fitness
-                || g + 1 >= self.exploration.terminate.generations
-            {
+            if solution.is_some() || g + 1 >= self.exploration.terminate.generations {
+                let &(solution, solution_complexity, _) =
+                    solution.unwrap_or(&(best, best_complexity, best_penalized_fitness));
+                let solution_fitness = solution.fitness.unwrap();
+
                 if self.print {
                     println!("EANT2 terminated in {} generations", g + 1);
                     println!(
-                        "Solution found with size {} and {} fitness",
-                        best.network.len(),
-                        best_fitness,
+                        "Solution found with complexity {} ({:?}) and {} fitness",
+                        solution_complexity, complexity, solution_fitness,
                     );
                 }
 
-                return (best.network.clone(), best_fitness);
+                return (solution.network.clone(), solution_fitness, statistics);
             }
 
             if self.print {
-                println!("Current best fitness: {}", best.fitness.unwrap());
+                println!(
+                    "Current best fitness: {} (complexity {})",
+                    best_fitness, best_complexity
+                );
             }
 
             g += 1;
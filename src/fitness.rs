@@ -1,6 +1,7 @@
 //! The fitness function.
 
 use crate::cge_utils::NetworkView;
+use crate::gradient::Dataset;
 
 /// The fitness function used by the EANT2 algorithm. A lower fitness represents a better
 /// individual. Implement it for a type, and pass the type to the `EANT2::eant2` function. Use
@@ -8,4 +9,12 @@ use crate::cge_utils::NetworkView;
 /// fitness calculation.
 pub trait FitnessFunction {
     fn fitness(&self, network: NetworkView) -> f64;
+
+    /// Returns the dataset the fitness is computed on if the task is supervised, which allows the
+    /// weights to be fine-tuned with gradients (see `Exploitation::fine_tuning`). The fitness
+    /// should then be consistent with the loss of the dataset, but it is still used to decide
+    /// whether the fine-tuned weights are kept. Default: `None`.
+    fn dataset(&self) -> Option<&Dataset> {
+        None
+    }
 }
@@ -0,0 +1,241 @@
+//! Records of how the individuals of a run came about.
+//!
+//! Every individual that is evaluated during a run is assigned a unique ID and recorded in the
+//! [`Genealogy`] of the run, together with the ID of its parent and the structural mutations that
+//! produced it. The lineage of any individual, such as the best one of the last generation, can be
+//! traced back to the initial population this way.
+
+use cge::gene::{Gene, NeuronId};
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::mutation_probabilities::MutationType;
+use crate::utils::Individual;
+use crate::FitnessFunction;
+
+/// The gene that a structural mutation added or removed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Source {
+    /// A connection from a network input.
+    Input(usize),
+    /// A bias.
+    Bias,
+    /// A forward jumper connection from a neuron.
+    ForwardJumper(NeuronId),
+    /// A recurrent jumper connection from a neuron.
+    RecurrentJumper(NeuronId),
+    /// A new hidden neuron, which is the root of a new subnetwork.
+    Neuron(NeuronId),
+}
+
+impl Source {
+    /// Returns the source corresponding to a gene.
+    pub(crate) fn of(gene: &Gene<f64>) -> Source {
+        match gene {
+            Gene::Input(input) => Source::Input(input.id().as_usize()),
+            Gene::Bias(_) => Source::Bias,
+            Gene::ForwardJumper(forward) => Source::ForwardJumper(forward.source_id()),
+            Gene::RecurrentJumper(recurrent) => Source::RecurrentJumper(recurrent.source_id()),
+            Gene::Neuron(neuron) => Source::Neuron(neuron.id()),
+        }
+    }
+}
+
+/// A structural mutation that was applied to a network.
+///
+/// Adding a subnetwork is recorded as the new neuron, followed by one record for each forward
+/// jumper that connects it to a further neuron.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MutationRecord {
+    /// The type of the mutation.
+    pub kind: MutationType,
+    /// The neuron whose inputs were changed.
+    pub parent: NeuronId,
+    /// The gene that was added to or removed from the inputs of `parent`.
+    pub source: Source,
+}
+
+impl MutationRecord {
+    /// Returns whether this is the record of the mutation itself, rather than of one of the
+    /// further connections a new subnetwork was given.
+    pub(crate) fn is_primary(&self) -> bool {
+        !(self.kind == MutationType::AddNode && matches!(self.source, Source::ForwardJumper(_)))
+    }
+}
+
+/// The origin of a single individual.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineageRecord {
+    /// The unique ID of the individual.
+    pub id: usize,
+    /// The ID of the individual it was produced from, or `None` if it is part of the initial
+    /// population.
+    pub parent: Option<usize>,
+    /// The ID of the individual that `parent` was combined with by crossover, if any.
+    pub mate: Option<usize>,
+    /// The generation the individual was produced in, where the initial population is generation
+    /// zero.
+    pub generation: usize,
+    /// The mutations that were applied to the parent (or the result of crossover) to produce the
+    /// individual, in order.
+    pub mutations: Vec<MutationRecord>,
+    /// The fitness of the parent when the individual was produced.
+    pub parent_fitness: Option<f64>,
+    /// The fitness of the individual after its first optimization.
+    pub fitness: f64,
+}
+
+/// The records of all individuals evaluated during a run.
+#[derive(Clone, Debug, Default)]
+pub struct Genealogy {
+    records: BTreeMap<usize, LineageRecord>,
+}
+
+impl Genealogy {
+    /// Records an individual after it was evaluated, unless it is already recorded.
+    pub(crate) fn record<T: FitnessFunction + Clone>(&mut self, individual: &Individual<T>) {
+        self.records
+            .entry(individual.id)
+            .or_insert_with(|| LineageRecord {
+                id: individual.id,
+                parent: individual.parent_id,
+                mate: individual.mate_id,
+                generation: individual.birth_generation,
+                mutations: individual.mutations.clone(),
+                parent_fitness: individual.parent_fitness,
+                fitness: individual.fitness.unwrap(),
+            });
+    }
+
+    /// Returns the record of the individual with the given ID.
+    pub fn get(&self, id: usize) -> Option<&LineageRecord> {
+        self.records.get(&id)
+    }
+
+    /// Returns all records, ordered by ID.
+    pub fn records(&self) -> impl Iterator<Item = &LineageRecord> {
+        self.records.values()
+    }
+
+    /// Returns the records of the individual with the given ID and all of its ancestors, starting
+    /// with an individual of the initial population. Mates are not included.
+    pub fn lineage(&self, id: usize) -> Vec<&LineageRecord> {
+        let mut lineage = Vec::new();
+        let mut next = Some(id);
+
+        while let Some(record) = next.and_then(|id| self.get(id)) {
+            lineage.push(record);
+            next = record.parent;
+        }
+
+        lineage.reverse();
+        lineage
+    }
+
+    /// Writes all records to `writer` as CSV, with one line per individual.
+    ///
+    /// The columns are `id`, `parent`, `mate`, `generation`, `parent_fitness`, `fitness` and
+    /// `mutations`. Missing values are left empty. Mutations are separated by `;`, and each one is
+    /// written as `type:parent:source`, for example `AddConnection:0:ForwardJumper(2)`.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(
+            writer,
+            "id,parent,mate,generation,parent_fitness,fitness,mutations"
+        )?;
+
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        for record in self.records() {
+            let mutations = record
+                .mutations
+                .iter()
+                .map(|m| format!("{:?}:{}:{}", m.kind, m.parent.as_usize(), source(m.source)))
+                .collect::<Vec<_>>()
+                .join(";");
+
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{}",
+                record.id,
+                optional(record.parent.map(|id| id.to_string())),
+                optional(record.mate.map(|id| id.to_string())),
+                record.generation,
+                optional(record.parent_fitness.map(|f| f.to_string())),
+                record.fitness,
+                mutations,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes all records to a file as CSV. See [`write`][Self::write] for the format.
+    pub fn to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+}
+
+/// Formats a mutation source for the CSV output.
+fn source(source: Source) -> String {
+    match source {
+        Source::Input(id) => format!("Input({})", id),
+        Source::Bias => "Bias".to_string(),
+        Source::ForwardJumper(id) => format!("ForwardJumper({})", id.as_usize()),
+        Source::RecurrentJumper(id) => format!("RecurrentJumper({})", id.as_usize()),
+        Source::Neuron(id) => format!("Neuron({})", id.as_usize()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: usize, parent: Option<usize>, mutations: Vec<MutationRecord>) -> LineageRecord {
+        LineageRecord {
+            id,
+            parent,
+            mate: None,
+            generation: id,
+            mutations,
+            parent_fitness: parent.map(|_| 1.0),
+            fitness: 0.5,
+        }
+    }
+
+    #[test]
+    fn test_lineage_and_export() {
+        let mutation = MutationRecord {
+            kind: MutationType::AddConnection,
+            parent: NeuronId::new(0),
+            source: Source::ForwardJumper(NeuronId::new(2)),
+        };
+        let mut genealogy = Genealogy::default();
+        for record in [
+            record(0, None, vec![]),
+            record(1, Some(0), vec![mutation]),
+            record(2, Some(1), vec![mutation, mutation]),
+            record(3, Some(0), vec![]),
+        ] {
+            genealogy.records.insert(record.id, record);
+        }
+
+        let lineage = genealogy.lineage(2);
+        let ids = lineage.iter().map(|r| r.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![0, 1, 2]);
+
+        let mut csv = Vec::new();
+        genealogy.write(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[1], "0,,,0,,0.5,");
+        assert_eq!(
+            lines[3],
+            "2,1,,2,1,0.5,AddConnection:0:ForwardJumper(2);AddConnection:0:ForwardJumper(2)"
+        );
+    }
+}
@@ -1,14 +1,16 @@
-use cge::gene::{Gene, Input, InputId, Neuron, NeuronId};
-use cge::Activation;
-use rand::{thread_rng, Rng};
+use cge::gene::Gene;
+use rand::seq::IteratorRandom;
+use rand::thread_rng;
 use rayon::prelude::*;
 
-use std::collections::HashSet;
 use std::sync::Arc;
 
-use crate::cge_utils::{Network, INITIAL_WEIGHT_VALUE};
+use crate::cge_utils::{initialize_weights, map_genome, Network};
 use crate::cmaes_utils::optimize_network;
+use crate::constraints::Checker;
 use crate::eant2::EANT2;
+use crate::mutation::mutate;
+use crate::options::{Reoptimization, Seeding};
 use crate::utils::Individual;
 use crate::FitnessFunction;
 
@@ -20,89 +22,410 @@ pub struct Generation<T: FitnessFunction + Clone> {
 }
 
 impl<T: FitnessFunction + Clone> Generation<T> {
-    /// Creates a generation of random, minimal neural networks.
+    /// Creates the initial generation, either from random, minimal neural networks or from the seed
+    /// network.
+    ///
+    /// Panics if the connection mask, the seed network or the dataset used for fine-tuning does not
+    /// match the options.
     pub fn initialize(options: &EANT2, object: Arc<T>) -> Generation<T> {
+        validate_connections(options);
+        validate_dataset(options, object.as_ref());
         let individual_count = options.exploration.population;
+        let random_individual = || {
+            let network = get_random_initial_network(options);
+            Individual::new(options.inputs, options.outputs, network, object.clone())
+        };
 
-        let individuals = (0..individual_count)
-            .map(|_| {
-                let network =
-                    get_random_initial_network(options.inputs, options.outputs, options.activation);
+        let seed = match &options.seed {
+            Some(seed) => seed,
+            None => {
+                let individuals = (0..individual_count).map(|_| random_individual()).collect();
+                return Self::numbered(individuals);
+            }
+        };
 
-                Individual::new(options.inputs, options.outputs, network, object.clone())
-            })
-            .collect();
+        validate_seed(seed, options);
+
+        let mut seed_individual = Individual::new(
+            options.inputs,
+            options.outputs,
+            seed.clone(),
+            object.clone(),
+        );
+        seed_individual.ages = vec![options.seed_age; seed.len()];
+
+        let individuals = match options.seeding {
+            Seeding::Copies => vec![seed_individual; individual_count],
+            Seeding::Variants { mutations, random } => {
+                // Always keep at least one exact copy of the seed
+                let random_count = ((random * individual_count as f64).round() as usize)
+                    .min(individual_count.saturating_sub(1));
+                let variant_count = individual_count - random_count;
+
+                let mut individuals = Vec::with_capacity(individual_count);
+                individuals.push(seed_individual.clone());
+
+                for _ in 1..variant_count {
+                    let mut variant = seed_individual.clone();
+                    for _ in 0..mutations {
+                        mutate(
+                            &mut variant,
+                            &options.exploration.mutation_probabilities,
+                            &options.exploration.constraints,
+                            &options.weight_init,
+                        );
+                    }
+                    variant.mutations.clear();
+                    individuals.push(variant);
+                }
+
+                individuals.extend((0..random_count).map(|_| random_individual()));
+                individuals
+            }
+        };
+
+        Self::numbered(individuals)
+    }
+
+    /// Creates the initial generation from the given individuals, assigning them sequential IDs.
+    fn numbered(mut individuals: Vec<Individual<T>>) -> Generation<T> {
+        for (id, individual) in individuals.iter_mut().enumerate() {
+            individual.id = id;
+        }
 
         Generation { individuals }
     }
 
     /// Use `CMA-ES` to optimize all the individuals in the generation in parallel, exploiting their existing structure.
-    pub fn update_generation(&mut self, options: &EANT2)
+    /// Individuals that were already optimized are only optimized again according to `Exploitation::reoptimization`.
+    /// `parsimony_coefficient` is the coefficient of the complexity penalty, if it applies during optimization.
+    pub fn update_generation(&mut self, options: &EANT2, parsimony_coefficient: f64)
     where
         T: 'static + FitnessFunction + Clone + Send + Sync,
     {
-        self.individuals
-            .par_iter_mut()
-            .for_each(|individual| optimize_network(individual, &options));
+        let policy = options.exploitation.reoptimization;
+        self.individuals.par_iter_mut().for_each(|individual| {
+            if individual.fitness.is_none() {
+                optimize_network(individual, options, parsimony_coefficient, None);
+                return;
+            }
+
+            let youngest_gene = individual.ages.iter().copied().min().unwrap_or(0);
+            if !policy.applies(youngest_gene, individual.skipped, individual.improvement) {
+                individual.skipped += 1;
+                return;
+            }
+
+            let evaluations = match policy {
+                Reoptimization::Reduced(evaluations) => Some(evaluations),
+                _ => None,
+            };
+            let previous_fitness = individual.fitness;
+            optimize_network(individual, options, parsimony_coefficient, evaluations);
+            individual.skipped = 0;
+            individual.improvement = previous_fitness
+                .zip(individual.fitness)
+                .map(|(previous, fitness)| (previous - fitness).max(0.0));
+        });
+    }
+}
+
+/// Panics if the connection mask does not cover exactly the configured inputs and outputs.
+fn validate_connections(options: &EANT2) {
+    if let Some(mask) = &options.exploration.constraints.connections {
+        assert!(
+            mask.inputs() == options.inputs && mask.outputs() == options.outputs,
+            "the connection mask covers {} inputs and {} outputs, but {} inputs and {} outputs were configured",
+            mask.inputs(),
+            mask.outputs(),
+            options.inputs,
+            options.outputs,
+        );
     }
 }
 
-/// Returns a random, minimal network with the specified number of inputs and outputs.
-fn get_random_initial_network(inputs: usize, outputs: usize, activation: Activation) -> Network {
+/// Panics if fine-tuning is enabled and the dataset of the fitness function does not fit the
+/// configured inputs and outputs. Fine-tuning runs in parallel for many individuals, so invalid
+/// samples are rejected before the run rather than in the middle of it.
+fn validate_dataset<T: FitnessFunction>(options: &EANT2, object: &T) {
+    let dataset = options.exploitation.fine_tuning.and(object.dataset());
+    if let Some(dataset) = dataset {
+        assert!(
+            dataset.fits(options.inputs, options.outputs),
+            "every sample of the dataset must have {} inputs and {} targets",
+            options.inputs,
+            options.outputs,
+        );
+    }
+}
+
+/// Panics if the seed network cannot be used with the options.
+fn validate_seed(seed: &Network, options: &EANT2) {
+    assert_eq!(
+        seed.num_outputs(),
+        options.outputs,
+        "the seed network has {} outputs, but {} were configured",
+        seed.num_outputs(),
+        options.outputs,
+    );
+    assert!(
+        seed.num_inputs() <= options.inputs,
+        "the seed network uses {} inputs, but only {} were configured",
+        seed.num_inputs(),
+        options.inputs,
+    );
+    assert_eq!(
+        seed.activation(),
+        options.activation,
+        "the seed network uses a different activation function than configured",
+    );
+    assert!(
+        Checker::new(&options.exploration.constraints, seed).is_satisfied(),
+        "the seed network does not satisfy the structural constraints",
+    );
+}
+
+/// Returns a random initial network generated by the configured initial topology. Input
+/// connections forbidden by the connection mask are removed, the network is trimmed to fit into its
+/// gene budget if possible, and its weights are initialized according to the options.
+///
+/// Panics if the generated network is invalid or does not satisfy the structural constraints.
+fn get_random_initial_network(options: &EANT2) -> Network {
     let mut rng = thread_rng();
+    let constraints = &options.exploration.constraints;
+
+    let genome =
+        options
+            .initial_topology
+            .genome(options.inputs, options.outputs, constraints, &mut rng);
+    let mut network = Network::new(genome, options.activation)
+        .expect("the initial topology generated an invalid genome");
+    assert_eq!(
+        network.num_outputs(),
+        options.outputs,
+        "the initial topology generated a network with the wrong number of outputs",
+    );
+    assert!(
+        network.num_inputs() <= options.inputs,
+        "the initial topology generated a network that uses too many inputs",
+    );
 
-    // Generate the genome randomly
-    let genome = (0..outputs)
-        .map(|id| NeuronId::new(id))
-        .flat_map(|neuron_id| {
-            // Create a random subgenome for each network output
-            let mut subgenome = Vec::new();
+    // Remove input connections that the connection mask forbids. Jumpers only forward the values of
+    // neurons that are restricted at least as much as their targets, so they can be kept.
+    if constraints.connections.is_some() {
+        let checker = Checker::new(constraints, &network);
+        let keep = network
+            .genome()
+            .iter()
+            .zip(network.parents())
+            .map(|(g, parent)| match (g, parent) {
+                (Gene::Input(input), Some(parent)) => {
+                    checker.allows_input(*parent, input.id().as_usize())
+                }
+                _ => true,
+            })
+            .collect::<Vec<_>>();
 
-            let create_input = |id| Gene::from(Input::new(id, INITIAL_WEIGHT_VALUE));
+        let (genome, _) = map_genome(network.genome(), &keep, true, |g, keep| {
+            keep.then(|| g.clone())
+        });
+        network = Network::new(genome, options.activation).unwrap();
+    }
 
-            // Each subgenome is connected to ~50% of network inputs
-            for input_id in (0..inputs).map(|id| InputId::new(id)) {
-                if rng.gen() {
-                    subgenome.push(create_input(input_id));
+    // Remove random connections until the network fits into the gene budget
+    if let Some(max_genes) = constraints.max_genes {
+        while network.len() > max_genes {
+            match network.get_valid_removals().choose(&mut rng) {
+                Some(index) => {
+                    network.remove_non_neuron(index).unwrap();
                 }
+                None => break,
             }
+        }
+    }
 
-            // If no input genes were added, add a random input gene
-            if subgenome.is_empty() {
-                let input_id = InputId::new(rng.gen_range(0..inputs));
-                subgenome.push(create_input(input_id));
-            }
+    assert!(
+        Checker::new(constraints, &network).is_satisfied(),
+        "the initial topology generated a network that does not satisfy the structural constraints",
+    );
 
-            // Add the subgenome's root neuron
-            let subgenome_inputs = subgenome.len();
-            let neuron = Neuron::new(neuron_id, subgenome_inputs, INITIAL_WEIGHT_VALUE).into();
-            subgenome.insert(0, neuron);
+    let len = network.len();
+    initialize_weights(&mut network, 0..len, &options.weight_init, false, &mut rng);
 
-            subgenome
-        })
-        .collect();
+    network
+}
+
+#[cfg(test)]
+mod tests {
+    use cge::gene::{Input, InputId, Neuron, NeuronId};
+    use cge::Activation;
+
+    use std::sync::Mutex;
 
-    let mut network = Network::new(genome, activation).unwrap();
+    use crate::cge_utils::NetworkView;
+    use crate::gradient::{Dataset, Sample, SquaredError};
+    use crate::optimizer::{ParameterOptimizer, Problem};
+    use crate::options::{ConnectionMask, Constraints, Exploitation, Exploration, FineTuning};
+    use crate::utils::Zero;
 
-    // Ensure that all network inputs are connected to a neuron
-    let mut input_ids_not_connected = (0..inputs).collect::<HashSet<_>>();
+    use super::*;
 
-    for g in network.genome() {
-        if let Gene::Input(input) = g {
-            input_ids_not_connected.remove(&input.id().as_usize());
+    #[test]
+    fn test_seed_variants() {
+        let genome = vec![
+            Neuron::new(NeuronId::new(0), 2, 0.5).into(),
+            Input::new(InputId::new(0), 0.5).into(),
+            Input::new(InputId::new(1), 0.5).into(),
+        ];
+        let seed = Network::new(genome, Activation::Sigmoid).unwrap();
+        let options = EANT2::builder()
+            .inputs(2)
+            .outputs(1)
+            .seed(seed.clone())
+            .seeding(Seeding::Variants {
+                mutations: 2,
+                random: 0.5,
+            })
+            .seed_age(3)
+            .build();
+
+        let generation = Generation::initialize(&options, Arc::new(Zero));
+        let individuals = &generation.individuals;
+        assert_eq!(individuals.len(), options.exploration.population);
+        assert_eq!(individuals[0].network.genome(), seed.genome());
+        assert_eq!(individuals[0].ages, vec![3; seed.len()]);
+
+        for individual in individuals {
+            assert_eq!(individual.ages.len(), individual.network.len());
+            assert!(individual.mutations.is_empty());
         }
     }
 
-    for input_id in input_ids_not_connected
-        .into_iter()
-        .map(|id| InputId::new(id))
-    {
-        // Add unconnected network inputs to a random output neuron
-        let parent_id = NeuronId::new(rng.gen_range(0..outputs));
-        network
-            .add_non_neuron(parent_id, Input::new(input_id, INITIAL_WEIGHT_VALUE))
-            .unwrap();
+    #[test]
+    #[should_panic(expected = "random")]
+    fn test_invalid_random_fraction() {
+        EANT2::builder()
+            .inputs(2)
+            .outputs(1)
+            .seeding(Seeding::Variants {
+                mutations: 2,
+                random: 5.0,
+            })
+            .build();
     }
 
-    network
+    #[test]
+    #[should_panic(expected = "connection mask")]
+    fn test_connection_mask_dimensions() {
+        let options = EANT2::builder()
+            .inputs(3)
+            .outputs(1)
+            .exploration(
+                Exploration::builder()
+                    .constraints(
+                        Constraints::builder()
+                            .connections(ConnectionMask::allow_all(2, 1))
+                            .build(),
+                    )
+                    .build(),
+            )
+            .build();
+
+        Generation::initialize(&options, Arc::new(Zero));
+    }
+
+    #[test]
+    #[should_panic(expected = "every sample of the dataset")]
+    fn test_dataset_dimensions() {
+        #[derive(Clone)]
+        struct Supervised(Dataset);
+
+        impl FitnessFunction for Supervised {
+            fn fitness(&self, _: NetworkView) -> f64 {
+                0.0
+            }
+
+            fn dataset(&self) -> Option<&Dataset> {
+                Some(&self.0)
+            }
+        }
+
+        let samples = vec![
+            Sample::new(vec![0.0, 1.0], vec![1.0]),
+            Sample::new(vec![1.0, 0.0], vec![1.0, 0.0]),
+        ];
+        let dataset = Dataset::new(samples, SquaredError);
+        let options = EANT2::builder()
+            .inputs(2)
+            .outputs(1)
+            .exploitation(
+                Exploitation::builder()
+                    .fine_tuning(FineTuning::builder().build())
+                    .build(),
+            )
+            .build();
+
+        Generation::initialize(&options, Arc::new(Supervised(dataset)));
+    }
+
+    /// Records the evaluation budget of each optimization and keeps the initial weights.
+    #[derive(Clone, Default)]
+    struct Recording {
+        budgets: Arc<Mutex<Vec<Option<usize>>>>,
+    }
+
+    impl ParameterOptimizer for Recording {
+        fn optimize(
+            &self,
+            problem: &Problem,
+            objective: &mut dyn FnMut(&[f64]) -> f64,
+        ) -> (Vec<f64>, f64) {
+            self.budgets.lock().unwrap().push(problem.max_evaluations);
+            (problem.initial.to_vec(), objective(problem.initial))
+        }
+    }
+
+    #[test]
+    fn test_reoptimization_policies() {
+        let update = |policy, fitness| {
+            let recording = Recording::default();
+            let options = EANT2::builder()
+                .inputs(1)
+                .outputs(1)
+                .exploitation(
+                    Exploitation::builder()
+                        .optimizer(recording.clone())
+                        .reoptimization(policy)
+                        .build(),
+                )
+                .build();
+            let mut generation = Generation::initialize(&options, Arc::new(Zero));
+            generation.individuals.truncate(1);
+            generation.individuals[0].fitness = fitness;
+
+            generation.update_generation(&options, 0.0);
+            let first = generation.individuals[0].clone();
+            generation.update_generation(&options, 0.0);
+            let second = generation.individuals[0].clone();
+            let budgets = recording.budgets.lock().unwrap().clone();
+            (first, second, budgets)
+        };
+
+        // New structures are always optimized without a reduced budget
+        let (first, _, budgets) = update(Reoptimization::Reduced(5), None);
+        assert_eq!(first.fitness, Some(0.0));
+        assert_eq!(first.improvement, None);
+        assert_eq!(budgets, vec![None, Some(5)]);
+
+        // Re-optimization is skipped once, and then records the improvement
+        let (first, second, budgets) = update(Reoptimization::Every(2), Some(3.0));
+        assert_eq!((first.skipped, first.fitness), (1, Some(3.0)));
+        assert_eq!((second.skipped, second.fitness), (0, Some(0.0)));
+        assert_eq!(second.improvement, Some(3.0));
+        assert_eq!(budgets, vec![None]);
+
+        let (first, second, budgets) = update(Reoptimization::Never, Some(3.0));
+        assert_eq!((first.skipped, second.skipped), (1, 2));
+        assert!(budgets.is_empty());
+    }
 }
@@ -0,0 +1,694 @@
+//! Analytic gradients through networks for supervised tasks, and gradient-based fine-tuning of the
+//! weights.
+//!
+//! CMA-ES only uses fitness values, so it needs many evaluations to tune networks with many
+//! weights. When the fitness is a loss over a dataset, the fitness function can provide the dataset
+//! through [`FitnessFunction::dataset`][crate::FitnessFunction::dataset], and the gradient of the
+//! loss with respect to the weights is computed by backpropagation through the network. Recurrent
+//! jumpers are handled by backpropagation through time, which can be truncated for long sequences.
+//! The `fine_tuning` option of [`Exploitation`][crate::options::Exploitation] uses the gradient to
+//! replace or follow the optimizer.
+
+use cge::gene::Gene;
+use cge::Activation;
+
+use std::sync::Arc;
+
+use crate::cge_utils::Network;
+use crate::options::{FineTuning, GradientMethod};
+
+/// A sample of a supervised dataset.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    /// The inputs to the network.
+    pub inputs: Vec<f64>,
+    /// The target outputs of the network.
+    pub targets: Vec<f64>,
+}
+
+impl Sample {
+    /// Creates a sample with the given inputs and target outputs.
+    pub fn new(inputs: Vec<f64>, targets: Vec<f64>) -> Self {
+        Self { inputs, targets }
+    }
+}
+
+/// A differentiable loss of the outputs of a network for a single sample.
+pub trait Loss: Send + Sync {
+    /// Returns the loss of `outputs` given `targets`.
+    fn loss(&self, outputs: &[f64], targets: &[f64]) -> f64;
+
+    /// Writes the gradient of the loss with respect to each output into `gradient`.
+    fn gradient(&self, outputs: &[f64], targets: &[f64], gradient: &mut [f64]);
+}
+
+/// The sum of the squared errors of the outputs.
+#[derive(Clone, Copy, Debug)]
+pub struct SquaredError;
+
+impl Loss for SquaredError {
+    fn loss(&self, outputs: &[f64], targets: &[f64]) -> f64 {
+        outputs
+            .iter()
+            .zip(targets)
+            .map(|(output, target)| (output - target).powi(2))
+            .sum()
+    }
+
+    fn gradient(&self, outputs: &[f64], targets: &[f64], gradient: &mut [f64]) {
+        for ((g, output), target) in gradient.iter_mut().zip(outputs).zip(targets) {
+            *g = 2.0 * (output - target);
+        }
+    }
+}
+
+/// The binary cross-entropy of the outputs, which must be probabilities (e.g., from the sigmoid
+/// activation). Outputs are clamped away from 0 and 1 to keep the loss finite.
+#[derive(Clone, Copy, Debug)]
+pub struct CrossEntropy;
+
+impl CrossEntropy {
+    const EPSILON: f64 = 1e-12;
+}
+
+impl Loss for CrossEntropy {
+    fn loss(&self, outputs: &[f64], targets: &[f64]) -> f64 {
+        outputs
+            .iter()
+            .zip(targets)
+            .map(|(output, target)| {
+                let p = output.clamp(Self::EPSILON, 1.0 - Self::EPSILON);
+                -(target * p.ln() + (1.0 - target) * (1.0 - p).ln())
+            })
+            .sum()
+    }
+
+    fn gradient(&self, outputs: &[f64], targets: &[f64], gradient: &mut [f64]) {
+        for ((g, output), target) in gradient.iter_mut().zip(outputs).zip(targets) {
+            let p = output.clamp(Self::EPSILON, 1.0 - Self::EPSILON);
+            *g = (p - target) / (p * (1.0 - p));
+        }
+    }
+}
+
+/// A supervised dataset, made of sequences of samples that are evaluated in order. Each sequence
+/// starts from a cleared recurrent state, so the fitness function should clear the state of the
+/// network at the start of each sequence as well. The loss of the network is the mean loss over
+/// all samples.
+#[derive(Clone)]
+pub struct Dataset {
+    /// The sequences of samples, each of which starts from a cleared recurrent state.
+    pub sequences: Vec<Vec<Sample>>,
+    /// The loss of the outputs of the network for each sample.
+    pub loss: Arc<dyn Loss>,
+}
+
+impl Dataset {
+    /// Creates a dataset of independent samples, each of which is evaluated from a cleared state.
+    pub fn new(samples: Vec<Sample>, loss: impl Loss + 'static) -> Self {
+        Self::sequences(samples.into_iter().map(|s| vec![s]).collect(), loss)
+    }
+
+    /// Creates a dataset of sequences of samples.
+    pub fn sequences(sequences: Vec<Vec<Sample>>, loss: impl Loss + 'static) -> Self {
+        Self {
+            sequences,
+            loss: Arc::new(loss),
+        }
+    }
+
+    /// Returns the total number of samples.
+    pub fn len(&self) -> usize {
+        self.sequences.iter().map(Vec::len).sum()
+    }
+
+    /// Returns whether the dataset has no samples.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns whether the dataset can be used with a network with the given numbers of inputs
+    /// and outputs, which is the case if every sample has at least `inputs` inputs and exactly
+    /// `outputs` targets.
+    pub fn fits(&self, inputs: usize, outputs: usize) -> bool {
+        self.sequences
+            .iter()
+            .flatten()
+            .all(|sample| sample.inputs.len() >= inputs && sample.targets.len() == outputs)
+    }
+}
+
+/// Returns the mean loss of the network over the dataset and its gradient with respect to the
+/// weights of the network, in genome order.
+///
+/// If `truncation` is set, each sequence is split into windows of that many steps, and gradients
+/// are not propagated back through recurrent jumpers across the windows.
+///
+/// Panics if the dataset does not fit the network (see [`Dataset::fits`]).
+pub fn gradient(
+    network: &Network,
+    dataset: &Dataset,
+    truncation: Option<usize>,
+) -> (f64, Vec<f64>) {
+    let graph = Graph::new(network);
+    assert!(
+        dataset.fits(graph.num_inputs, graph.outputs.len()),
+        "the dataset does not fit the inputs and outputs of the network"
+    );
+    let weights = network.weights().collect::<Vec<_>>();
+    let mut gradient = vec![0.0; weights.len()];
+    let loss = graph.gradient(&weights, dataset, truncation, &mut gradient);
+    (loss, gradient)
+}
+
+/// Minimizes the loss of the network over the dataset starting from `initial`, and returns the
+/// weights with the lowest loss found. The search is scaled by `deviations` like the optimizer
+/// (see `optimizer::Problem`), so the steps of each weight are proportional to its deviation.
+///
+/// The dataset must fit the network, which is checked once at the start of the run.
+pub(crate) fn fine_tune(
+    network: &Network,
+    dataset: &Dataset,
+    options: &FineTuning,
+    initial: &[f64],
+    deviations: &[f64],
+) -> Vec<f64> {
+    let graph = Graph::new(network);
+    match options.method {
+        GradientMethod::Adam { learning_rate } => {
+            adam(&graph, dataset, options, learning_rate, initial, deviations)
+        }
+        GradientMethod::Lbfgs { memory } => {
+            lbfgs(&graph, dataset, options, memory, initial, deviations)
+        }
+    }
+}
+
+/// Adam, with the gradient and the steps of each weight scaled by its deviation.
+fn adam(
+    graph: &Graph,
+    dataset: &Dataset,
+    options: &FineTuning,
+    learning_rate: f64,
+    initial: &[f64],
+    deviations: &[f64],
+) -> Vec<f64> {
+    const BETA_1: f64 = 0.9;
+    const BETA_2: f64 = 0.999;
+    const EPSILON: f64 = 1e-8;
+
+    let n = initial.len();
+    let mut weights = initial.to_vec();
+    let mut gradient = vec![0.0; n];
+    let mut first_moment = vec![0.0; n];
+    let mut second_moment = vec![0.0; n];
+    let mut best = (weights.clone(), f64::INFINITY);
+
+    for step in 1..=options.steps {
+        let loss = graph.gradient(&weights, dataset, options.truncation, &mut gradient);
+        if loss < best.1 {
+            best = (weights.clone(), loss);
+        }
+        scale(&mut gradient, deviations);
+        if norm(&gradient) <= options.tolerance {
+            return best.0;
+        }
+
+        let correction_1 = 1.0 - BETA_1.powi(step as i32);
+        let correction_2 = 1.0 - BETA_2.powi(step as i32);
+        for i in 0..n {
+            first_moment[i] = BETA_1 * first_moment[i] + (1.0 - BETA_1) * gradient[i];
+            second_moment[i] = BETA_2 * second_moment[i] + (1.0 - BETA_2) * gradient[i].powi(2);
+            let update = (first_moment[i] / correction_1)
+                / ((second_moment[i] / correction_2).sqrt() + EPSILON);
+            weights[i] -= learning_rate * deviations[i] * update;
+        }
+    }
+
+    if graph.loss(&weights, dataset) < best.1 {
+        best.0 = weights;
+    }
+    best.0
+}
+
+/// L-BFGS with a backtracking line search, in coordinates scaled by the deviations.
+fn lbfgs(
+    graph: &Graph,
+    dataset: &Dataset,
+    options: &FineTuning,
+    memory: usize,
+    initial: &[f64],
+    deviations: &[f64],
+) -> Vec<f64> {
+    const SUFFICIENT_DECREASE: f64 = 1e-4;
+    const MAX_BACKTRACKS: usize = 30;
+
+    let n = initial.len();
+    let mut weights = initial.to_vec();
+    let mut gradient = vec![0.0; n];
+    let mut loss = graph.gradient(&weights, dataset, options.truncation, &mut gradient);
+    scale(&mut gradient, deviations);
+
+    // The most recent position and gradient differences, oldest first
+    let mut history: Vec<(Vec<f64>, Vec<f64>, f64)> = Vec::with_capacity(memory);
+    let mut candidate = vec![0.0; n];
+    let mut candidate_gradient = vec![0.0; n];
+
+    for _ in 0..options.steps {
+        if norm(&gradient) <= options.tolerance {
+            break;
+        }
+
+        // Two-loop recursion for the search direction
+        let mut direction = gradient.iter().map(|g| -g).collect::<Vec<_>>();
+        let mut alphas = Vec::with_capacity(history.len());
+        for (s, y, rho) in history.iter().rev() {
+            let alpha = rho * dot(s, &direction);
+            for (d, y) in direction.iter_mut().zip(y) {
+                *d -= alpha * y;
+            }
+            alphas.push(alpha);
+        }
+        let gamma = match history.last() {
+            Some((s, y, _)) => dot(s, y) / dot(y, y),
+            None => 1.0 / norm(&gradient),
+        };
+        for d in &mut direction {
+            *d *= gamma;
+        }
+        for ((s, y, rho), alpha) in history.iter().zip(alphas.into_iter().rev()) {
+            let beta = rho * dot(y, &direction);
+            for (d, s) in direction.iter_mut().zip(s) {
+                *d += (alpha - beta) * s;
+            }
+        }
+
+        // Backtracking line search for sufficient decrease
+        let slope = dot(&gradient, &direction);
+        if slope >= 0.0 {
+            break;
+        }
+        let mut step = 1.0;
+        let mut accepted = None;
+        for _ in 0..MAX_BACKTRACKS {
+            for i in 0..n {
+                candidate[i] = weights[i] + step * direction[i] * deviations[i];
+            }
+            let candidate_loss = graph.gradient(
+                &candidate,
+                dataset,
+                options.truncation,
+                &mut candidate_gradient,
+            );
+            if candidate_loss <= loss + SUFFICIENT_DECREASE * step * slope {
+                accepted = Some(candidate_loss);
+                break;
+            }
+            step /= 2.0;
+        }
+        let Some(candidate_loss) = accepted else {
+            break;
+        };
+        scale(&mut candidate_gradient, deviations);
+
+        let s = direction.iter().map(|d| step * d).collect::<Vec<_>>();
+        let y = candidate_gradient
+            .iter()
+            .zip(&gradient)
+            .map(|(new, old)| new - old)
+            .collect::<Vec<_>>();
+        let curvature = dot(&s, &y);
+        if curvature > 1e-10 {
+            if history.len() == memory {
+                history.remove(0);
+            }
+            history.push((s, y, 1.0 / curvature));
+        }
+
+        weights.copy_from_slice(&candidate);
+        gradient.copy_from_slice(&candidate_gradient);
+        loss = candidate_loss;
+    }
+
+    weights
+}
+
+fn scale(gradient: &mut [f64], deviations: &[f64]) {
+    for (g, deviation) in gradient.iter_mut().zip(deviations) {
+        *g *= deviation;
+    }
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+fn norm(a: &[f64]) -> f64 {
+    dot(a, a).sqrt()
+}
+
+/// A gene of the network, with jumpers referring to the gene index of their source neuron.
+#[derive(Clone, Copy)]
+enum Node {
+    Bias,
+    Input(usize),
+    Neuron,
+    ForwardJumper(usize),
+    RecurrentJumper(usize),
+}
+
+/// The structure of a network, which is evaluated one neuron at a time rather than on a stack so
+/// that the intermediate values can be kept for backpropagation. Values are indexed by the gene
+/// index of their neuron.
+struct Graph {
+    nodes: Vec<Node>,
+    /// The gene indices of the inputs of each neuron, empty for other genes
+    children: Vec<Vec<usize>>,
+    /// The gene indices of the neurons, each after every neuron it reads from in the same step
+    order: Vec<usize>,
+    /// The gene indices of the output neurons in the order of the outputs of the network
+    outputs: Vec<usize>,
+    num_inputs: usize,
+    activation: Activation,
+}
+
+/// The sums of inputs and the values of the neurons in a single step.
+struct Step {
+    sums: Vec<f64>,
+    values: Vec<f64>,
+}
+
+impl Graph {
+    fn new(network: &Network) -> Self {
+        let index_of = |id| network.neuron_info(id).unwrap().subgenome_range().start;
+        let nodes = network
+            .genome()
+            .iter()
+            .map(|gene| match gene {
+                Gene::Bias(_) => Node::Bias,
+                Gene::Input(input) => Node::Input(input.id().as_usize()),
+                Gene::Neuron(_) => Node::Neuron,
+                Gene::ForwardJumper(forward) => Node::ForwardJumper(index_of(forward.source_id())),
+                Gene::RecurrentJumper(recurrent) => {
+                    Node::RecurrentJumper(index_of(recurrent.source_id()))
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut children = vec![Vec::new(); nodes.len()];
+        let mut outputs = Vec::new();
+        for (index, parent) in network.parents().iter().enumerate() {
+            match parent {
+                Some(parent) => children[index_of(*parent)].push(index),
+                None => outputs.push(index),
+            }
+        }
+        // The network leaves the last output in the genome first on its stack
+        outputs.reverse();
+
+        // Depth-first post-order over the neurons read by each neuron in the same step
+        fn visit(
+            index: usize,
+            nodes: &[Node],
+            children: &[Vec<usize>],
+            visited: &mut [bool],
+            order: &mut Vec<usize>,
+        ) {
+            if visited[index] {
+                return;
+            }
+            visited[index] = true;
+            for &child in &children[index] {
+                match nodes[child] {
+                    Node::Neuron => visit(child, nodes, children, visited, order),
+                    Node::ForwardJumper(source) => visit(source, nodes, children, visited, order),
+                    _ => {}
+                }
+            }
+            order.push(index);
+        }
+        let mut visited = vec![false; nodes.len()];
+        let mut order = Vec::with_capacity(network.num_neurons());
+        for &output in &outputs {
+            visit(output, &nodes, &children, &mut visited, &mut order);
+        }
+
+        Self {
+            nodes,
+            children,
+            order,
+            outputs,
+            num_inputs: network.num_inputs(),
+            activation: network.activation(),
+        }
+    }
+
+    /// Returns the value that the gene at `index` contributes to its parent before weighting.
+    fn input_value(&self, index: usize, inputs: &[f64], step: &Step, previous: &[f64]) -> f64 {
+        match self.nodes[index] {
+            Node::Bias => 1.0,
+            Node::Input(id) => inputs[id],
+            Node::Neuron => step.values[index],
+            Node::ForwardJumper(source) => step.values[source],
+            Node::RecurrentJumper(source) => previous[source],
+        }
+    }
+
+    /// Evaluates a single step given the values of the neurons in the previous step.
+    fn forward(&self, weights: &[f64], inputs: &[f64], previous: &[f64]) -> Step {
+        debug_assert!(inputs.len() >= self.num_inputs);
+        let mut step = Step {
+            sums: vec![0.0; self.nodes.len()],
+            values: vec![0.0; self.nodes.len()],
+        };
+        for &neuron in &self.order {
+            let sum = self.children[neuron]
+                .iter()
+                .map(|&child| weights[child] * self.input_value(child, inputs, &step, previous))
+                .sum::<f64>();
+            step.sums[neuron] = sum;
+            step.values[neuron] = self.activation.apply(sum);
+        }
+        step
+    }
+
+    /// Returns the outputs of the network, which are the values of the output neurons multiplied
+    /// by their weights.
+    fn outputs(&self, weights: &[f64], step: &Step) -> Vec<f64> {
+        self.outputs
+            .iter()
+            .map(|&o| weights[o] * step.values[o])
+            .collect()
+    }
+
+    /// Returns the mean loss over the dataset.
+    fn loss(&self, weights: &[f64], dataset: &Dataset) -> f64 {
+        let mut total = 0.0;
+        for sequence in &dataset.sequences {
+            let mut previous = vec![0.0; self.nodes.len()];
+            for sample in sequence {
+                let step = self.forward(weights, &sample.inputs, &previous);
+                total += dataset
+                    .loss
+                    .loss(&self.outputs(weights, &step), &sample.targets);
+                previous = step.values;
+            }
+        }
+        total / dataset.len().max(1) as f64
+    }
+
+    /// Returns the mean loss over the dataset and writes its gradient into `gradient`.
+    fn gradient(
+        &self,
+        weights: &[f64],
+        dataset: &Dataset,
+        truncation: Option<usize>,
+        gradient: &mut [f64],
+    ) -> f64 {
+        let scale = 1.0 / dataset.len().max(1) as f64;
+        let mut total = 0.0;
+        gradient.fill(0.0);
+        let mut output_gradient = vec![0.0; self.outputs.len()];
+
+        for sequence in &dataset.sequences {
+            let zeros = vec![0.0; self.nodes.len()];
+            let mut steps: Vec<Step> = Vec::with_capacity(sequence.len());
+            for sample in sequence {
+                let previous = steps.last().map_or(&zeros, |step| &step.values);
+                let step = self.forward(weights, &sample.inputs, previous);
+                steps.push(step);
+            }
+
+            // The gradient with respect to the values of the neurons, carried back from the next
+            // step through recurrent jumpers
+            let mut carried = vec![0.0; self.nodes.len()];
+            for (t, (sample, step)) in sequence.iter().zip(&steps).enumerate().rev() {
+                let previous = if t == 0 { &zeros } else { &steps[t - 1].values };
+                let outputs = self.outputs(weights, step);
+                total += dataset.loss.loss(&outputs, &sample.targets);
+                dataset
+                    .loss
+                    .gradient(&outputs, &sample.targets, &mut output_gradient);
+
+                let mut values = std::mem::replace(&mut carried, vec![0.0; self.nodes.len()]);
+                for (&output, g) in self.outputs.iter().zip(&output_gradient) {
+                    gradient[output] += scale * g * step.values[output];
+                    values[output] += scale * g * weights[output];
+                }
+
+                for &neuron in self.order.iter().rev() {
+                    let delta = values[neuron]
+                        * derivative(self.activation, step.sums[neuron], step.values[neuron]);
+                    if delta == 0.0 {
+                        continue;
+                    }
+                    for &child in &self.children[neuron] {
+                        gradient[child] +=
+                            delta * self.input_value(child, &sample.inputs, step, previous);
+                        match self.nodes[child] {
+                            Node::Neuron => values[child] += delta * weights[child],
+                            Node::ForwardJumper(source) => values[source] += delta * weights[child],
+                            Node::RecurrentJumper(source) => {
+                                carried[source] += delta * weights[child]
+                            }
+                            Node::Bias | Node::Input(_) => {}
+                        }
+                    }
+                }
+
+                if truncation.is_some_and(|k| t % k.max(1) == 0) {
+                    carried.fill(0.0);
+                }
+            }
+        }
+
+        total * scale
+    }
+}
+
+/// Returns the derivative of the activation function given its input and output.
+fn derivative(activation: Activation, sum: f64, value: f64) -> f64 {
+    match activation {
+        Activation::Linear => 1.0,
+        Activation::UnitStep | Activation::Sign => 0.0,
+        Activation::Sigmoid => value * (1.0 - value),
+        Activation::Tanh => 1.0 - value * value,
+        Activation::SoftSign => 1.0 / (1.0 + sum.abs()).powi(2),
+        Activation::BentIdentity => sum / (2.0 * (sum * sum + 1.0).sqrt()) + 1.0,
+        Activation::Relu => {
+            if sum > 0.0 {
+                1.0
+            } else {
+                0.0
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cge::gene::{Bias, ForwardJumper, Input, InputId, Neuron, NeuronId, RecurrentJumper};
+
+    use super::*;
+    use crate::options::FineTuningMode;
+
+    /// A network with two outputs, a forward jumper and two recurrent jumpers.
+    fn network() -> Network {
+        let genome = vec![
+            Neuron::new(NeuronId::new(0), 3, 1.2).into(),
+            Neuron::new(NeuronId::new(1), 3, 0.7).into(),
+            Input::new(InputId::new(0), 0.5).into(),
+            Input::new(InputId::new(1), -0.8).into(),
+            RecurrentJumper::new(NeuronId::new(0), 0.6).into(),
+            Bias::new(0.2).into(),
+            Input::new(InputId::new(1), 1.1).into(),
+            Neuron::new(NeuronId::new(2), 3, 0.8).into(),
+            ForwardJumper::new(NeuronId::new(1), -0.9).into(),
+            RecurrentJumper::new(NeuronId::new(2), 0.4).into(),
+            Bias::new(-0.3).into(),
+        ];
+        Network::new(genome, Activation::Tanh).unwrap()
+    }
+
+    fn dataset() -> Dataset {
+        let sequence = |offset: f64| {
+            (0..5)
+                .map(|t| {
+                    let t = t as f64 + offset;
+                    Sample::new(vec![t.sin(), t.cos()], vec![0.5 * t.cos(), -0.2])
+                })
+                .collect()
+        };
+        Dataset::sequences(vec![sequence(0.0), sequence(1.5)], SquaredError)
+    }
+
+    /// The mean loss computed with `Network::evaluate`.
+    fn loss(network: &mut Network, dataset: &Dataset) -> f64 {
+        let mut total = 0.0;
+        for sequence in &dataset.sequences {
+            network.clear_state();
+            for sample in sequence {
+                let outputs = network.evaluate(&sample.inputs).unwrap();
+                total += dataset.loss.loss(outputs, &sample.targets);
+            }
+        }
+        total / dataset.len() as f64
+    }
+
+    #[test]
+    fn test_gradient() {
+        let mut network = network();
+        let dataset = dataset();
+        let (value, gradient) = super::gradient(&network, &dataset, None);
+        assert!((value - loss(&mut network, &dataset)).abs() < 1e-12);
+
+        // Compare with central finite differences
+        let weights = network.weights().collect::<Vec<_>>();
+        for i in 0..weights.len() {
+            let mut shifted = weights.clone();
+            shifted[i] += 1e-6;
+            network.set_weights(&shifted).unwrap();
+            let above = loss(&mut network, &dataset);
+            shifted[i] -= 2e-6;
+            network.set_weights(&shifted).unwrap();
+            let below = loss(&mut network, &dataset);
+            let expected = (above - below) / 2e-6;
+            assert!(
+                (gradient[i] - expected).abs() < 1e-6,
+                "{} {} {}",
+                i,
+                gradient[i],
+                expected
+            );
+        }
+
+        // Truncating to single steps removes the gradient through recurrent jumpers
+        let (_, truncated) = super::gradient(&network, &dataset, Some(1));
+        assert_ne!(truncated[4], gradient[4]);
+    }
+
+    #[test]
+    fn test_fine_tune() {
+        let network = network();
+        let dataset = dataset();
+        let initial = network.weights().collect::<Vec<_>>();
+        let deviations = vec![1.0; initial.len()];
+        let (before, _) = super::gradient(&network, &dataset, None);
+
+        for method in [
+            GradientMethod::Adam {
+                learning_rate: 0.05,
+            },
+            GradientMethod::Lbfgs { memory: 5 },
+        ] {
+            let options = FineTuning::builder()
+                .method(method)
+                .mode(FineTuningMode::Replace)
+                .steps(50)
+                .build();
+            let tuned = fine_tune(&network, &dataset, &options, &initial, &deviations);
+            let graph = Graph::new(&network);
+            assert!(graph.loss(&tuned, &dataset) < 0.5 * before, "{:?}", method);
+        }
+    }
+}
@@ -7,17 +7,17 @@
 //! Here are some tips for creating a good one:
 //!
 //! 1. Lower the amount of inputs and outputs to each neural network. Design the fitness function
-//! to either do some of the work, or just simplify the problem. Another option is to train a
-//! separate neural network to do processing of raw inputs, then feed its simplified output to the
-//! EANT2 network.
+//!    to either do some of the work, or just simplify the problem. Another option is to train a
+//!    separate neural network to do processing of raw inputs, then feed its simplified output to the
+//!    EANT2 network.
 //!
 //! 2. Run the algorithm on good hardware to take advantage of the multithreaded support. The
-//! algorithm is a one time thing; create a neural network with it and distribute and run it on
-//! less powerful computers. On a related note, it is okay if it takes some time to run because it
-//! will only have to run once.
+//!    algorithm is a one time thing; create a neural network with it and distribute and run it on
+//!    less powerful computers. On a related note, it is okay if it takes some time to run because it
+//!    will only have to run once.
 //!
 //! 3. Adjust individual options for the algorithm through `EANT2Options`. The documentation
-//! provides a lot of information on how each option effects the algorithm.
+//!    provides a lot of information on how each option effects the algorithm.
 //!
 //! Note that it is important to get everything right the first time, otherwise the results might
 //! be below expectation, which can mean running the algorithm again.
@@ -26,15 +26,26 @@
 //!
 //! Complete this section when the project is finished
 
+pub mod canonical;
 mod cge_utils;
 mod cmaes_utils;
+mod constraints;
+mod crossover;
 pub mod eant2;
 pub mod fitness;
+pub mod genealogy;
 mod generation;
+pub mod gradient;
 mod mutation;
 pub mod mutation_probabilities;
+pub mod optimizer;
 pub mod options;
+pub mod prune;
 mod select;
+pub mod selection;
+pub mod speciation;
+pub mod statistics;
+pub mod topology;
 mod utils;
 
 pub use cge::Activation;
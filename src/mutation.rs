@@ -33,14 +33,16 @@ use cge::gene::{
     Bias, ForwardJumper, Gene, Input, InputId, NeuronId, NonNeuronGene, RecurrentJumper,
 };
 use rand::rngs::ThreadRng;
-use rand::seq::IteratorRandom;
+use rand::seq::{IteratorRandom, SliceRandom};
 use rand::{thread_rng, Rng};
 
 use std::collections::HashSet;
-use std::iter;
 
-use crate::cge_utils::INITIAL_WEIGHT_VALUE;
+use crate::cge_utils::{initialize_weights, INITIAL_WEIGHT_VALUE};
+use crate::constraints::Checker;
+use crate::genealogy::{MutationRecord, Source};
 use crate::mutation_probabilities::MutationSampler;
+use crate::options::{Constraints, WeightInit};
 use crate::utils::{self, Individual};
 use crate::FitnessFunction;
 
@@ -50,7 +52,7 @@ use crate::FitnessFunction;
 const NEW_SUBNETWORK_FORWARD_CONNECTION_PROBABILITY: f64 = 0.2;
 
 /// The type of mutation to perform.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum MutationType {
     AddConnection,
     RemoveConnection,
@@ -59,45 +61,79 @@ pub enum MutationType {
 }
 
 /// Tries to apply a random mutation operator to the network. Returns whether any mutation was
-/// actually performed.
+/// actually performed, in which case it is also recorded in the individual.
+///
+/// Only mutations that keep the network within `constraints` are considered. The weights of new
+/// genes are initialized according to `weight_init`.
 pub fn mutate<T: FitnessFunction + Clone>(
     individual: &mut Individual<T>,
     probabilities: &MutationSampler,
+    constraints: &Constraints,
+    weight_init: &WeightInit,
 ) -> bool {
     let mut rng = thread_rng();
+    let kind = probabilities.sample(&mut rng);
+    // Mutations may record further genes they added themselves, which follow their own record
+    let recorded = individual.mutations.len();
 
-    match probabilities.sample(&mut rng) {
-        MutationType::AddConnection => add_connection(individual, &mut rng),
-        MutationType::AddNode => add_subnetwork(individual, &mut rng),
-        MutationType::AddBias => add_bias(individual, &mut rng),
+    let mutated = match kind {
+        MutationType::AddConnection => {
+            add_connection(individual, constraints, weight_init, &mut rng)
+        }
+        MutationType::AddNode => add_subnetwork(individual, constraints, weight_init, &mut rng),
+        MutationType::AddBias => add_bias(individual, constraints, weight_init, &mut rng),
         MutationType::RemoveConnection => remove_connection(individual, &mut rng),
+    };
+
+    if let Some((parent, source)) = mutated {
+        individual.mutations.insert(
+            recorded,
+            MutationRecord {
+                kind,
+                parent,
+                source,
+            },
+        );
     }
+
+    mutated.is_some()
 }
 
-/// Randomly adds a connection between two neurons or a neuron and a network input. Returns whether
-/// any mutation was performed.
+/// Randomly adds a connection between two neurons or a neuron and a network input. Returns the
+/// parent neuron and source of the new connection if any mutation was performed.
 ///
 /// The original paper was not clear about whether input genes count as connections, but this
 /// function assumes they do and therefore may add them.
 fn add_connection<T: FitnessFunction + Clone>(
     individual: &mut Individual<T>,
+    constraints: &Constraints,
+    weight_init: &WeightInit,
     rng: &mut ThreadRng,
-) -> bool {
+) -> Option<(NeuronId, Source)> {
     // TODO: Add option to customize the probabilities for these connection types
-    match rng.gen_range(0..=2) {
-        0 => add_forward_jumper(individual, rng),
-        1 => add_recurrent_jumper(individual, rng),
-        2 => add_input(individual, rng),
+    // Recurrent jumpers are skipped entirely in feedforward networks
+    let connection_types = if constraints.feedforward { 2 } else { 3 };
+    match rng.gen_range(0..connection_types) {
+        0 => add_forward_jumper(individual, constraints, weight_init, rng),
+        1 => add_input(individual, constraints, weight_init, rng),
+        2 => add_recurrent_jumper(individual, constraints, weight_init, rng),
         _ => unreachable!(),
     }
 }
 
-/// Randomly adds a forward jumper gene to the network. Returns whether any mutation was performed.
+/// Randomly adds a forward jumper gene to the network. Returns the parent neuron and source of the
+/// changed gene if any mutation was performed.
 fn add_forward_jumper<T: FitnessFunction + Clone>(
     individual: &mut Individual<T>,
+    constraints: &Constraints,
+    weight_init: &WeightInit,
     rng: &mut ThreadRng,
-) -> bool {
-    let network = &mut individual.network;
+) -> Option<(NeuronId, Source)> {
+    let network = &individual.network;
+    let checker = Checker::new(constraints, network);
+    if checker.gene_budget() == 0 {
+        return None;
+    }
 
     // Find all valid, non-redundant forward jumper connections between neurons
     let valid_connections = network.neuron_ids().flat_map(|parent_id| {
@@ -122,24 +158,32 @@ fn add_forward_jumper<T: FitnessFunction + Clone>(
             .into_iter()
             .map(move |source_id| (parent_id, source_id))
     });
+    let valid_connections = valid_connections
+        .filter(|(parent_id, source_id)| checker.allows_source(*parent_id, *source_id));
 
     // Choose one at random and add it
     if let Some((parent, source)) = valid_connections.choose(rng) {
         let input = ForwardJumper::new(source, INITIAL_WEIGHT_VALUE);
-        add_non_neuron(individual, parent, input);
-        true
+        add_non_neuron(individual, parent, input, weight_init, rng);
+        Some((parent, Source::ForwardJumper(source)))
     } else {
-        false
+        None
     }
 }
 
-/// Randomly adds a recurrent jumper gene to the network. Returns whether any mutation was
-/// performed.
+/// Randomly adds a recurrent jumper gene to the network. Returns the parent neuron and source of
+/// the changed gene if any mutation was performed.
 fn add_recurrent_jumper<T: FitnessFunction + Clone>(
     individual: &mut Individual<T>,
+    constraints: &Constraints,
+    weight_init: &WeightInit,
     rng: &mut ThreadRng,
-) -> bool {
-    let network = &mut individual.network;
+) -> Option<(NeuronId, Source)> {
+    let network = &individual.network;
+    let checker = Checker::new(constraints, network);
+    if checker.gene_budget() == 0 {
+        return None;
+    }
 
     // Find all non-redundant recurrent jumper connections between neurons
     let valid_connections = network.neuron_ids().flat_map(|parent_id| {
@@ -156,23 +200,32 @@ fn add_recurrent_jumper<T: FitnessFunction + Clone>(
             .into_iter()
             .map(move |source_id| (parent_id, source_id))
     });
+    let valid_connections = valid_connections
+        .filter(|(parent_id, source_id)| checker.allows_source(*parent_id, *source_id));
 
     // Choose one at random and add it
     if let Some((parent, source)) = valid_connections.choose(rng) {
         let input = RecurrentJumper::new(source, INITIAL_WEIGHT_VALUE);
-        add_non_neuron(individual, parent, input);
-        true
+        add_non_neuron(individual, parent, input, weight_init, rng);
+        Some((parent, Source::RecurrentJumper(source)))
     } else {
-        false
+        None
     }
 }
 
-/// Randomly adds an input gene to the network. Returns whether any mutation was performed.
+/// Randomly adds an input gene to the network. Returns the parent neuron and source of the
+/// changed gene if any mutation was performed.
 fn add_input<T: FitnessFunction + Clone>(
     individual: &mut Individual<T>,
+    constraints: &Constraints,
+    weight_init: &WeightInit,
     rng: &mut ThreadRng,
-) -> bool {
-    let network = &mut individual.network;
+) -> Option<(NeuronId, Source)> {
+    let network = &individual.network;
+    let checker = Checker::new(constraints, network);
+    if checker.gene_budget() == 0 {
+        return None;
+    }
 
     // Find all non-redundant network input to neuron connections
     let valid_connections = network.neuron_ids().flat_map(|parent_id| {
@@ -190,40 +243,52 @@ fn add_input<T: FitnessFunction + Clone>(
             .into_iter()
             .map(move |input_id| (parent_id, InputId::new(input_id)))
     });
+    let valid_connections = valid_connections
+        .filter(|(parent_id, input_id)| checker.allows_input(*parent_id, input_id.as_usize()));
 
     // Choose one at random and add it
     if let Some((parent, id)) = valid_connections.choose(rng) {
         let input = Input::new(id, INITIAL_WEIGHT_VALUE);
-        add_non_neuron(individual, parent, input);
-        true
+        add_non_neuron(individual, parent, input, weight_init, rng);
+        Some((parent, Source::Input(id.as_usize())))
     } else {
-        false
+        None
     }
 }
 
 /// Adds a subnetwork to a random parent neuron in the network. Randomly connects network inputs and
-/// other neurons to it as inputs, and randomly connects its output to other neurons.
+/// other neurons to it as inputs, and randomly connects its output to other neurons. Returns the
+/// parent neuron and the root neuron of the subnetwork if any mutation was performed.
 ///
 /// The original paper was not clear about how forward jumper connections should be added here. This
 /// function simply applies a fixed probability to each possible connection, which results in more
 /// connections being added on average to larger networks than to smaller ones.
 fn add_subnetwork<T: FitnessFunction + Clone>(
     individual: &mut Individual<T>,
+    constraints: &Constraints,
+    weight_init: &WeightInit,
     rng: &mut ThreadRng,
-) -> bool {
-    let network = &mut individual.network;
+) -> Option<(NeuronId, Source)> {
+    let network = &individual.network;
+    let checker = Checker::new(constraints, network);
 
     // Choose a random parent neuron to add the subnetwork to
-    let parent = network.neuron_ids().choose(rng).unwrap();
+    let parent = network
+        .neuron_ids()
+        .filter(|id| checker.allows_neuron(*id))
+        .choose(rng)?;
 
     // Add random inputs to the subnetwork
     let mut subnetwork_inputs = Vec::new();
+    // The network inputs the subnetwork's output depends on
+    let mut subnetwork_reach = HashSet::new();
 
     // Each network input has a 50% chance of being connected
     for i in 0..individual.inputs {
-        if rng.gen() {
+        if rng.gen() && checker.allows_input(parent, i) {
             let input = Input::new(InputId::new(i), INITIAL_WEIGHT_VALUE);
             subnetwork_inputs.push(input.into());
+            subnetwork_reach.insert(i);
         }
     }
 
@@ -232,67 +297,104 @@ fn add_subnetwork<T: FitnessFunction + Clone>(
     let subnetwork_depth = parent_depth + 1;
 
     for id in network.get_valid_forward_jumper_sources(subnetwork_depth) {
-        if rng.gen::<f64>() < NEW_SUBNETWORK_FORWARD_CONNECTION_PROBABILITY {
+        if rng.gen::<f64>() < NEW_SUBNETWORK_FORWARD_CONNECTION_PROBABILITY
+            && checker.allows_source(parent, id)
+        {
             let forward = ForwardJumper::new(id, INITIAL_WEIGHT_VALUE);
             subnetwork_inputs.push(forward.into());
+            subnetwork_reach.extend(checker.reach(id));
         }
     }
 
     // If the subnetwork has no inputs, connect it to a random network input
     if subnetwork_inputs.is_empty() {
-        if individual.inputs > 0 {
-            let id = rng.gen_range(0..individual.inputs);
+        let allowed_input = (0..individual.inputs)
+            .filter(|i| checker.allows_input(parent, *i))
+            .choose(rng);
+
+        if let Some(id) = allowed_input {
             let input = Input::new(InputId::new(id), INITIAL_WEIGHT_VALUE);
             subnetwork_inputs.push(input.into());
+            subnetwork_reach.insert(id);
         } else {
-            // If the network has no inputs, no subnetwork is added
+            // If the network has no (allowed) inputs, no subnetwork is added
             // It may be possible to find a different parent or input connection to add, but this case should be
             // extremely rare anyways
-            return false;
+            return None;
         }
     }
 
-    // Insert new age counters for the subnetwork's genes
-    let parent_index = network[parent].subgenome_range().start;
-    let subgenome_index = parent_index + 1;
-    // Each element of `subnetwork_inputs` is a new gene, plus the subnetwork's root neuron itself
-    let num_new_genes = 1 + subnetwork_inputs.len();
-    individual.ages.splice(
-        subgenome_index..subgenome_index,
-        iter::repeat(0).take(num_new_genes),
-    );
-
-    // Add the subnetwork
-    let subnetwork_id = network
-        .add_subnetwork(parent, INITIAL_WEIGHT_VALUE, subnetwork_inputs)
-        .unwrap();
+    // Drop random inputs if the subnetwork would not fit into the gene budget (one gene is needed for
+    // the subnetwork's root neuron)
+    let budget = checker.gene_budget();
+    if subnetwork_inputs.len() > budget - 1 {
+        subnetwork_inputs.shuffle(rng);
+        subnetwork_inputs.truncate(budget - 1);
+    }
+    let mut remaining_budget = budget - 1 - subnetwork_inputs.len();
 
-    // Finally, the new subnetwork itself has a chance to be connected to each neuron with lesser
-    // depth other than its parent
+    // The new subnetwork itself has a chance to be connected to each neuron with lesser depth other
+    // than its parent
     let output_connections = network
         .neuron_info_map()
         .iter()
         .filter(|(id, info)| **id != parent && info.depth() < subnetwork_depth)
         .map(|(id, _)| *id)
         .filter(|_| rng.gen::<f64>() < NEW_SUBNETWORK_FORWARD_CONNECTION_PROBABILITY)
+        .filter(|id| checker.allows_inputs(*id, subnetwork_reach.iter().copied()))
         .collect::<Vec<_>>();
 
+    // Insert new age counters for the subnetwork's genes
+    let parent_index = network[parent].subgenome_range().start;
+    let subgenome_index = parent_index + 1;
+    // Each element of `subnetwork_inputs` is a new gene, plus the subnetwork's root neuron itself
+    let num_new_genes = 1 + subnetwork_inputs.len();
+    individual.insert_genes(subgenome_index, num_new_genes);
+
+    // Add the subnetwork, replacing the placeholder weights of its genes with initial ones
+    let subnetwork_id = individual
+        .network
+        .add_subnetwork(parent, INITIAL_WEIGHT_VALUE, subnetwork_inputs)
+        .unwrap();
+    initialize_weights(
+        &mut individual.network,
+        subgenome_index..subgenome_index + num_new_genes,
+        weight_init,
+        true,
+        rng,
+    );
+
+    // Finally, connect the subnetwork's output to the chosen neurons
     for id in output_connections {
-        if rng.gen::<f64>() < NEW_SUBNETWORK_FORWARD_CONNECTION_PROBABILITY {
+        if remaining_budget > 0 && rng.gen::<f64>() < NEW_SUBNETWORK_FORWARD_CONNECTION_PROBABILITY
+        {
             let forward = ForwardJumper::new(subnetwork_id, INITIAL_WEIGHT_VALUE);
-            add_non_neuron(individual, id, forward);
+            add_non_neuron(individual, id, forward, weight_init, rng);
+            individual.mutations.push(MutationRecord {
+                kind: MutationType::AddNode,
+                parent: id,
+                source: Source::ForwardJumper(subnetwork_id),
+            });
+            remaining_budget -= 1;
         }
     }
 
-    true
+    Some((parent, Source::Neuron(subnetwork_id)))
 }
 
-/// Randomly adds a bias gene to the network. Returns whether any mutation was performed.
+/// Randomly adds a bias gene to the network. Returns the parent neuron and source of the
+/// changed gene if any mutation was performed.
 fn add_bias<T: FitnessFunction + Clone>(
     individual: &mut Individual<T>,
+    constraints: &Constraints,
+    weight_init: &WeightInit,
     rng: &mut ThreadRng,
-) -> bool {
-    let network = &mut individual.network;
+) -> Option<(NeuronId, Source)> {
+    let network = &individual.network;
+    let checker = Checker::new(constraints, network);
+    if checker.gene_budget() == 0 {
+        return None;
+    }
 
     // Choose a random neuron without an existing bias input
     let valid_parents = network
@@ -303,14 +405,15 @@ fn add_bias<T: FitnessFunction + Clone>(
     // Add a bias gene to it
     if let Some(id) = parent {
         let bias = Bias::new(INITIAL_WEIGHT_VALUE);
-        add_non_neuron(individual, id, bias);
-        true
+        add_non_neuron(individual, id, bias, weight_init, rng);
+        Some((id, Source::Bias))
     } else {
-        false
+        None
     }
 }
 
-/// Randomly removes a connection from the network. Returns whether any mutation was performed.
+/// Randomly removes a connection from the network. Returns the parent neuron and source of the
+/// changed gene if any mutation was performed.
 ///
 /// The original paper was not clear about whether bias and input genes count as connections for the
 /// purposes of this mutation, but this function assumes they do and therefore may remove them. Note
@@ -318,29 +421,175 @@ fn add_bias<T: FitnessFunction + Clone>(
 fn remove_connection<T: FitnessFunction + Clone>(
     individual: &mut Individual<T>,
     rng: &mut ThreadRng,
-) -> bool {
+) -> Option<(NeuronId, Source)> {
     let network = &mut individual.network;
 
     // Choose a random, valid non-neuron to remove and remove it
     let index = network.get_valid_removals().choose(rng);
     if let Some(i) = index {
+        let parent = network.parent_of(i).flatten().unwrap();
+        let source = Source::of(&network[i]);
         network.remove_non_neuron(i).unwrap();
-        individual.ages.remove(i);
-        true
+        individual.remove_gene(i);
+        Some((parent, source))
     } else {
-        false
+        None
     }
 }
 
-/// Adds a non-neuron gene to the network and initializes a corresponding gene age counter.
+/// Adds a non-neuron gene to the network, initializes its weight and initializes a corresponding
+/// gene age counter.
 fn add_non_neuron<T: FitnessFunction + Clone, G: Into<NonNeuronGene<f64>>>(
     individual: &mut Individual<T>,
     parent: NeuronId,
     gene: G,
+    weight_init: &WeightInit,
+    rng: &mut ThreadRng,
 ) {
     let parent_index = individual.network[parent].subgenome_range().start;
     let gene_index = parent_index + 1;
     individual.network.add_non_neuron(parent, gene).unwrap();
+    initialize_weights(
+        &mut individual.network,
+        gene_index..gene_index + 1,
+        weight_init,
+        true,
+        rng,
+    );
     // Insert a new age counter for the gene
-    individual.ages.insert(gene_index, 0);
+    individual.insert_genes(gene_index, 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use cge::gene::Neuron;
+
+    use super::*;
+    use crate::cge_utils::is_stateless;
+    use crate::cmaes_utils::SearchDistribution;
+    use crate::mutation_probabilities::MutationProbabilities;
+
+    #[test]
+    fn test_chained_mutations_keep_ages_in_sync() {
+        let genome = vec![
+            Neuron::new(NeuronId::new(0), 1, INITIAL_WEIGHT_VALUE).into(),
+            Input::new(InputId::new(0), INITIAL_WEIGHT_VALUE).into(),
+            Neuron::new(NeuronId::new(1), 1, INITIAL_WEIGHT_VALUE).into(),
+            Input::new(InputId::new(1), INITIAL_WEIGHT_VALUE).into(),
+        ];
+        let mut individual = Individual::for_test(3, genome);
+        individual.search = Some(SearchDistribution {
+            step_size: 1.0,
+            covariance: nalgebra::DMatrix::identity(4, 4),
+            genes: (0..4).map(Some).collect(),
+        });
+        let sampler = MutationSampler::default();
+
+        for _ in 0..200 {
+            mutate(
+                &mut individual,
+                &sampler,
+                &Constraints::default(),
+                &WeightInit::Constant(INITIAL_WEIGHT_VALUE),
+            );
+            assert_eq!(individual.ages.len(), individual.network.len());
+            let genes = &individual.search.as_ref().unwrap().genes;
+            assert_eq!(genes.len(), individual.network.len());
+        }
+    }
+
+    #[test]
+    fn test_feedforward_networks_stay_stateless() {
+        let genome = vec![
+            Neuron::new(NeuronId::new(0), 1, INITIAL_WEIGHT_VALUE).into(),
+            Input::new(InputId::new(0), INITIAL_WEIGHT_VALUE).into(),
+        ];
+        let mut individual = Individual::for_test(2, genome);
+        let sampler = MutationSampler::default();
+        let constraints = Constraints::builder().feedforward().build();
+
+        for _ in 0..200 {
+            mutate(&mut individual, &sampler, &constraints, &WeightInit::Xavier);
+            assert!(is_stateless(&individual.network));
+        }
+    }
+
+    #[test]
+    fn test_add_subnetwork_within_gene_budget() {
+        let genome = vec![
+            Neuron::new(NeuronId::new(0), 1, INITIAL_WEIGHT_VALUE).into(),
+            Input::new(InputId::new(0), INITIAL_WEIGHT_VALUE).into(),
+        ];
+        let constraints = Constraints::builder().max_genes(4).build();
+        let weight_init = WeightInit::Constant(INITIAL_WEIGHT_VALUE);
+
+        for _ in 0..50 {
+            // With many inputs, the subnetwork is almost always truncated to a single input
+            let mut individual = Individual::for_test(8, genome.clone());
+            let added = add_subnetwork(
+                &mut individual,
+                &constraints,
+                &weight_init,
+                &mut thread_rng(),
+            );
+            assert!(added.is_some());
+            assert_eq!(individual.network.num_neurons(), 2);
+            assert!(individual.network.len() <= 4);
+            assert_eq!(individual.ages.len(), individual.network.len());
+
+            // The network is now full
+            let checker = Checker::new(&constraints, &individual.network);
+            assert!(checker.is_satisfied());
+            assert!(!checker.allows_neuron(NeuronId::new(0)));
+        }
+    }
+
+    #[test]
+    fn test_add_subnetwork_records_output_connections() {
+        let genome = vec![
+            Neuron::new(NeuronId::new(0), 1, INITIAL_WEIGHT_VALUE).into(),
+            Input::new(InputId::new(0), INITIAL_WEIGHT_VALUE).into(),
+            Neuron::new(NeuronId::new(1), 1, INITIAL_WEIGHT_VALUE).into(),
+            Input::new(InputId::new(0), INITIAL_WEIGHT_VALUE).into(),
+        ];
+        let sampler = MutationProbabilities::zeros()
+            .add_neuron(1.0)
+            .build()
+            .unwrap();
+        let weight_init = WeightInit::Constant(INITIAL_WEIGHT_VALUE);
+
+        for _ in 0..50 {
+            let mut individual = Individual::for_test(1, genome.clone());
+            assert!(mutate(
+                &mut individual,
+                &sampler,
+                &Constraints::default(),
+                &weight_init
+            ));
+
+            // The subnetwork is recorded first, followed by its connections to other neurons
+            let (first, rest) = individual.mutations.split_first().unwrap();
+            assert_eq!(first.kind, MutationType::AddNode);
+            let subnetwork_id = match first.source {
+                Source::Neuron(id) => id,
+                source => panic!("unexpected source {:?}", source),
+            };
+
+            let jumpers = individual
+                .network
+                .genome()
+                .iter()
+                .filter(|g| {
+                    g.as_forward_jumper()
+                        .is_some_and(|f| f.source_id() == subnetwork_id)
+                })
+                .count();
+            assert_eq!(rest.len(), jumpers);
+            for record in rest {
+                assert_eq!(record.kind, MutationType::AddNode);
+                assert_eq!(record.source, Source::ForwardJumper(subnetwork_id));
+                assert_ne!(record.parent, first.parent);
+            }
+        }
+    }
 }
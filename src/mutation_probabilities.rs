@@ -1,4 +1,5 @@
-use crate::mutation::MutationType;
+pub use crate::mutation::MutationType;
+use crate::options::{AdaptationScheme, AdaptiveMutation, MutationCredit};
 use rand::prelude::Rng;
 use rand_distr::{Distribution, WeightedAliasIndex, WeightedError};
 use std::convert::TryFrom;
@@ -7,15 +8,23 @@ use std::convert::TryFrom;
 ///
 /// - Use `MutationProbabilities` to create a `MutationSampler`:
 /// ```rust
-/// MutationProbabilities::zeros()
+/// # use eant2::mutation_probabilities::MutationProbabilities;
+/// # fn main() -> Result<(), rand_distr::WeightedError> {
+/// let sampler = MutationProbabilities::zeros()
 ///   .add_connection(2.)     // 1/3rd chance = 2 / (2 + 2 + 1 + 1)
 ///   .remove_connection(2.)  // 1/3rd chance
 ///   .add_neuron(1.)         // 1/6th chance
 ///   .add_bias(1.)           // 1/6th chance
-///   .build()?               // build the sampler
+///   .build()?;              // build the sampler
+/// # Ok(())
+/// # }
 /// ```
 #[derive(Clone)]
-pub struct MutationSampler(WeightedAliasIndex<u16>);
+pub struct MutationSampler {
+    sampler: WeightedAliasIndex<u16>,
+    /// The normalized probabilities of each element of `OUTPUTS`.
+    probabilities: [f64; 4],
+}
 
 impl MutationSampler {
     /// Reasonable default that works for most problems.
@@ -31,21 +40,42 @@ impl MutationSampler {
 
     /// Create a new `MutationSampler` with given relative probabilities as u16 values.
     fn new(probabilities: (u16, u16, u16, u16)) -> Result<Self, WeightedError> {
-        let sampler = WeightedAliasIndex::new(vec![
+        let weights = [
             probabilities.0,
             probabilities.1,
             probabilities.2,
             probabilities.3,
-        ])?;
+        ];
+        let sampler = WeightedAliasIndex::new(weights.to_vec())?;
+
+        let total = weights.iter().map(|&w| w as f64).sum::<f64>();
+        let probabilities = weights.map(|w| w as f64 / total);
 
-        Ok(MutationSampler(sampler))
+        Ok(MutationSampler {
+            sampler,
+            probabilities,
+        })
     }
 
     /// Sample a mutation type (using relative probabilities declared on creation).
     pub fn sample<R: Rng>(&self, rng: &mut R) -> MutationType {
-        let j = self.0.sample(rng);
+        let j = self.sampler.sample(rng);
         Self::OUTPUTS[j]
     }
+
+    /// The normalized probability of sampling each mutation type.
+    pub fn probabilities(&self) -> [(MutationType, f64); 4] {
+        let mut result = Self::OUTPUTS.map(|kind| (kind, 0.0));
+        for (r, p) in result.iter_mut().zip(self.probabilities) {
+            r.1 = p;
+        }
+        result
+    }
+
+    /// The index of the mutation type in `OUTPUTS`.
+    pub(crate) fn index(kind: MutationType) -> usize {
+        Self::OUTPUTS.iter().position(|k| *k == kind).unwrap()
+    }
 }
 
 impl Default for MutationSampler {
@@ -63,12 +93,16 @@ impl Default for MutationSampler {
 /// - For complex problems where network size isn't an issue, high connection addition probability is a good idea.
 /// - The reasonable default (when not specified) is `(3, 8, 1, 3)`.
 /// ```rust
-/// MutationProbabilities::zeros()
+/// # use eant2::mutation_probabilities::MutationProbabilities;
+/// # fn main() -> Result<(), rand_distr::WeightedError> {
+/// let sampler = MutationProbabilities::zeros()
 ///   .add_connection(2.)     // 1/3rd chance = 2 / (2 + 2 + 1 + 1)
 ///   .remove_connection(2.)  // 1/3rd chance
 ///   .add_neuron(1.)         // 1/6th chance
 ///   .add_bias(1.)           // 1/6th chance
-///   .build()?               // build the sampler
+///   .build()?;              // build the sampler
+/// # Ok(())
+/// # }
 /// ```
 #[derive(Copy, Clone)]
 pub struct MutationProbabilities((f64, f64, f64, f64));
@@ -135,3 +169,194 @@ impl MutationProbabilities {
         Self((a, b, c, p))
     }
 }
+
+/// Adapts the mutation probabilities online according to how successful each mutation type has
+/// been so far (see `AdaptiveMutation`).
+pub(crate) struct MutationAdaptation {
+    options: AdaptiveMutation,
+    /// The estimated reward of each mutation type
+    quality: [f64; 4],
+    /// The current probability of each mutation type
+    probabilities: [f64; 4],
+}
+
+impl MutationAdaptation {
+    /// Starts adapting from the probabilities of `initial`.
+    pub fn new(options: AdaptiveMutation, initial: &MutationSampler) -> Self {
+        let probabilities = initial.probabilities;
+
+        Self {
+            options,
+            // Start from the probabilities so that probability matching initially reproduces them
+            quality: probabilities,
+            probabilities,
+        }
+    }
+
+    /// Updates the probabilities given the average reward (in `[0, 1]`) of each mutation type in
+    /// the latest generation. Types that were not applied have no reward and keep their quality.
+    pub fn update(&mut self, rewards: [Option<f64>; 4]) {
+        let alpha = self.options.learning_rate;
+        for (q, r) in self.quality.iter_mut().zip(rewards) {
+            if let Some(r) = r.filter(|r| r.is_finite()) {
+                *q += alpha * (r - *q);
+            }
+        }
+
+        let k = self.probabilities.len() as f64;
+        let min = self.options.min_probability;
+        let max = self.options.max_probability;
+
+        match self.options.scheme {
+            AdaptationScheme::ProbabilityMatching => {
+                let total = self.quality.iter().sum::<f64>();
+                for (p, q) in self.probabilities.iter_mut().zip(self.quality) {
+                    let share = if total > 0.0 { q / total } else { 1.0 / k };
+                    *p = min + (1.0 - k * min) * share;
+                }
+            }
+            AdaptationScheme::AdaptivePursuit { beta } => {
+                // Move the best type towards `max` and all others towards `min`
+                let best = (0..self.quality.len())
+                    .max_by(|a, b| self.quality[*a].total_cmp(&self.quality[*b]))
+                    .unwrap();
+                for (i, p) in self.probabilities.iter_mut().enumerate() {
+                    let target = if i == best { max } else { min };
+                    *p += beta * (target - *p);
+                }
+            }
+        }
+
+        bound(&mut self.probabilities, min, max);
+    }
+
+    /// Returns what counts as a success of a mutation type.
+    pub fn credit(&self) -> MutationCredit {
+        self.options.credit
+    }
+
+    /// Returns a sampler that uses the current probabilities.
+    pub fn sampler(&self) -> MutationSampler {
+        let [a, b, c, d] = self.probabilities;
+        MutationProbabilities((a, b, c, d))
+            .build()
+            .expect("adapted mutation probabilities are always valid")
+    }
+}
+
+/// Moves the probabilities to the closest ones that sum to one and lie in `[min, max]`, which
+/// requires `min <= 1 / k <= max` for `k` probabilities. The closest probabilities are
+/// `clamp(p - shift, min, max)` for the shift at which they sum to one, which is found by
+/// bisection as the sum decreases with the shift.
+fn bound(probabilities: &mut [f64], min: f64, max: f64) {
+    let sum = |shift: f64| {
+        probabilities
+            .iter()
+            .map(|p| (p - shift).clamp(min, max))
+            .sum::<f64>()
+    };
+
+    // Every probability is clamped to `max` at the lower end, and to `min` at the upper end
+    let mut low = probabilities
+        .iter()
+        .fold(f64::INFINITY, |low, p| low.min(p - max));
+    let mut high = probabilities
+        .iter()
+        .fold(f64::NEG_INFINITY, |high, p| high.max(p - min));
+    for _ in 0..100 {
+        let middle = (low + high) / 2.0;
+        if sum(middle) > 1.0 {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+
+    let shift = (low + high) / 2.0;
+    for p in probabilities.iter_mut() {
+        *p = (*p - shift).clamp(min, max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adaptation(scheme: AdaptationScheme, min: f64, max: f64) -> MutationAdaptation {
+        let options = AdaptiveMutation::builder()
+            .scheme(scheme)
+            .min_probability(min)
+            .max_probability(max)
+            .build();
+        MutationAdaptation::new(options, &MutationSampler::default())
+    }
+
+    fn assert_bounded(adaptation: &MutationAdaptation, min: f64, max: f64) {
+        let probabilities = adaptation.probabilities;
+        assert!((probabilities.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        for p in probabilities {
+            assert!(p >= min - 1e-9 && p <= max + 1e-9, "{:?}", probabilities);
+        }
+    }
+
+    #[test]
+    fn test_probability_matching() {
+        let mut adaptation = adaptation(AdaptationScheme::ProbabilityMatching, 0.05, 1.0);
+        for _ in 0..50 {
+            adaptation.update([Some(1.0), Some(0.0), None, Some(0.5)]);
+            assert_bounded(&adaptation, 0.05, 1.0);
+        }
+
+        // Probabilities follow the rewards, and untried types keep their quality
+        let [a, b, c, d] = adaptation.probabilities;
+        assert!(a > d && d > b);
+        assert!(c > b);
+        assert!((b - 0.05).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_adaptive_pursuit() {
+        let pursuit = AdaptationScheme::AdaptivePursuit { beta: 0.5 };
+        let mut adaptation = adaptation(pursuit, 0.05, 1.0);
+        for _ in 0..50 {
+            adaptation.update([Some(0.0), Some(0.0), Some(1.0), Some(0.0)]);
+            assert_bounded(&adaptation, 0.05, 1.0);
+        }
+
+        // The best type approaches the largest probability the others leave it
+        assert!((adaptation.probabilities[2] - 0.85).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_bounds() {
+        for scheme in [
+            AdaptationScheme::ProbabilityMatching,
+            AdaptationScheme::AdaptivePursuit { beta: 0.9 },
+        ] {
+            let mut adaptation = adaptation(scheme, 0.2, 0.3);
+            for _ in 0..20 {
+                adaptation.update([Some(1.0), Some(0.0), Some(0.0), Some(f64::NAN)]);
+                assert_bounded(&adaptation, 0.2, 0.3);
+            }
+            assert!((adaptation.probabilities[0] - 0.3).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_invalid_bounds() {
+        adaptation(AdaptationScheme::ProbabilityMatching, 0.2, 0.1);
+    }
+
+    #[test]
+    #[should_panic(expected = "learning_rate")]
+    fn test_invalid_learning_rate() {
+        AdaptiveMutation::builder().learning_rate(2.5).build();
+    }
+
+    #[test]
+    #[should_panic(expected = "beta")]
+    fn test_invalid_pursuit_rate() {
+        adaptation(AdaptationScheme::AdaptivePursuit { beta: f64::NAN }, 0.05, 1.0);
+    }
+}
@@ -0,0 +1,333 @@
+//! Optimizers for the weights of a network in the exploitation phase.
+//!
+//! Every individual whose structure is new (and, depending on `Exploitation::reoptimization`,
+//! every surviving parent) has its weights optimized by a [`ParameterOptimizer`]. It is set with
+//! the `optimizer` option of [`Exploitation`]. The default is [`Cmaes`], which runs CMA-ES with
+//! restarts as in the original paper. CMA-ES takes time quadratic in the number of weights per
+//! sample, so [`SeparableCmaes`] is provided as a linear-time alternative for larger networks.
+//! Custom optimizers can be used by implementing the trait.
+
+use cmaes::objective_function::Scale;
+use cmaes::restart::{RestartOptions, Restarter};
+use cmaes::{DVector, Mode};
+use rand::thread_rng;
+use rand_distr::{Distribution, StandardNormal};
+
+use crate::options::{positive, Exploitation};
+
+/// A weight optimization problem for a single network.
+#[derive(Clone, Copy)]
+pub struct Problem<'a> {
+    /// The current weights of the network, in genome order.
+    pub initial: &'a [f64],
+    /// The deviation of each weight according to the age of its gene (see
+    /// `Exploitation::deviation`). The search should be scaled by these, so that old genes are
+    /// only fine-tuned.
+    pub deviations: &'a [f64],
+    /// The objective value at which the optimization should stop.
+    pub target: f64,
+    /// The maximum number of objective evaluations, if limited.
+    pub max_evaluations: Option<usize>,
+    /// The exploitation options, which contain the search range and the other limits.
+    pub exploitation: &'a Exploitation,
+}
+
+/// An optimizer for the weights of a network.
+pub trait ParameterOptimizer: Send + Sync {
+    /// Minimizes `objective`, which takes the weights of the network in genome order, and returns
+    /// the best weights found along with their objective value.
+    fn optimize(
+        &self,
+        problem: &Problem,
+        objective: &mut dyn FnMut(&[f64]) -> f64,
+    ) -> (Vec<f64>, f64);
+}
+
+/// CMA-ES with the restart strategy of `Exploitation::restart`. Each run starts from a random point
+/// of `Exploitation::search_range` around the current weights, scaled by the gene deviations.
+#[derive(Clone, Copy, Debug)]
+pub struct Cmaes;
+
+impl ParameterOptimizer for Cmaes {
+    fn optimize(
+        &self,
+        problem: &Problem,
+        objective: &mut dyn FnMut(&[f64]) -> f64,
+    ) -> (Vec<f64>, f64) {
+        let exploitation = problem.exploitation;
+        let initial = DVector::from_column_slice(problem.initial);
+        let scaled = Scale::new(
+            |x: &DVector<f64>| objective((x + &initial).as_slice()),
+            problem.deviations.to_vec(),
+        );
+
+        let mut restart_options = RestartOptions::new(
+            problem.initial.len(),
+            exploitation.search_range.clone(),
+            exploitation.restart.clone(),
+        )
+        .mode(Mode::Minimize)
+        .fun_target(problem.target);
+
+        if let Some(max_gens) = exploitation.terminate.generations {
+            restart_options = restart_options.max_generations_per_run(max_gens);
+        }
+        if let Some(max_evals) = problem.max_evaluations {
+            restart_options = restart_options.max_function_evals(max_evals);
+        }
+
+        let best = Restarter::new(restart_options)
+            .unwrap()
+            .run_with_reuse(scaled)
+            .best
+            .expect("CMA-ES optimization failed, this is likely the result of FitnessFunction returning f64::NAN");
+
+        // The returned point does not have the scaling applied
+        let weights = problem
+            .initial
+            .iter()
+            .zip(best.point.iter())
+            .zip(problem.deviations)
+            .map(|((initial, x), deviation)| initial + x * deviation)
+            .collect();
+        (weights, best.value)
+    }
+}
+
+/// Separable CMA-ES (Ros and Hansen, 2008), which only adapts a diagonal covariance matrix. It
+/// cannot learn correlations between weights, but takes time linear in the number of weights per
+/// sample and learns the variances faster, so it scales to much larger networks.
+///
+/// A single run starts from the current weights. The initial standard deviation of each weight is
+/// the step size times half the width of `Exploitation::search_range`, scaled by its gene
+/// deviation. The run ends when the limits of the problem are reached, or when the search has
+/// converged.
+///
+/// Samples are drawn from [`thread_rng`], so runs are not reproducible.
+#[derive(Clone, Copy, Debug)]
+pub struct SeparableCmaes {
+    step_size: f64,
+}
+
+impl SeparableCmaes {
+    /// Creates the optimizer with the given initial step size, relative to half the width of
+    /// `Exploitation::search_range`.
+    ///
+    /// Panics if the step size is not positive and finite. The optimizer has no builder whose
+    /// setters could check it like the other options, so it is checked here instead, with the
+    /// same message.
+    pub fn new(step_size: f64) -> Self {
+        Self {
+            step_size: positive("step_size", step_size),
+        }
+    }
+
+    /// Returns the initial step size.
+    pub fn step_size(&self) -> f64 {
+        self.step_size
+    }
+}
+
+impl Default for SeparableCmaes {
+    /// Creates the optimizer with a step size of `0.5`.
+    fn default() -> Self {
+        Self::new(0.5)
+    }
+}
+
+impl ParameterOptimizer for SeparableCmaes {
+    fn optimize(
+        &self,
+        problem: &Problem,
+        objective: &mut dyn FnMut(&[f64]) -> f64,
+    ) -> (Vec<f64>, f64) {
+        let n = problem.initial.len();
+        let nf = n as f64;
+        let mut rng = thread_rng();
+
+        // Strategy parameters, with the learning rates of the covariance matrix increased by
+        // `(n + 2) / 3` as it only has `n` degrees of freedom
+        let lambda = 4 + (3.0 * nf.ln()).floor() as usize;
+        let mu = lambda / 2;
+        let weights = {
+            let raw = (1..=mu)
+                .map(|i| (mu as f64 + 0.5).ln() - (i as f64).ln())
+                .collect::<Vec<_>>();
+            let sum = raw.iter().sum::<f64>();
+            raw.into_iter().map(|w| w / sum).collect::<Vec<_>>()
+        };
+        let mu_eff = 1.0 / weights.iter().map(|w| w * w).sum::<f64>();
+        let c_sigma = (mu_eff + 2.0) / (nf + mu_eff + 5.0);
+        let d_sigma = 1.0 + 2.0 * (((mu_eff - 1.0) / (nf + 1.0)).sqrt() - 1.0).max(0.0) + c_sigma;
+        let c_c = (4.0 + mu_eff / nf) / (nf + 4.0 + 2.0 * mu_eff / nf);
+        let c_1 = 2.0 / ((nf + 1.3).powi(2) + mu_eff) * (nf + 2.0) / 3.0;
+        let c_mu = (2.0 * (mu_eff - 2.0 + 1.0 / mu_eff) / ((nf + 2.0).powi(2) + mu_eff)
+            * (nf + 2.0)
+            / 3.0)
+            .min(1.0 - c_1);
+        let expected_norm = nf.sqrt() * (1.0 - 1.0 / (4.0 * nf) + 1.0 / (21.0 * nf * nf));
+        let max_generations = problem
+            .exploitation
+            .terminate
+            .generations
+            .unwrap_or((1000.0 * (nf + 5.0).powi(2) / (lambda as f64).sqrt()) as usize);
+
+        // State of the search distribution
+        let range = &problem.exploitation.search_range;
+        let mut sigma = self.step_size * (range.end() - range.start()) / 2.0;
+        let mut mean = problem.initial.to_vec();
+        let mut variances = problem.deviations.iter().map(|d| d * d).collect::<Vec<_>>();
+        let mut path_sigma = vec![0.0; n];
+        let mut path_c = vec![0.0; n];
+
+        let mut best = (mean.clone(), objective(&mean));
+        let mut evaluations = 1;
+        let mut generation = 0;
+
+        while best.1 > problem.target
+            && generation < max_generations
+            && problem.max_evaluations.is_none_or(|max| evaluations < max)
+        {
+            // Sample and evaluate the offspring, best first. If the remaining evaluations do not
+            // suffice for a full generation, the last samples only compete for the best weights.
+            let batch = problem
+                .max_evaluations
+                .map_or(lambda, |max| lambda.min(max - evaluations));
+            let mut samples = (0..batch)
+                .map(|_| {
+                    let z = (0..n)
+                        .map(|_| StandardNormal.sample(&mut rng))
+                        .collect::<Vec<f64>>();
+                    let y = z
+                        .iter()
+                        .zip(&variances)
+                        .map(|(z, v)| z * v.sqrt())
+                        .collect::<Vec<_>>();
+                    let x = mean
+                        .iter()
+                        .zip(&y)
+                        .map(|(m, y)| m + sigma * y)
+                        .collect::<Vec<_>>();
+                    let value = objective(&x);
+                    (z, y, x, value)
+                })
+                .collect::<Vec<_>>();
+            evaluations += batch;
+            generation += 1;
+            samples.sort_by(|a, b| a.3.total_cmp(&b.3));
+
+            if samples[0].3 < best.1 {
+                best = (samples[0].2.clone(), samples[0].3);
+            }
+            if batch < lambda {
+                break;
+            }
+
+            // Recombine the best samples
+            let mut z_w = vec![0.0; n];
+            let mut y_w = vec![0.0; n];
+            for ((z, y, _, _), w) in samples.iter().zip(&weights) {
+                for (sum, z) in z_w.iter_mut().zip(z) {
+                    *sum += w * z;
+                }
+                for (sum, y) in y_w.iter_mut().zip(y) {
+                    *sum += w * y;
+                }
+            }
+            for (m, y) in mean.iter_mut().zip(&y_w) {
+                *m += sigma * y;
+            }
+
+            // Update the evolution paths
+            let sigma_factor = (c_sigma * (2.0 - c_sigma) * mu_eff).sqrt();
+            for (p, z) in path_sigma.iter_mut().zip(&z_w) {
+                *p = (1.0 - c_sigma) * *p + sigma_factor * z;
+            }
+            let norm = path_sigma.iter().map(|p| p * p).sum::<f64>().sqrt();
+            let correction = (1.0 - (1.0 - c_sigma).powi(2 * generation as i32)).sqrt();
+            let stalled = norm / correction >= (1.4 + 2.0 / (nf + 1.0)) * expected_norm;
+            let h_sigma = if stalled { 0.0 } else { 1.0 };
+            let c_factor = (c_c * (2.0 - c_c) * mu_eff).sqrt();
+            for (p, y) in path_c.iter_mut().zip(&y_w) {
+                *p = (1.0 - c_c) * *p + h_sigma * c_factor * y;
+            }
+
+            // Update the variances and the step size
+            for (i, v) in variances.iter_mut().enumerate() {
+                let rank_mu = samples
+                    .iter()
+                    .zip(&weights)
+                    .map(|(sample, w)| w * sample.1[i] * sample.1[i])
+                    .sum::<f64>();
+                let rank_one = path_c[i] * path_c[i] + (1.0 - h_sigma) * c_c * (2.0 - c_c) * *v;
+                *v = (1.0 - c_1 - c_mu) * *v + c_1 * rank_one + c_mu * rank_mu;
+            }
+            sigma *= ((c_sigma / d_sigma) * (norm / expected_norm - 1.0)).exp();
+
+            // Stop once the search has converged
+            let max_deviation = variances.iter().copied().fold(0.0, f64::max).sqrt();
+            let spread = samples[lambda - 1].3 - samples[0].3;
+            if sigma * max_deviation < 1e-12 || (spread < 1e-12 && generation > 10) {
+                break;
+            }
+        }
+
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_separable_cmaes() {
+        let exploitation = Exploitation::builder().build();
+        let problem = Problem {
+            initial: &[0.0; 10],
+            deviations: &[1.0; 10],
+            target: 1e-10,
+            max_evaluations: Some(20_000),
+            exploitation: &exploitation,
+        };
+        // A sphere function centered on 1, 2, ..., 10 with very different scales per coordinate
+        let mut objective = |x: &[f64]| {
+            x.iter()
+                .enumerate()
+                .map(|(i, x)| (i + 1) as f64 * (x - (i + 1) as f64).powi(2))
+                .sum::<f64>()
+        };
+
+        let (weights, value) = SeparableCmaes::default().optimize(&problem, &mut objective);
+        assert!(value <= 1e-10, "{}", value);
+        assert!((weights[9] - 10.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_separable_cmaes_budget() {
+        let exploitation = Exploitation::builder().build();
+        for max_evaluations in [1, 7, 10, 25] {
+            let problem = Problem {
+                initial: &[0.0; 10],
+                deviations: &[1.0; 10],
+                target: 0.0,
+                max_evaluations: Some(max_evaluations),
+                exploitation: &exploitation,
+            };
+            let mut evaluations = 0;
+            let mut objective = |x: &[f64]| {
+                evaluations += 1;
+                x.iter().map(|x| (x - 1.0).powi(2)).sum::<f64>()
+            };
+
+            SeparableCmaes::default().optimize(&problem, &mut objective);
+            assert_eq!(evaluations, max_evaluations);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "positive")]
+    fn test_invalid_step_size() {
+        SeparableCmaes::new(0.0);
+    }
+}
@@ -1,19 +1,70 @@
+use crate::cge_utils::{Network, INITIAL_WEIGHT_VALUE};
 use crate::mutation_probabilities::MutationSampler;
+use crate::optimizer::{Cmaes, ParameterOptimizer};
+use crate::selection::{Grouping, SelectionStrategy};
 use cge::Activation;
 use cmaes::restart::{Local, RestartStrategy};
+use rand::Rng;
+use rand_distr::{Distribution, GeoError, Geometric, Normal, Poisson, PoissonError};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
 use typed_builder::TypedBuilder;
 
 pub(crate) const DEFAULT_ACTIVATION: Activation = Activation::Sigmoid;
 pub(crate) const DEFAULT_POPULATION_SIZE: usize = 10;
 pub(crate) const DEFAULT_OFFSPRING_COUNT: usize = 4;
-pub(crate) const DEFAULT_SIMILARITY: f64 = 0.15;
+pub(crate) const DEFAULT_REPRODUCTION: Reproduction = Reproduction::Uniform;
+pub(crate) const DEFAULT_REOPTIMIZATION: Reoptimization = Reoptimization::Always;
+pub(crate) const DEFAULT_SEARCH_RANGE: RangeInclusive<f64> = -1.0..=1.0;
+pub(crate) const DEFAULT_AGE_DEVIATION: AgeDeviation = AgeDeviation::InverseSquare;
+pub(crate) const DEFAULT_SIMILARITY: FitnessSimilarity = FitnessSimilarity::Absolute(0.15);
+pub(crate) const DEFAULT_COMPLEXITY: ComplexityMeasure = ComplexityMeasure::Genes;
 pub(crate) const DEFAULT_MAX_GENERATIONS: usize = 30;
 pub(crate) const DEFAULT_TERMINATING_FITNESS: f64 = 0.0;
+pub(crate) const DEFAULT_CROSSOVER_RATE: f64 = 0.0;
+// The paper was not entirely clear about whether this should be set to one or two, but one seems
+// more likely and was chosen, as retaining any duplicates probably provides little to no benefit on
+// average (they effectively serve only as additional CMA-ES runs with potentially differing initial
+// means).
+pub(crate) const DEFAULT_MAX_COPIES: usize = 1;
+// The paper was not entirely clear about whether this should be set to two or three, but two is
+// more consistent with the above interpretation of `DEFAULT_MAX_COPIES` and was chosen.
+pub(crate) const DEFAULT_MAX_SIMILAR: usize = 2;
+pub(crate) const DEFAULT_SIMILARITY_CHECK: SimilarityCheck = SimilarityCheck::Genome;
+pub(crate) const DEFAULT_MUTATION_COUNT: MutationCount = MutationCount::Fixed(1);
+pub(crate) const DEFAULT_SEEDING: Seeding = Seeding::Copies;
+pub(crate) const DEFAULT_SEED_AGE: usize = 1;
+pub(crate) const DEFAULT_WEIGHT_INIT: WeightInit = WeightInit::Constant(INITIAL_WEIGHT_VALUE);
 pub(crate) const DEFAULT_EANT2_TERMINATION: EANT2Termination = EANT2Termination {
     fitness: 0.0,
     generations: 30,
 };
 
+/// Returns `value` if it lies in `range`, and panics with the name of the option otherwise. Used by
+/// setters to reject invalid options when they are built rather than in the middle of a run.
+fn checked<T: PartialOrd + fmt::Debug>(name: &str, value: T, range: RangeInclusive<T>) -> T {
+    assert!(
+        range.contains(&value),
+        "`{}` must be in {:?}, but is {:?}",
+        name,
+        range,
+        value
+    );
+    value
+}
+
+/// Returns `value` if it is positive and finite, and panics with the name of the option otherwise.
+pub(crate) fn positive(name: &str, value: f64) -> f64 {
+    assert!(
+        value.is_finite() && value > 0.0,
+        "`{}` must be positive and finite, but is {}",
+        name,
+        value
+    );
+    value
+}
+
 /// When should the (outer) EANT2 algorithm terminate?
 #[derive(TypedBuilder)]
 pub struct EANT2Termination {
@@ -40,22 +91,56 @@ pub struct Exploration {
 
     #[builder(
     default = DEFAULT_OFFSPRING_COUNT,
-    setter(doc = "Sets the offspring count (how many offspring each individual spawns). Increasing this
-                  option may produce higher quality neural networks, but will increase the time needed to
-                  find a solution."))]
+    setter(doc = "Sets the offspring count (how many offspring each individual spawns on average; see
+                  `reproduction`). Increasing this option may produce higher quality neural networks,
+                  but will increase the time needed to find a solution."))]
     pub offspring: usize,
 
+    #[builder(
+    default = DEFAULT_REPRODUCTION,
+    setter(doc = "Sets how the offspring of a generation are divided among the individuals. The total
+                  number of offspring is always `offspring` times the population size, but better
+                  individuals may get more of them, which concentrates the CMA-ES runs on promising
+                  structures. Default: `Reproduction::Uniform`."))]
+    pub reproduction: Reproduction,
+
     #[builder(
     default = DEFAULT_SIMILARITY,
-    setter(doc= "Sets the threshold for deciding whether two neural networks have a similar fitness.
+    setter(into, doc= "Sets the threshold for deciding whether two neural networks have a similar fitness.
                  Increasing this option will make the algorithm more aggresively prefer smaller
                  neural networks. Decreasing it will do the opposite, allowing larger individuals to stay in
                  the population. It is recommended to set this option higher if a small neural network is
                  preferred. The downside is it will take slightly longer to find a solution, due to more
-                 higher fitness neural networks being discarded.
-                 
+                 higher fitness neural networks being discarded. A plain number is an absolute
+                 threshold; see `FitnessSimilarity` for thresholds that scale with the fitness values.
                  Default: `0.15`."))]
-    pub similarity: f64,
+    pub similarity: FitnessSimilarity,
+
+    #[builder(
+        default = DEFAULT_COMPLEXITY,
+        setter(
+            doc = "Sets how the size of a network is measured wherever smaller networks are preferred: when
+                  breaking ties between networks with similar fitness during selection, when choosing
+                  the best network among those with equal fitness, and in reports. Default:
+                  `ComplexityMeasure::Genes`."
+        )
+    )]
+    pub complexity: ComplexityMeasure,
+
+    #[builder(
+    default = None,
+    setter(strip_option, doc = "Enables penalizing the fitness of networks by their complexity during selection,
+                  and optionally during CMA-ES. The raw fitness is still reported and used for the
+                  termination conditions. Default: disabled."))]
+    pub parsimony: Option<Parsimony>,
+
+    #[builder(
+    default = None,
+    setter(strip_option, doc = "Enables dividing the population into species of structurally compatible networks,
+                  which share their fitness and are allocated population slots (and thereby offspring)
+                  accordingly. This protects new structures from being displaced by established ones
+                  before their weights are tuned. Default: disabled."))]
+    pub speciation: Option<Speciation>,
 
     #[builder(
         default_code = "MutationSampler::default()",
@@ -65,10 +150,795 @@ pub struct Exploration {
     )]
     pub mutation_probabilities: MutationSampler,
 
+    #[builder(
+    default = DEFAULT_MUTATION_COUNT,
+    setter(doc = "Sets how many structural mutations are applied to each offspring. Applying more than
+                  one allows larger structural jumps per generation, at the cost of a less local
+                  search. Default: `MutationCount::Fixed(1)`."))]
+    pub mutations: MutationCount,
+
+    #[builder(
+    default = DEFAULT_CROSSOVER_RATE,
+    setter(
+        transform = |p: f64| checked("crossover", p, 0.0..=1.0),
+        doc = "Sets the probability that an offspring is produced by crossover with another random
+                  individual of the population before being mutated. Crossover copies a whole output
+                  subnetwork or hidden subnetwork from the other parent, which lets useful structures
+                  found in different lineages meet. Default: `0.0` (mutation only)."))]
+    pub crossover: f64,
+
+    #[builder(
+    default = None,
+    setter(strip_option, doc = "Enables adapting the mutation probabilities online according to how successful
+                  each mutation type is, starting from `mutation_probabilities`. The adapted probabilities
+                  are reported in the run statistics. Default: disabled."))]
+    pub adaptive_mutation: Option<AdaptiveMutation>,
+
+    #[builder(
+        default_code = "Selection::builder().build()",
+        setter(
+            doc = "Sets how duplicate and structurally similar networks are limited during selection.
+                  Default: at most one copy and two similar networks, without forcing the population size."
+        )
+    )]
+    pub selection: Selection,
+
+    #[builder(
+        default_code = "Constraints::builder().build()",
+        setter(
+            doc = "Sets the structural constraints that all networks must satisfy. Mutations and crossovers
+                  that would violate them are never performed. Default: unconstrained."
+        )
+    )]
+    pub constraints: Constraints,
+
     #[builder(default = DEFAULT_EANT2_TERMINATION, setter(doc = "Termination conditions (target fitness, max generations)"))]
     pub terminate: EANT2Termination,
 }
 
+/// Selection options.
+/// These control how the individuals that survive to the next generation are selected, and for
+/// the default strategy, how duplicate and structurally similar networks are limited. Two networks
+/// are duplicates if they are structurally identical, and similar if they share the same neuron
+/// structure regardless of their connections.
+#[derive(TypedBuilder, Clone)]
+pub struct Selection {
+    #[builder(
+        default_code = "Arc::new(Grouping)",
+        setter(
+            transform = |strategy: impl SelectionStrategy + 'static| Arc::new(strategy) as Arc<dyn SelectionStrategy>,
+            doc = "Sets the strategy that selects the surviving individuals. The other selection options
+                  only apply to the default strategy. Default: `Grouping`."
+        )
+    )]
+    pub strategy: Arc<dyn SelectionStrategy>,
+
+    #[builder(
+        default = DEFAULT_MAX_COPIES,
+        setter(
+            doc = "Sets the maximum number of copies of each unique network that may survive selection. With
+                  one, no duplicates survive. Duplicates only serve as additional CMA-ES runs with
+                  different initial means, so allowing them rarely helps but costs evaluations.
+                  Default: `1`."
+        )
+    )]
+    pub max_copies: usize,
+
+    #[builder(
+        default = DEFAULT_MAX_SIMILAR,
+        setter(
+            doc = "Sets the maximum number of structurally similar networks that may survive for each
+                  unique network. With two, only one additional network similar to a given one may
+                  survive. Higher values let the population explore connections around a promising
+                  neuron structure, at the cost of structural diversity. Default: `2`."
+        )
+    )]
+    pub max_similar: usize,
+
+    #[builder(
+        default = DEFAULT_SIMILARITY_CHECK,
+        setter(
+            doc = "Sets how networks are compared to decide whether they are duplicates or similar.
+                  Default: `SimilarityCheck::Genome`."
+        )
+    )]
+    pub similarity_check: SimilarityCheck,
+
+    #[builder(setter(
+        strip_bool,
+        doc = "Discards offspring before optimization if the population and earlier offspring already
+                  contain `max_copies` duplicates of them, according to `similarity_check`. This saves
+                  whole CMA-ES runs on networks that selection would discard anyway (unless
+                  `force_meet_population_size` is set), but the discarded runs would otherwise give
+                  the best of the duplicates additional chances to find good weights. Default: disabled."
+    ))]
+    pub deduplicate_offspring: bool,
+
+    #[builder(setter(
+        strip_bool,
+        doc = "Gradually relaxes `max_similar` and then `max_copies` whenever too few networks satisfy
+                  them to fill the population. Without this option, the population may shrink below
+                  its target size, which is common with small populations, but no CMA-ES runs are
+                  wasted on similar or duplicate networks. Default: disabled."
+    ))]
+    pub force_meet_population_size: bool,
+
+    #[builder(setter(
+        strip_bool,
+        doc = "Records the decision about every candidate of every selection in
+                  `GenerationStatistics::selection`: its rank, its groups and species, whether it
+                  survived, and if not, why. Default: disabled."
+    ))]
+    pub report: bool,
+}
+
+impl fmt::Debug for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Selection")
+            .field("strategy", &format_args!("SelectionStrategy"))
+            .field("max_copies", &self.max_copies)
+            .field("max_similar", &self.max_similar)
+            .field("similarity_check", &self.similarity_check)
+            .field("deduplicate_offspring", &self.deduplicate_offspring)
+            .field("force_meet_population_size", &self.force_meet_population_size)
+            .field("report", &self.report)
+            .finish()
+    }
+}
+
+/// How many structural mutations are applied in a row to produce each offspring. Use
+/// [`MutationCount::poisson`] and [`MutationCount::geometric`] to create the random counts, which
+/// validate their parameters.
+#[derive(Clone, Copy, Debug)]
+pub enum MutationCount {
+    /// Always apply exactly this many mutations.
+    Fixed(usize),
+    /// Apply `1 + X` mutations, where `X` follows the Poisson distribution.
+    Poisson(Poisson<f64>),
+    /// Apply `1 + X` mutations, where `X` is the number of failures before the first success of
+    /// the geometric distribution.
+    Geometric(Geometric),
+    /// Apply `min` mutations, plus one more for every `patience` consecutive generations in which
+    /// the best fitness did not improve, up to a maximum of `max`. The count drops back to `min`
+    /// as soon as the best fitness improves again.
+    Adaptive {
+        min: usize,
+        max: usize,
+        patience: usize,
+    },
+}
+
+impl MutationCount {
+    /// Apply `1 + X` mutations, where `X` follows a Poisson distribution with the given mean.
+    /// Returns `Err` unless the mean is positive and finite.
+    pub fn poisson(mean: f64) -> Result<Self, PoissonError> {
+        if !mean.is_finite() {
+            return Err(PoissonError::ShapeTooSmall);
+        }
+        Poisson::new(mean).map(MutationCount::Poisson)
+    }
+
+    /// Apply `X` mutations, where `X` follows a geometric distribution (starting at one) with the
+    /// given success probability. The mean number of mutations is `1 / p`. Returns `Err` unless
+    /// `0 < p <= 1`.
+    pub fn geometric(p: f64) -> Result<Self, GeoError> {
+        if p == 0.0 {
+            return Err(GeoError::InvalidProbability);
+        }
+        Geometric::new(p).map(MutationCount::Geometric)
+    }
+
+    /// Samples the number of mutations to apply to a single offspring. `stagnation` is the number
+    /// of consecutive generations in which the best fitness has not improved.
+    pub(crate) fn sample<R: Rng>(&self, rng: &mut R, stagnation: usize) -> usize {
+        match *self {
+            MutationCount::Fixed(n) => n,
+            MutationCount::Poisson(poisson) => 1 + poisson.sample(rng) as usize,
+            MutationCount::Geometric(geometric) => 1 + geometric.sample(rng) as usize,
+            MutationCount::Adaptive { min, max, patience } => {
+                let extra = stagnation.checked_div(patience).unwrap_or(0);
+                min.saturating_add(extra).min(max.max(min))
+            }
+        }
+    }
+}
+
+/// How the offspring of a generation are divided among the individuals. Individuals are ranked by
+/// their fitness (including any parsimony penalty), and by complexity if their fitness is equal.
+/// The number of offspring of each individual is proportional to its weight, rounded so that the
+/// total stays the same.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reproduction {
+    /// Every individual gets the same weight, so each one produces exactly `offspring` children.
+    Uniform,
+    /// Linear ranking with the given selection pressure between one and two. The best individual
+    /// gets a weight of `pressure` and the worst a weight of `2 - pressure`, with linear
+    /// interpolation in between. A pressure of one is uniform.
+    LinearRank(f64),
+    /// The weight of each individual is how much better its fitness is than the worst fitness in
+    /// the population, so the worst individual produces no offspring. If all individuals are
+    /// equally fit, this is uniform.
+    FitnessProportional,
+    /// Fixed weights by rank, starting with the best individual. Individuals ranked beyond the end
+    /// of the schedule get its last weight. For example, `[3.0, 2.0, 1.0]` gives the best
+    /// individual three times as many offspring as the third and all below it.
+    Schedule(Vec<f64>),
+}
+
+impl Reproduction {
+    /// Returns the number of offspring of each individual, given their fitness values sorted from
+    /// best to worst and the total number of offspring.
+    pub(crate) fn offspring(&self, ranked: &[f64], total: usize) -> Vec<usize> {
+        let n = ranked.len();
+        let mut weights = match self {
+            Reproduction::Uniform => vec![1.0; n],
+            Reproduction::LinearRank(pressure) => {
+                let pressure = pressure.clamp(1.0, 2.0);
+                (0..n)
+                    .map(|i| {
+                        let position = (n - 1 - i) as f64 / (n - 1).max(1) as f64;
+                        2.0 - pressure + 2.0 * (pressure - 1.0) * position
+                    })
+                    .collect()
+            }
+            Reproduction::FitnessProportional => {
+                let worst = ranked
+                    .iter()
+                    .copied()
+                    .filter(|f| f.is_finite())
+                    .fold(f64::NEG_INFINITY, f64::max);
+                ranked
+                    .iter()
+                    .map(|f| if f.is_finite() { worst - f } else { 0.0 })
+                    .collect()
+            }
+            Reproduction::Schedule(schedule) => (0..n)
+                .map(|i| {
+                    schedule
+                        .get(i)
+                        .or(schedule.last())
+                        .map_or(1.0, |w| w.max(0.0))
+                })
+                .collect(),
+        };
+        if weights.iter().sum::<f64>() <= 0.0 {
+            weights = vec![1.0; n];
+        }
+
+        // Round down, and then round up the individuals with the largest remainders (the better
+        // individual first among equal remainders)
+        let sum = weights.iter().sum::<f64>();
+        let exact = weights
+            .iter()
+            .map(|w| total as f64 * w / sum)
+            .collect::<Vec<_>>();
+        let mut counts = exact.iter().map(|e| e.floor() as usize).collect::<Vec<_>>();
+        let mut order = (0..n).collect::<Vec<_>>();
+        order.sort_by(|a, b| {
+            let remainder = |i: usize| exact[i] - exact[i].floor();
+            remainder(*b).total_cmp(&remainder(*a))
+        });
+        let remaining = total.saturating_sub(counts.iter().sum());
+        for i in order.into_iter().take(remaining) {
+            counts[i] += 1;
+        }
+
+        counts
+    }
+}
+
+/// How the weights of new genes are initialized, both in the initial networks and in genes added by
+/// mutations.
+///
+/// The fan-in of a gene is the number of inputs of the neuron it is connected to. Output neurons
+/// are treated as having a fan-in of one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WeightInit {
+    /// Every weight starts at the given value.
+    Constant(f64),
+    /// Weights are drawn uniformly from `[-x, x]`.
+    Uniform(f64),
+    /// Weights are drawn from a normal distribution with mean zero and the given standard
+    /// deviation.
+    Normal(f64),
+    /// Genes added by mutations get weights drawn uniformly from `[-x, x]` for a small `x`, so that
+    /// they barely change the behavior of an already optimized network. The genes of the initial
+    /// networks start at `1.0`.
+    NearZero(f64),
+    /// Weights are drawn from a normal distribution with mean zero and variance `1 / fan_in`.
+    Xavier,
+    /// Weights are drawn from a normal distribution with mean zero and variance `2 / fan_in`,
+    /// which suits the ReLU activation function.
+    He,
+}
+
+impl WeightInit {
+    /// Samples the weight of a single gene. `mutation` is whether the gene is added by a mutation
+    /// rather than being part of an initial network.
+    pub(crate) fn sample<R: Rng>(&self, rng: &mut R, fan_in: usize, mutation: bool) -> f64 {
+        let normal = |rng: &mut R, std_dev: f64| {
+            Normal::new(0.0, std_dev)
+                .map(|d| d.sample(rng))
+                .unwrap_or(0.0)
+        };
+        let uniform = |rng: &mut R, x: f64| {
+            if x > 0.0 {
+                rng.gen_range(-x..=x)
+            } else {
+                0.0
+            }
+        };
+        let fan_in = fan_in.max(1) as f64;
+
+        match *self {
+            WeightInit::Constant(value) => value,
+            WeightInit::Uniform(x) => uniform(rng, x),
+            WeightInit::Normal(std_dev) => normal(rng, std_dev),
+            WeightInit::NearZero(x) if mutation => uniform(rng, x),
+            WeightInit::NearZero(_) => INITIAL_WEIGHT_VALUE,
+            WeightInit::Xavier => normal(rng, (1.0 / fan_in).sqrt()),
+            WeightInit::He => normal(rng, (2.0 / fan_in).sqrt()),
+        }
+    }
+}
+
+/// How the threshold for deciding whether two fitness values are similar is determined.
+#[derive(Clone, Copy, Debug)]
+pub enum FitnessSimilarity {
+    /// Fitness values are similar if they differ by less than this amount. This is only suitable
+    /// for a known range of fitness values.
+    Absolute(f64),
+    /// Fitness values are similar if they differ by less than this fraction of the absolute value
+    /// of the better one, so `0.05` means within 5%.
+    Relative(f64),
+    /// Fitness values are similar if they differ by less than this fraction of the current fitness
+    /// spread, which is the difference between the worst and the best fitness among the
+    /// individuals being selected from.
+    Adaptive(f64),
+}
+
+impl FitnessSimilarity {
+    /// Returns the threshold with an adaptive threshold replaced by the absolute threshold it
+    /// corresponds to for the given fitness values.
+    pub(crate) fn resolve(self, fitnesses: impl Iterator<Item = f64>) -> Self {
+        match self {
+            FitnessSimilarity::Adaptive(fraction) => {
+                let (min, max) = fitnesses
+                    .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), f| {
+                        (min.min(f), max.max(f))
+                    });
+                let spread = if min <= max { max - min } else { 0.0 };
+                FitnessSimilarity::Absolute(fraction * spread)
+            }
+            other => other,
+        }
+    }
+
+    /// Returns whether the two fitness values are similar. Lower fitness is better. Adaptive
+    /// thresholds must be resolved first.
+    pub(crate) fn is_similar(&self, a: f64, b: f64) -> bool {
+        let threshold = match *self {
+            FitnessSimilarity::Absolute(threshold) => threshold,
+            FitnessSimilarity::Relative(fraction) => fraction * a.min(b).abs(),
+            FitnessSimilarity::Adaptive(_) => panic!("adaptive thresholds must be resolved first"),
+        };
+
+        (a - b).abs() < threshold
+    }
+}
+
+impl From<f64> for FitnessSimilarity {
+    fn from(threshold: f64) -> Self {
+        FitnessSimilarity::Absolute(threshold)
+    }
+}
+
+/// How the complexity of a network is measured. Lower values are preferred.
+#[derive(Clone)]
+pub enum ComplexityMeasure {
+    /// The total number of genes.
+    Genes,
+    /// The number of neurons, including the outputs.
+    Neurons,
+    /// The number of connections between values, which are the inputs, jumpers and hidden neurons
+    /// (each of which feeds its parent). This is the number of multiply-adds per evaluation, not
+    /// counting biases.
+    Connections,
+    /// The number of weights that affect the outputs, which are all weights except for those of
+    /// the output neurons.
+    Weights,
+    /// Like `Weights`, but only counts weights whose absolute value is greater than the given
+    /// threshold. Unlike the other built-in measures, this depends on the weights, so with
+    /// `Parsimony::during_optimization` it drives CMA-ES towards connections with no effect.
+    ActiveWeights(f64),
+    /// The number of neurons on the longest path from an output to a neuron without hidden
+    /// children.
+    Depth,
+    /// The number of values that must be stored between evaluations for recurrent jumpers.
+    RecurrentState,
+    /// A user-defined measure.
+    Custom(Arc<dyn Fn(&Network) -> f64 + Send + Sync>),
+}
+
+impl ComplexityMeasure {
+    /// Returns a user-defined measure.
+    pub fn custom<F: Fn(&Network) -> f64 + Send + Sync + 'static>(measure: F) -> Self {
+        ComplexityMeasure::Custom(Arc::new(measure))
+    }
+
+    /// Returns the complexity of the network.
+    pub fn measure(&self, network: &Network) -> f64 {
+        let complexity = match self {
+            ComplexityMeasure::Genes => network.len(),
+            ComplexityMeasure::Neurons => network.num_neurons(),
+            ComplexityMeasure::Connections => {
+                let biases = network.genome().iter().filter(|g| g.is_bias()).count();
+                network.len() - network.num_outputs() - biases
+            }
+            ComplexityMeasure::Weights => network.len() - network.num_outputs(),
+            ComplexityMeasure::ActiveWeights(threshold) => network
+                .genome()
+                .iter()
+                .zip(network.parents())
+                .filter(|(gene, parent)| parent.is_some() && gene.weight().abs() > *threshold)
+                .count(),
+            ComplexityMeasure::Depth => network
+                .neuron_ids()
+                .map(|id| network[id].depth() + 1)
+                .max()
+                .unwrap_or(0),
+            ComplexityMeasure::RecurrentState => network.recurrent_state_len(),
+            ComplexityMeasure::Custom(measure) => return measure(network),
+        };
+
+        complexity as f64
+    }
+}
+
+impl fmt::Debug for ComplexityMeasure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ComplexityMeasure::Genes => write!(f, "Genes"),
+            ComplexityMeasure::Neurons => write!(f, "Neurons"),
+            ComplexityMeasure::Connections => write!(f, "Connections"),
+            ComplexityMeasure::Weights => write!(f, "Weights"),
+            ComplexityMeasure::ActiveWeights(threshold) => {
+                write!(f, "ActiveWeights({:?})", threshold)
+            }
+            ComplexityMeasure::Depth => write!(f, "Depth"),
+            ComplexityMeasure::RecurrentState => write!(f, "RecurrentState"),
+            ComplexityMeasure::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+/// How networks are compared to decide whether they are duplicates or similar during selection.
+#[derive(Clone, Copy, Debug)]
+pub enum SimilarityCheck {
+    /// Compares neuron IDs and genes directly. This is fast, but networks with the same structure
+    /// are only recognized if they share neuron IDs, which is usually the case for networks
+    /// descended from the same ancestor.
+    Genome,
+    /// Compares the canonical forms of the networks, which detects structurally equivalent networks
+    /// regardless of their neuron IDs and gene order (see [`canonical`][crate::canonical]).
+    Canonical,
+    /// Like `Canonical`, but ignores connections whose weight has an absolute value of at most
+    /// `epsilon`, as they barely affect the output of a network.
+    CanonicalIgnoringWeightless { epsilon: f64 },
+}
+
+/// How the initial population is created from a seed network.
+#[derive(Clone, Copy, Debug)]
+pub enum Seeding {
+    /// The whole population starts as copies of the seed.
+    Copies,
+    /// One individual is an exact copy of the seed, and the others are variants of it, each
+    /// produced by applying `mutations` random structural mutations. A fraction `random` of the
+    /// population consists of random minimal networks instead, as if no seed was given.
+    Variants { mutations: usize, random: f64 },
+}
+
+impl Seeding {
+    /// Returns the seeding, or panics if the fraction of random networks is not in `[0, 1]`.
+    pub(crate) fn checked(self) -> Self {
+        if let Seeding::Variants { random, .. } = self {
+            checked("random", random, 0.0..=1.0);
+        }
+        self
+    }
+}
+
+/// How operator rewards are turned into mutation probabilities.
+#[derive(Clone, Copy, Debug)]
+pub enum AdaptationScheme {
+    /// Each probability is proportional to the estimated reward of its mutation type (on top of
+    /// the minimum probability).
+    ProbabilityMatching,
+    /// The probability of the mutation type with the highest estimated reward is moved towards the
+    /// maximum probability and all others towards the minimum, at the rate `beta`. This reacts
+    /// faster to a single clearly better mutation type than probability matching.
+    AdaptivePursuit { beta: f64 },
+}
+
+impl AdaptationScheme {
+    /// Returns the scheme, or panics if the rate of adaptive pursuit is not in `[0, 1]`.
+    fn checked(self) -> Self {
+        if let AdaptationScheme::AdaptivePursuit { beta } = self {
+            checked("beta", beta, 0.0..=1.0);
+        }
+        self
+    }
+}
+
+/// What counts as a success for the mutation types that produced an offspring.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MutationCredit {
+    /// The offspring survived selection.
+    Survival,
+    /// The offspring reached a better fitness than its parent after optimization.
+    Improvement,
+}
+
+/// Options for adapting the mutation probabilities during the run.
+#[derive(TypedBuilder, Clone)]
+pub struct AdaptiveMutation {
+    #[builder(
+    default = AdaptationScheme::ProbabilityMatching,
+    setter(
+        transform = |scheme: AdaptationScheme| scheme.checked(),
+        doc = "Sets how the estimated rewards are turned into probabilities. The `beta` of adaptive pursuit
+                  must be in `[0, 1]`. Default: probability matching."))]
+    pub scheme: AdaptationScheme,
+
+    #[builder(
+    default = MutationCredit::Survival,
+    setter(doc = "Sets what counts as a success of a mutation type. Default: surviving selection."))]
+    pub credit: MutationCredit,
+
+    #[builder(
+    default = 0.3,
+    setter(
+        transform = |rate: f64| checked("learning_rate", rate, 0.0..=1.0),
+        doc = "Sets the rate at which the estimated reward of each mutation type follows its success rate
+                  in the latest generation. It must be in `[0, 1]`. Default: `0.3`."))]
+    pub learning_rate: f64,
+
+    #[builder(
+    default = 0.05,
+    setter(
+        transform = |p: f64| checked("min_probability", p, 0.0..=0.25),
+        doc = "Sets the lowest probability any mutation type may have, so that none is ever disabled
+                  entirely. There are four mutation types, so it must be at most `0.25`. Default: `0.05`."))]
+    pub min_probability: f64,
+
+    #[builder(
+    default = 1.0,
+    setter(
+        transform = |p: f64| checked("max_probability", p, 0.25..=1.0),
+        doc = "Sets the highest probability any mutation type may have. It is always limited such that
+                  all other types can keep `min_probability`. There are four mutation types, so it must be
+                  at least `0.25`. Default: `1.0`."))]
+    pub max_probability: f64,
+}
+
+/// How strongly the complexity of a network is penalized.
+#[derive(Clone, Copy, Debug)]
+pub enum ParsimonyPressure {
+    /// Adds `coefficient * complexity` to the fitness.
+    Linear(f64),
+    /// Adds no penalty, but ranks networks with equal fitness by complexity and disables the
+    /// fitness similarity threshold of the default selection strategy, so that fitness is never
+    /// traded for a smaller network.
+    Lexicographic,
+    /// Covariant parsimony pressure (Poli and McPhee, 2008). Adds `coefficient * complexity` to the
+    /// fitness, where the coefficient is recomputed before each selection as
+    /// `-Cov(complexity, fitness) / Var(complexity)` over the individuals being selected from. This
+    /// is the coefficient that cancels out the advantage larger networks have, which keeps the
+    /// complexity of the population from growing without a fixed trade-off between fitness and
+    /// complexity. Negative coefficients, which would reward complexity, are clamped to zero.
+    Covariant,
+}
+
+/// Options for penalizing complex networks.
+#[derive(TypedBuilder, Clone, Copy, Debug)]
+pub struct Parsimony {
+    #[builder(setter(
+        doc = "Sets the penalty that is applied to the complexity measure of `Exploration::complexity`."
+    ))]
+    pub pressure: ParsimonyPressure,
+
+    #[builder(setter(
+        strip_bool,
+        doc = "Also adds the penalty to the objective of CMA-ES, which only has an effect if the
+                  complexity measure depends on the weights, like `ComplexityMeasure::ActiveWeights`
+                  or a custom measure. With the other built-in measures, the penalty is the same for
+                  all weights. Covariant pressure uses the coefficient of the latest selection.
+                  Default: disabled."
+    ))]
+    pub during_optimization: bool,
+}
+
+impl Parsimony {
+    /// Returns the penalty coefficient for a population with the given pairs of raw fitness and
+    /// complexity.
+    pub(crate) fn coefficient(&self, population: &[(f64, f64)]) -> f64 {
+        match self.pressure {
+            ParsimonyPressure::Linear(coefficient) => coefficient,
+            ParsimonyPressure::Lexicographic => 0.0,
+            ParsimonyPressure::Covariant => {
+                let n = population.len() as f64;
+                let mean_fitness = population.iter().map(|(f, _)| f).sum::<f64>() / n;
+                let mean_complexity = population.iter().map(|(_, c)| c).sum::<f64>() / n;
+                let covariance = population
+                    .iter()
+                    .map(|(f, c)| (f - mean_fitness) * (c - mean_complexity))
+                    .sum::<f64>()
+                    / n;
+                let variance = population
+                    .iter()
+                    .map(|(_, c)| (c - mean_complexity).powi(2))
+                    .sum::<f64>()
+                    / n;
+
+                if variance > 0.0 {
+                    (-covariance / variance).max(0.0)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+/// Options for dividing the population into species, as in NEAT.
+///
+/// The compatibility distance between two networks is
+/// `connections * C / N + neurons * M / N + weights * W`, where `C` is the number of connections
+/// that only one of the networks has, `M` the number of neurons only one of them has, `N` the
+/// length of the longer genome, and `W` the mean absolute weight difference of the genes they
+/// share. Genes are matched by neuron ID and, for connections, by the neuron they belong to and the
+/// input, bias or neuron they connect from.
+#[derive(TypedBuilder, Clone, Copy, Debug)]
+pub struct Speciation {
+    #[builder(
+        default = 3.0,
+        setter(
+            doc = "Sets the compatibility distance below which a network belongs to a species, measured to
+                  the best network of the species in the previous generation. Default: `3.0`."
+        )
+    )]
+    pub threshold: f64,
+
+    #[builder(
+        default = 1.0,
+        setter(doc = "Sets the weight of differing connections in the distance. Default: `1.0`.")
+    )]
+    pub connections: f64,
+
+    #[builder(
+        default = 1.0,
+        setter(doc = "Sets the weight of differing neurons in the distance. Default: `1.0`.")
+    )]
+    pub neurons: f64,
+
+    #[builder(
+        default = 0.4,
+        setter(
+            doc = "Sets the weight of the mean weight difference in the distance. Default: `0.4`."
+        )
+    )]
+    pub weights: f64,
+
+    #[builder(
+        default = 15,
+        setter(
+            doc = "Sets the number of generations without improving on its best fitness after which a species
+                  is removed from the population. The species with the best individual is never
+                  removed. Default: `15`."
+        )
+    )]
+    pub stagnation: usize,
+}
+
+/// Structural constraints on the networks produced by the algorithm. Use these to keep networks
+/// within a size that CMA-ES can still optimize, or within the budget of the target hardware.
+///
+/// Initial networks are trimmed to `max_genes` where possible, but must contain at least one neuron
+/// and one input gene per output.
+#[derive(TypedBuilder, Clone, Default)]
+pub struct Constraints {
+    #[builder(
+    default = None,
+    setter(strip_option, doc = "Sets the maximum number of hidden (non-output) neurons. Default: no limit."))]
+    pub max_hidden_neurons: Option<usize>,
+
+    #[builder(
+    default = None,
+    setter(strip_option, doc = "Sets the maximum depth of any neuron, where output neurons have a depth of zero.
+                  Default: no limit."))]
+    pub max_depth: Option<usize>,
+
+    #[builder(
+    default = None,
+    setter(strip_option, doc = "Sets the maximum number of genes in a network. Every gene holds exactly one weight,
+                  so this is also the maximum number of parameters. Default: no limit."))]
+    pub max_genes: Option<usize>,
+
+    #[builder(
+    default = None,
+    setter(strip_option, doc = "Sets which network inputs each network output may depend on. Jumper connections
+                  are followed, so an input may not reach a forbidden output indirectly either. The mask
+                  must cover exactly the configured inputs and outputs, otherwise running the algorithm
+                  panics. Default: all connections allowed."))]
+    pub connections: Option<ConnectionMask>,
+
+    #[builder(setter(
+        strip_bool,
+        doc = "Forbids recurrent connections, so that all networks are feedforward and stateless. Default:
+                  recurrent connections allowed."
+    ))]
+    pub feedforward: bool,
+}
+
+/// A mask of which network inputs each network output may depend on.
+///
+/// ```rust
+/// use eant2::options::ConnectionMask;
+///
+/// // Output 1 may not depend on input 0
+/// let mask = ConnectionMask::allow_all(3, 2).forbid(0, 1);
+/// assert!(!mask.allows(0, 1));
+/// assert!(mask.allows(0, 0));
+/// ```
+#[derive(Clone, Debug)]
+pub struct ConnectionMask {
+    /// Whether each input is allowed, indexed by output first
+    allowed: Vec<Vec<bool>>,
+}
+
+impl ConnectionMask {
+    /// A mask that allows every input to reach every output.
+    pub fn allow_all(inputs: usize, outputs: usize) -> Self {
+        Self {
+            allowed: vec![vec![true; inputs]; outputs],
+        }
+    }
+
+    /// A mask that forbids every input from reaching every output.
+    pub fn forbid_all(inputs: usize, outputs: usize) -> Self {
+        Self {
+            allowed: vec![vec![false; inputs]; outputs],
+        }
+    }
+
+    /// Allows the input to reach the output.
+    pub fn allow(mut self, input: usize, output: usize) -> Self {
+        self.allowed[output][input] = true;
+        self
+    }
+
+    /// Forbids the input from reaching the output.
+    pub fn forbid(mut self, input: usize, output: usize) -> Self {
+        self.allowed[output][input] = false;
+        self
+    }
+
+    /// Returns whether the input may reach the output. Panics if either is outside of the mask.
+    pub fn allows(&self, input: usize, output: usize) -> bool {
+        self.allowed[output][input]
+    }
+
+    /// Returns the number of inputs the mask covers.
+    pub fn inputs(&self) -> usize {
+        self.allowed.first().map_or(0, Vec::len)
+    }
+
+    /// Returns the number of outputs the mask covers.
+    pub fn outputs(&self) -> usize {
+        self.allowed.len()
+    }
+}
+
 /// When should CMA-ES (inner loop) terminate?
 /// You usually don't need to configure this.
 #[derive(TypedBuilder)]
@@ -81,9 +951,19 @@ pub struct CMAESTermination {
 }
 
 /// Exploitation options.
-/// These are the options that control parameter optimization (CMA-ES).
+/// These are the options that control parameter optimization (CMA-ES by default).
 #[derive(TypedBuilder)]
 pub struct Exploitation {
+    #[builder(
+        default_code = "Arc::new(Cmaes)",
+        setter(
+            transform = |optimizer: impl ParameterOptimizer + 'static| Arc::new(optimizer) as Arc<dyn ParameterOptimizer>,
+            doc = "Sets the optimizer for the weights of the networks. `restart` only applies to the default
+                  optimizer. Default: `Cmaes`."
+        )
+    )]
+    pub optimizer: Arc<dyn ParameterOptimizer>,
+
     /// CMA-ES parameter optimization restart strategy. Defaults to `RestartStrategy::Local`.
     #[builder(
         default_code = "RestartStrategy::Local(Local::new(2, Some(5e1)).unwrap())",
@@ -100,4 +980,431 @@ pub struct Exploitation {
         )
     )]
     pub terminate: CMAESTermination,
+
+    #[builder(
+        default = DEFAULT_REOPTIMIZATION,
+        setter(
+            transform = |policy: Reoptimization| policy.checked(),
+            doc = "Sets whether individuals that were already optimized, which are mainly the surviving
+                  parents, are optimized again in later generations. `Every` and `Reduced` must be
+                  given at least one generation or evaluation. Default: `Reoptimization::Always`."
+        )
+    )]
+    pub reoptimization: Reoptimization,
+
+    #[builder(
+        default,
+        setter(
+            strip_option,
+            doc = "Continues optimization from the final CMA-ES search distribution of the parent, or
+                  of the last optimization when re-optimizing, instead of starting from scratch. Warm
+                  starting always uses CMA-ES, so `optimizer` is not used. Default: disabled."
+        )
+    )]
+    pub warm_start: Option<WarmStart>,
+
+    #[builder(
+        default = DEFAULT_SEARCH_RANGE,
+        setter(
+            doc = "Sets the range around the current weights that CMA-ES draws its initial means from,
+                  scaled by the deviation of each gene. The restart strategy derives its initial step
+                  size from the size of this range, so it should be larger for tasks that need large
+                  weights. Default: `-1.0..=1.0`."
+        )
+    )]
+    pub search_range: RangeInclusive<f64>,
+
+    #[builder(
+        default = DEFAULT_AGE_DEVIATION,
+        setter(
+            doc = "Sets how the search for the weight of a gene is narrowed as the gene ages. Default:
+                  `AgeDeviation::InverseSquare`."
+        )
+    )]
+    pub deviation: AgeDeviation,
+
+    #[builder(
+        default,
+        setter(
+            strip_option,
+            doc = "Resets the age of a gene to zero whenever an optimization changes its weight by more
+                  than this amount, so that genes that are still moving keep being searched widely.
+                  Default: disabled."
+        )
+    )]
+    pub age_reset: Option<f64>,
+
+    #[builder(
+        default,
+        setter(
+            strip_option,
+            doc = "Fine-tunes the weights by gradient descent on the dataset of the fitness function, if it
+                  provides one (see `FitnessFunction::dataset`). Default: disabled."
+        )
+    )]
+    pub fine_tuning: Option<FineTuning>,
+}
+
+/// The deviation of the search for the weight of a gene as a function of the age of the gene,
+/// which is the number of generations since it was added. The search range is scaled by the
+/// deviation, so old genes are only fine-tuned. When warm starting, only the initial distribution
+/// of individuals without an inherited distribution is scaled by it, as inherited distributions
+/// keep the covariance of the parent and new genes start with `WarmStart::new_gene_variance`.
+#[derive(Clone)]
+pub enum AgeDeviation {
+    /// `1 / (1 + age^2)`, which freezes old genes quickly.
+    InverseSquare,
+    /// `1 / (1 + age)`.
+    Inverse,
+    /// `exp(-rate * age)`.
+    Exponential(f64),
+    /// The same deviation regardless of age.
+    Constant(f64),
+    /// A user-defined schedule.
+    Custom(Arc<dyn Fn(usize) -> f64 + Send + Sync>),
+}
+
+impl AgeDeviation {
+    /// Returns a user-defined schedule.
+    pub fn custom<F: Fn(usize) -> f64 + Send + Sync + 'static>(deviation: F) -> Self {
+        AgeDeviation::Custom(Arc::new(deviation))
+    }
+
+    /// Returns the deviation for a gene of the given age.
+    pub fn deviation(&self, age: usize) -> f64 {
+        match self {
+            AgeDeviation::InverseSquare => 1.0 / (1 + age * age) as f64,
+            AgeDeviation::Inverse => 1.0 / (1 + age) as f64,
+            AgeDeviation::Exponential(rate) => (-rate * age as f64).exp(),
+            AgeDeviation::Constant(deviation) => *deviation,
+            AgeDeviation::Custom(deviation) => deviation(age),
+        }
+    }
+}
+
+impl fmt::Debug for AgeDeviation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AgeDeviation::InverseSquare => write!(f, "InverseSquare"),
+            AgeDeviation::Inverse => write!(f, "Inverse"),
+            AgeDeviation::Exponential(rate) => write!(f, "Exponential({rate})"),
+            AgeDeviation::Constant(deviation) => write!(f, "Constant({deviation})"),
+            AgeDeviation::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+/// Options for warm-starting CMA-ES.
+///
+/// Each optimization keeps its final step size and covariance matrix, which offspring inherit for
+/// the genes they share with their parent. New genes start uncorrelated with the given variance.
+/// Structures that differ from their parent by a single mutation then converge much faster, but
+/// each optimization is a single CMA-ES run starting from the current weights, so
+/// `Exploitation::restart` is not used.
+#[derive(TypedBuilder, Clone, Copy, Debug)]
+pub struct WarmStart {
+    #[builder(
+        default = 1.0,
+        setter(
+            transform = |step_size: f64| positive("step_size", step_size),
+            doc = "Sets the initial step size for individuals without a search distribution to continue
+                  from, which are the initial population and offspring produced by crossover. Their
+                  covariance is given by the gene ages. It is also used instead of the distribution of
+                  a run that diverged. Must be positive. Default: `1.0`."
+        )
+    )]
+    pub step_size: f64,
+
+    #[builder(
+        default = 0.25,
+        setter(
+            transform = |variance: f64| positive("new_gene_variance", variance),
+            doc = "Sets the variance of the weights of genes added by mutation. Must be positive.
+                  Default: `0.25`."
+        )
+    )]
+    pub new_gene_variance: f64,
+}
+
+/// Gradient-based fine-tuning of the weights for supervised tasks. The gradient of the mean loss
+/// over the dataset of the fitness function is computed by backpropagation (see the `gradient`
+/// module), and the steps of each weight are scaled by the deviation of its gene like the
+/// optimizer. The fine-tuned weights are kept if the fitness function rates them better than the
+/// weights found by the optimizer. Fitness functions without a dataset only use the optimizer.
+#[derive(TypedBuilder, Clone, Copy, Debug)]
+pub struct FineTuning {
+    #[builder(
+        default = GradientMethod::Adam { learning_rate: 0.01 },
+        setter(doc = "Sets the gradient method. Default: `GradientMethod::Adam { learning_rate: 0.01 }`.")
+    )]
+    pub method: GradientMethod,
+
+    #[builder(
+        default = FineTuningMode::Follow,
+        setter(
+            doc = "Sets whether fine-tuning replaces the optimizer or follows it. Default:
+                  `FineTuningMode::Follow`."
+        )
+    )]
+    pub mode: FineTuningMode,
+
+    #[builder(
+        default = 100,
+        setter(doc = "Sets the maximum number of gradient steps. Default: `100`.")
+    )]
+    pub steps: usize,
+
+    #[builder(
+        default,
+        setter(
+            strip_option,
+            doc = "Truncates backpropagation through time to windows of this many steps of each
+                  sequence, which bounds the cost of long sequences. Default: disabled."
+        )
+    )]
+    pub truncation: Option<usize>,
+
+    #[builder(
+        default = 1e-8,
+        setter(
+            doc = "Stops once the norm of the gradient, scaled by the gene deviations, falls to this
+                  value. Default: `1e-8`."
+        )
+    )]
+    pub tolerance: f64,
+}
+
+/// The method used to fine-tune the weights with the gradient.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GradientMethod {
+    /// Adam with the given learning rate, which is the largest step of a weight of deviation `1`.
+    Adam { learning_rate: f64 },
+    /// L-BFGS keeping the given number of past steps, with a backtracking line search. It usually
+    /// converges in far fewer steps than Adam on smooth losses, but each step may evaluate the
+    /// dataset several times.
+    Lbfgs { memory: usize },
+}
+
+/// Where fine-tuning runs in the exploitation phase.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FineTuningMode {
+    /// Fine-tune the current weights instead of running the optimizer.
+    Replace,
+    /// Fine-tune the best weights found by the optimizer.
+    Follow,
+}
+
+/// Whether individuals that were already optimized are optimized again. Surviving parents are
+/// carried over to the next generation unchanged, and re-optimizing them gives CMA-ES more chances
+/// to find good weights for their structure, but often for little gain. Offspring whose structure
+/// was changed are always optimized.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reoptimization {
+    /// Re-optimize in every generation.
+    Always,
+    /// Never re-optimize, and keep the fitness of the first optimization.
+    Never,
+    /// Re-optimize in every `K`th generation after the last optimization. `Every(1)` is the same
+    /// as `Always`.
+    Every(usize),
+    /// Re-optimize in every generation, but with at most the given number of fitness evaluations
+    /// (in addition to the limits of `Exploitation::terminate`).
+    Reduced(usize),
+    /// Re-optimize while the youngest gene of the network is younger than the given number of
+    /// generations. Genes age by one in every generation, so `WhileYoung(2)` re-optimizes a new
+    /// structure once more in the generation after it was produced.
+    WhileYoung(usize),
+    /// Re-optimize while the last optimization improved the fitness by at least the given amount.
+    WhileImproving(f64),
+}
+
+impl Reoptimization {
+    /// Returns whether an individual that was already optimized is optimized again, given the age
+    /// of its youngest gene, the number of generations in which its re-optimization was skipped
+    /// in a row, and how much its last re-optimization improved its fitness (if it was
+    /// re-optimized before).
+    pub(crate) fn applies(
+        &self,
+        youngest_gene: usize,
+        skipped: usize,
+        improvement: Option<f64>,
+    ) -> bool {
+        match *self {
+            Reoptimization::Always | Reoptimization::Reduced(_) => true,
+            Reoptimization::Never => false,
+            Reoptimization::Every(k) => skipped + 1 >= k,
+            Reoptimization::WhileYoung(max_age) => youngest_gene < max_age,
+            Reoptimization::WhileImproving(threshold) => {
+                improvement.is_none_or(|improvement| improvement >= threshold)
+            }
+        }
+    }
+
+    /// Returns the policy, or panics if it re-optimizes every zero generations or with a budget of
+    /// zero evaluations.
+    fn checked(self) -> Self {
+        match self {
+            Reoptimization::Every(0) => panic!("`Reoptimization::Every` needs at least one generation"),
+            Reoptimization::Reduced(0) => {
+                panic!("`Reoptimization::Reduced` needs at least one evaluation")
+            }
+            _ => self,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cge::gene::{Bias, Input, InputId, Neuron, NeuronId, RecurrentJumper};
+
+    use super::*;
+
+    /// Returns the mean and standard deviation of many samples of the weight initialization.
+    fn moments(init: WeightInit, fan_in: usize, mutation: bool) -> (f64, f64) {
+        let mut rng = rand::thread_rng();
+        let samples = (0..20_000)
+            .map(|_| init.sample(&mut rng, fan_in, mutation))
+            .collect::<Vec<_>>();
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        let variance =
+            samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / samples.len() as f64;
+        (mean, variance.sqrt())
+    }
+
+    #[test]
+    fn test_weight_init() {
+        let mut rng = rand::thread_rng();
+        assert_eq!(WeightInit::Constant(0.3).sample(&mut rng, 5, true), 0.3);
+        assert_eq!(
+            WeightInit::NearZero(0.01).sample(&mut rng, 5, false),
+            INITIAL_WEIGHT_VALUE
+        );
+        for _ in 0..100 {
+            assert!(WeightInit::NearZero(0.01).sample(&mut rng, 5, true).abs() <= 0.01);
+            assert!(WeightInit::Uniform(2.0).sample(&mut rng, 5, true).abs() <= 2.0);
+        }
+
+        // Standard deviations: `x / sqrt(3)` for uniform, and `sqrt(1 / fan_in)` and
+        // `sqrt(2 / fan_in)` for Xavier and He
+        let expected = [
+            (WeightInit::Uniform(3.0_f64.sqrt()), 7, 1.0),
+            (WeightInit::Normal(2.0), 7, 2.0),
+            (WeightInit::Xavier, 4, 0.5),
+            (WeightInit::He, 8, 0.5),
+            (WeightInit::Xavier, 0, 1.0),
+        ];
+        for (init, fan_in, std_dev) in expected {
+            let (mean, actual) = moments(init, fan_in, true);
+            assert!(mean.abs() < 0.05 * std_dev, "{:?}", init);
+            assert!((actual - std_dev).abs() < 0.05 * std_dev, "{:?}", init);
+        }
+    }
+
+    #[test]
+    fn test_mutation_count() {
+        assert!(MutationCount::poisson(0.0).is_err());
+        assert!(MutationCount::poisson(f64::NAN).is_err());
+        assert!(MutationCount::poisson(f64::INFINITY).is_err());
+        assert!(MutationCount::geometric(0.0).is_err());
+        assert!(MutationCount::geometric(1.5).is_err());
+
+        let mut rng = rand::thread_rng();
+        let poisson = MutationCount::poisson(0.5).unwrap();
+        assert!((0..100).all(|_| poisson.sample(&mut rng, 0) >= 1));
+        let geometric = MutationCount::geometric(1.0).unwrap();
+        assert!((0..100).all(|_| geometric.sample(&mut rng, 0) == 1));
+    }
+
+    #[test]
+    fn test_reproduction() {
+        let ranked = [1.0, 2.0, 3.0, 5.0];
+        assert_eq!(Reproduction::Uniform.offspring(&ranked, 8), vec![2; 4]);
+        assert_eq!(
+            Reproduction::LinearRank(2.0).offspring(&ranked, 8),
+            vec![4, 3, 1, 0]
+        );
+        assert_eq!(
+            Reproduction::FitnessProportional.offspring(&ranked, 9),
+            vec![4, 3, 2, 0]
+        );
+        let schedule = Reproduction::Schedule(vec![3.0, 1.0]);
+        assert_eq!(schedule.offspring(&ranked, 6), vec![3, 1, 1, 1]);
+        // Equal fitness falls back to uniform, with the better-ranked individual rounded up first
+        assert_eq!(
+            Reproduction::FitnessProportional.offspring(&[1.0, 1.0], 3),
+            vec![2, 1]
+        );
+    }
+
+    #[test]
+    fn test_age_deviation() {
+        assert_eq!(AgeDeviation::InverseSquare.deviation(2), 0.2);
+        assert_eq!(AgeDeviation::Inverse.deviation(3), 0.25);
+        assert_eq!(AgeDeviation::Exponential(0.5).deviation(0), 1.0);
+        assert_eq!(AgeDeviation::Constant(0.3).deviation(10), 0.3);
+        assert_eq!(AgeDeviation::custom(|age| age as f64).deviation(4), 4.0);
+    }
+
+    #[test]
+    fn test_complexity_measures() {
+        let genome = vec![
+            Neuron::new(NeuronId::new(0), 3, 1.0).into(),
+            Bias::new(1.0).into(),
+            Neuron::new(NeuronId::new(1), 2, 1.0).into(),
+            Input::new(InputId::new(0), 0.1).into(),
+            RecurrentJumper::new(NeuronId::new(0), -0.5).into(),
+            Input::new(InputId::new(1), 1.0).into(),
+        ];
+        let network = Network::new(genome, Activation::Sigmoid).unwrap();
+
+        let measures = [
+            (ComplexityMeasure::Genes, 6.0),
+            (ComplexityMeasure::Neurons, 2.0),
+            (ComplexityMeasure::Connections, 4.0),
+            (ComplexityMeasure::Weights, 5.0),
+            (ComplexityMeasure::ActiveWeights(0.5), 3.0),
+            (ComplexityMeasure::Depth, 2.0),
+            (ComplexityMeasure::RecurrentState, 1.0),
+            (ComplexityMeasure::custom(|n| n.num_inputs() as f64), 2.0),
+        ];
+        for (measure, expected) in measures {
+            assert_eq!(measure.measure(&network), expected, "{:?}", measure);
+        }
+    }
+
+    #[test]
+    fn test_fitness_similarity() {
+        assert!(FitnessSimilarity::Absolute(0.15).is_similar(1.0, 1.1));
+        assert!(!FitnessSimilarity::Relative(0.05).is_similar(1.0, 1.1));
+        assert!(FitnessSimilarity::Relative(0.05).is_similar(-1000.0, -960.0));
+
+        let adaptive = FitnessSimilarity::Adaptive(0.5).resolve([0.0, 10.0, 4.0].into_iter());
+        assert!(adaptive.is_similar(0.0, 4.9));
+        assert!(!adaptive.is_similar(0.0, 5.1));
+    }
+
+    #[test]
+    fn test_reoptimization() {
+        assert!(!Reoptimization::Never.applies(1, 5, None));
+        assert!(!Reoptimization::Every(3).applies(1, 1, None));
+        assert!(Reoptimization::Every(3).applies(1, 2, None));
+        assert!(Reoptimization::WhileYoung(2).applies(1, 0, None));
+        assert!(!Reoptimization::WhileYoung(2).applies(2, 0, None));
+        assert!(Reoptimization::WhileImproving(0.1).applies(5, 0, None));
+        assert!(!Reoptimization::WhileImproving(0.1).applies(5, 0, Some(0.05)));
+    }
+
+    #[test]
+    #[should_panic(expected = "crossover")]
+    fn test_invalid_crossover_rate() {
+        Exploration::builder().crossover(1.5).build();
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one evaluation")]
+    fn test_empty_reoptimization_budget() {
+        Exploitation::builder()
+            .reoptimization(Reoptimization::Reduced(0))
+            .build();
+    }
 }
@@ -0,0 +1,236 @@
+//! Simplification of trained networks.
+//!
+//! Networks found by EANT2 often contain genes that contribute almost nothing to their fitness.
+//! [`prune`] removes such genes one by one for as long as the fitness stays within a tolerance of
+//! the original fitness, which results in smaller networks that are faster to evaluate and easier
+//! to inspect.
+
+use cge::gene::{Bias, Gene, NeuronId};
+use cmaes::{CMAESOptions, DVector, Mode};
+
+use std::collections::HashSet;
+
+use crate::cge_utils::{map_genome, Network, NetworkView};
+use crate::FitnessFunction;
+
+/// The initial step size of the CMA-ES runs used to re-tune networks. It is small because the
+/// weights only need to compensate for a single removed gene.
+const RETUNE_STEP_SIZE: f64 = 0.1;
+
+/// A part of a network that may be removed.
+#[derive(Clone, Copy, Debug)]
+enum Removal {
+    /// A non-neuron gene at the given index
+    Gene(usize),
+    /// A hidden neuron along with its subgenome and all jumpers from the neurons in it
+    Neuron(NeuronId),
+}
+
+/// Repeatedly removes the least significant part of the network, until removing any more parts
+/// would make the fitness worse than the original fitness by more than `tolerance`. Returns the
+/// pruned network and its fitness.
+///
+/// Removal candidates are all non-neuron genes that are not the only input of their neuron, and
+/// all hidden neurons along with their subgenomes. Removing a neuron also removes all jumpers from
+/// it and from the neurons in its subgenome. In each step, every candidate is tried, and the one
+/// whose removal results in the best fitness is removed. Neurons that lose all of their inputs
+/// receive a zero-weight bias, so the result is always a valid network.
+///
+/// The state of the network is cleared before each evaluation of the fitness function.
+pub fn prune<T: FitnessFunction>(network: &Network, object: &T, tolerance: f64) -> (Network, f64) {
+    prune_network(network, object, tolerance, None)
+}
+
+/// Like [`prune`], but if removing even the least significant part would exceed the tolerance, the
+/// weights of the pruned network are first re-tuned with a short CMA-ES run that evaluates the
+/// fitness function at most `evaluations` times. Pruning only stops if the re-tuned network is
+/// still not good enough.
+pub fn prune_with_retuning<T: FitnessFunction>(
+    network: &Network,
+    object: &T,
+    tolerance: f64,
+    evaluations: usize,
+) -> (Network, f64) {
+    prune_network(network, object, tolerance, Some(evaluations))
+}
+
+fn prune_network<T: FitnessFunction>(
+    network: &Network,
+    object: &T,
+    tolerance: f64,
+    retune_evaluations: Option<usize>,
+) -> (Network, f64) {
+    let mut network = network.clone();
+    let mut fitness = evaluate(&mut network, object);
+    let limit = fitness + tolerance;
+
+    loop {
+        // Find the candidate whose removal hurts the fitness the least
+        let best = candidates(&network)
+            .into_iter()
+            .map(|removal| {
+                let mut pruned = remove(&network, removal);
+                let fitness = evaluate(&mut pruned, object);
+                (pruned, fitness)
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b));
+
+        let (mut pruned, mut pruned_fitness) = match best {
+            Some(best) => best,
+            None => break,
+        };
+
+        if pruned_fitness > limit {
+            if let Some(evaluations) = retune_evaluations {
+                pruned_fitness = retune(&mut pruned, object, pruned_fitness, evaluations);
+            }
+        }
+
+        if pruned_fitness > limit {
+            break;
+        }
+
+        network = pruned;
+        fitness = pruned_fitness;
+    }
+
+    (network, fitness)
+}
+
+/// Evaluates the fitness of the network, starting from a cleared state.
+fn evaluate<T: FitnessFunction>(network: &mut Network, object: &T) -> f64 {
+    network.clear_state();
+    object.fitness(NetworkView::new(network))
+}
+
+/// Returns all parts of the network that may be removed.
+fn candidates(network: &Network) -> Vec<Removal> {
+    let hidden = network
+        .neuron_ids()
+        .filter(|id| network[*id].depth() > 0)
+        .map(Removal::Neuron);
+
+    network
+        .get_valid_removals()
+        .map(Removal::Gene)
+        .chain(hidden)
+        .collect()
+}
+
+/// Returns a copy of the network with the given part removed.
+fn remove(network: &Network, removal: Removal) -> Network {
+    let mut network = network.clone();
+
+    match removal {
+        Removal::Gene(index) => {
+            network.remove_non_neuron(index).unwrap();
+            network
+        }
+        Removal::Neuron(id) => {
+            let range = network[id].subgenome_range();
+            let removed = network.genome()[range.clone()]
+                .iter()
+                .filter_map(|g| g.as_neuron().map(|n| n.id()))
+                .collect::<HashSet<_>>();
+
+            // Replace the subgenome with a placeholder gene that is dropped along with all jumpers
+            // from the removed neurons, so that the input count of the parent neuron is updated
+            let genome = network.genome();
+            let mut pruned = genome[..range.start].to_vec();
+            pruned.push(Bias::new(0.0).into());
+            pruned.extend_from_slice(&genome[range.end..]);
+            let mut placeholder = vec![false; pruned.len()];
+            placeholder[range.start] = true;
+
+            let (pruned, _) = map_genome(&pruned, &placeholder, false, |g, placeholder| {
+                let dropped = *placeholder
+                    || match g {
+                        Gene::ForwardJumper(forward) => removed.contains(&forward.source_id()),
+                        Gene::RecurrentJumper(recurrent) => {
+                            removed.contains(&recurrent.source_id())
+                        }
+                        _ => false,
+                    };
+                (!dropped).then(|| g.clone())
+            });
+
+            Network::new(pruned, network.activation()).unwrap()
+        }
+    }
+}
+
+/// Re-tunes the weights of the network with a short CMA-ES run, keeping the new weights only if
+/// they improve on `fitness`. Returns the resulting fitness.
+fn retune<T: FitnessFunction>(
+    network: &mut Network,
+    object: &T,
+    fitness: f64,
+    evaluations: usize,
+) -> f64 {
+    let initial_weights = network.weights().collect::<Vec<_>>();
+    let mut candidate = network.clone();
+    let objective = |x: &DVector<f64>| {
+        candidate.set_weights(x.as_slice()).unwrap();
+        evaluate(&mut candidate, object)
+    };
+
+    let best = CMAESOptions::new(initial_weights, RETUNE_STEP_SIZE)
+        .mode(Mode::Minimize)
+        .max_function_evals(evaluations)
+        .build(objective)
+        .ok()
+        .and_then(|mut cmaes| cmaes.run().overall_best);
+
+    match best {
+        Some(best) if best.value < fitness => {
+            network.set_weights(best.point.as_slice()).unwrap();
+            best.value
+        }
+        _ => fitness,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cge::gene::{ForwardJumper, Input, InputId, Neuron};
+    use cge::Activation;
+
+    use super::*;
+
+    /// The error of the network compared to `2 * x0 - x1`.
+    struct Target;
+
+    impl FitnessFunction for Target {
+        fn fitness(&self, mut network: NetworkView) -> f64 {
+            let data = [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0], [1.0, 2.0], [-1.0, 0.5]];
+            data.iter()
+                .map(|input| {
+                    let output = network.evaluate(input).unwrap()[0];
+                    (output - (2.0 * input[0] - input[1])).abs()
+                })
+                .sum()
+        }
+    }
+
+    #[test]
+    fn test_prune_removes_useless_genes() {
+        let genome = vec![
+            Neuron::new(NeuronId::new(0), 5, 1.0).into(),
+            Input::new(InputId::new(0), 2.0).into(),
+            Input::new(InputId::new(1), -1.0).into(),
+            Bias::new(0.0).into(),
+            Neuron::new(NeuronId::new(1), 2, 0.0).into(),
+            Input::new(InputId::new(0), 3.0).into(),
+            Neuron::new(NeuronId::new(2), 1, 1.0).into(),
+            Input::new(InputId::new(1), 1.0).into(),
+            ForwardJumper::new(NeuronId::new(2), 1e-12).into(),
+        ];
+        let network = Network::new(genome, Activation::Linear).unwrap();
+
+        let (pruned, fitness) = prune(&network, &Target, 1e-9);
+
+        assert!(fitness < 1e-9);
+        assert_eq!(pruned.len(), 3);
+        assert_eq!(pruned.num_outputs(), 1);
+    }
+}
@@ -1,31 +1,15 @@
 use cge::gene::{Gene, InputId, NeuronId};
 
 use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 
-use crate::generation::Generation;
-use crate::utils::{self, Individual};
-use crate::{FitnessFunction, Network};
-
-/// The maximum number of copies of each unique individual in the population allowed to survive
-/// selection. When set to one, no duplicates are allowed (only one unique copy may survive). If
-/// `force_meet_population_size` is set, this constraint may be violated if it would cause too few
-/// individuals to survive to meet the target population size.
-//
-// The paper was not entirely clear about whether this should be set to one or two, but one seems
-// more likely and was chosen, as retaining any duplicates probably provides little to no benefit on
-// average (they effectively serve only as additional CMA-ES runs with potentially differing initial
-// means).
-const MAX_COPIES: usize = 1;
-/// The maximum number of similar individuals allowed to survive for each unique individual in the
-/// population. When set to two, for a given network, only one additional network similar to it
-/// may survive, so if three or more networks are similar to each other, some will be culled. If
-/// `force_meet_population_size` is set, this constraint may be violated if it would cause too few
-/// individuals to survive to meet the target population size.
-//
-// The paper was not entirely clear about whether this should be set to two or three, but two is
-// more consistent with the above interpretation of `MAX_COPIES` and was chosen.
-const MAX_SIMILAR: usize = 2;
+use crate::canonical::{canonical_form, CanonicalForm};
+use crate::options::{FitnessSimilarity, Selection, SimilarityCheck};
+use crate::selection::{Candidate, Decision, Exclusion};
+use crate::utils;
+use crate::Network;
 
 /// The relationship between networks in a `NetworkGroup`.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -65,73 +49,47 @@ impl NetworkGroup {
     }
 
     /// Sorts this `NetworkGroup` by the criteria represented by `compare`.
-    fn sort<T>(
-        &mut self,
-        network_map: &HashMap<NetworkId, Individual<T>>,
-        similar_fitness_threshold: f64,
-    ) where
-        T: FitnessFunction + Clone,
-    {
-        self.network_ids.sort_by(|a, b| {
-            compare(
-                &network_map[&a],
-                &network_map[&b],
-                similar_fitness_threshold,
-            )
-        });
+    fn sort(&mut self, candidates: &[Candidate], similarity: &FitnessSimilarity) {
+        self.network_ids
+            .sort_by(|a, b| compare(&candidates[a.0], &candidates[b.0], similarity));
     }
 
     /// Returns the ID of the best network in this `NetworkGroup` that is valid to select if one
     /// exists. Networks for which the maximum number of similar networks or copies have already
-    /// been selected will not be returned.
+    /// been selected will not be returned. `similar_of` and `duplicate_of` contain the index of the
+    /// group containing each network in `similar_groups` and `duplicate_groups` respectively.
     fn best_constrained(
         &self,
         similar_groups: &[NetworkGroup],
+        similar_of: &[usize],
         max_similar: usize,
         duplicate_groups: &[NetworkGroup],
+        duplicate_of: &[usize],
         max_copies: usize,
     ) -> Option<NetworkId> {
         // Check that networks may still be removed from this group
-        if self.kind == GroupKind::Similar && self.num_taken() >= max_similar {
-            return None;
-        } else if self.kind == GroupKind::Duplicate && self.num_taken() >= max_copies {
+        if (self.kind == GroupKind::Similar && self.num_taken() >= max_similar)
+            || (self.kind == GroupKind::Duplicate && self.num_taken() >= max_copies)
+        {
             return None;
         }
 
         self.network_ids
             .iter()
-            .filter(|id| {
-                // Check other groups containing the ID to see whether the maximum number of
-                // networks have already been taken from them (which would mean the ID should not
-                // be removed from this one, as it would also need to be removed from the others,
-                // violating their constraints)
-                let constrained_by_any_group = |groups: &[NetworkGroup], max_per_group| {
-                    for g in groups {
-                        // Check whether the group's constraint on the network (if any) has already
-                        // been reached
-                        if g.contains(**id) {
-                            if g.num_taken() >= max_per_group {
-                                return true;
-                            }
-
-                            // Networks may only be in one group per group category
-                            break;
-                        }
-                    }
-
-                    false
-                };
-
+            .find(|id| {
+                // Check the group of the other category containing the ID to see whether the
+                // maximum number of networks have already been taken from it (which would mean the
+                // ID should not be removed from this one, as it would also need to be removed from
+                // the other, violating its constraint)
                 match self.kind {
-                    // If this is a Similar group, only check Duplicate groups (this is the only
-                    // group containing the ID in the Similar category)
-                    GroupKind::Similar => !constrained_by_any_group(duplicate_groups, max_copies),
-                    // If this is a Duplicate group, only check Similar groups (this is the only
-                    // group containing the ID in the Duplicate category)
-                    GroupKind::Duplicate => !constrained_by_any_group(similar_groups, max_similar),
+                    GroupKind::Similar => {
+                        duplicate_groups[duplicate_of[id.0]].num_taken() < max_copies
+                    }
+                    GroupKind::Duplicate => {
+                        similar_groups[similar_of[id.0]].num_taken() < max_similar
+                    }
                 }
             })
-            .next()
             .cloned()
     }
 
@@ -145,11 +103,6 @@ impl NetworkGroup {
         self.taken
     }
 
-    /// Returns whether this `NetworkGroup` contains the ID.
-    fn contains(&self, id: NetworkId) -> bool {
-        self.network_ids.contains(&id)
-    }
-
     /// Removes the given ID from this `NetworkGroup` if it exists. Returns whether the ID was
     /// found.
     fn find_and_remove(&mut self, id: NetworkId) -> bool {
@@ -163,35 +116,36 @@ impl NetworkGroup {
     }
 }
 
-/// Applies the selection operator to the population. By default, no more than `MAX_COPIES`
-/// instances of an individual and no more than `MAX_SIMILAR` structurally similar individuals will
-/// be kept in the population per unique individual, where structurally similar means that two
+/// Applies the selection operator to the population. No more than `options.max_copies` instances
+/// of an individual and no more than `options.max_similar` structurally similar individuals will be
+/// kept in the population per unique individual, where structurally similar means that two
 /// networks share the same base neuron structure regardless of their connections.
 ///
-/// The next generation will be no larger than `target_population_size`. However, it may be
-/// smaller than this value if `individuals.len() < target_population_size`. It may also be smaller
-/// due to the `MAX_COPIES` and `MAX_SIMILAR` constraints above. If this is not desired,
-/// `force_meet_population_size` may be set to `true` to gradually relax these constraints
-/// until the target size is met (or the entire population is selected).
+/// Returns the indices of the selected candidates, best first. No more than
+/// `target_population_size` candidates are selected. However, fewer may be selected if
+/// `candidates.len() < target_population_size`. It may also be smaller
+/// due to the constraints above. If this is not desired, `options.force_meet_population_size` may
+/// be set to gradually relax these constraints until the target size is met (or the entire
+/// population is selected).
 ///
-/// Uses `similar_fitness_threshold` (an absolute difference) to determine whether two networks have
-/// similar fitness values.
+/// Uses `similarity` to determine whether two networks have similar fitness values. An adaptive
+/// threshold is relative to the fitness spread of the candidates.
 //
 // NOTE: The algorithm for the selection operator implemented here is described below:
 //
 // 1. Sort all networks into groups of networks that are similar to and duplicates of each other.
 //     a. For each network:
-//         1. Compare it to the first network of each Duplicate group and add it to the group if the
-//            two are duplicates (this step requires that structural identicality is
-//            transitive).
+//         1. Compare it to the first network of each Duplicate group with the same structural hash
+//            and add it to the group if the two are duplicates (this step requires that structural
+//            identicality is transitive).
 //         2. If not added to an existing Duplicate group, create a new one and add the network to
 //            it.
-//         3. Compare it to the first network of each Similar group and add it to the group if the
-//            two are either similar or duplicates (this step requires that this comparison as a
-//            whole is transitive).
+//         3. Compare it to the first network of each Similar group with the same hash of the neuron
+//            structure and add it to the group if the two are either similar or duplicates (this
+//            step requires that this comparison as a whole is transitive).
 //         4. If not added to an existing Similar group, create a new one and add the network to it.
-//     b. Sort each group according to the ranking criteria (prefer better fitness, or smaller size
-//        if two fitness values are similar).
+//     b. Sort each group according to the ranking criteria (prefer better fitness, or lower
+//        complexity if two fitness values are similar).
 // 2. Until the target population size is met or all networks have been selected:
 //     a. Find the highest-ranked network across all groups such that from no group containing the
 //        network has the maximum number of networks for that group's type been selected so far:
@@ -207,20 +161,58 @@ impl NetworkGroup {
 //         2. Otherwise, increment `max_copies`.
 //
 // This algorithm should be O(N^2 * L) + O(N^3) worst case, where N is the length of `individuals`
-// and L is the average size of the networks in the population. On average however, much of the work
-// will be skipped or stopped early, and the only O(N^3) operations are `usize` comparisons, so the
-// performance should still be good in general.
-pub fn select<T: FitnessFunction + Clone + Send>(
-    individuals: Vec<Individual<T>>,
+// and L is the average size of the networks in the population. The worst case only occurs if most
+// networks share a structural hash without being duplicates or similar, however. Otherwise, grouping
+// takes O(N * L), and the only O(N^3) operations are `usize` comparisons, so the performance should
+// still be good in general.
+pub fn select(
+    candidates: &[Candidate],
     target_population_size: usize,
-    similar_fitness_threshold: f64,
-    force_meet_population_size: bool,
-) -> Generation<T> {
-    let mut individuals = individuals
-        .into_iter()
-        .enumerate()
-        .map(|(i, x)| (NetworkId(i), x))
-        .collect::<HashMap<_, _>>();
+    similarity: FitnessSimilarity,
+    options: &Selection,
+) -> Vec<usize> {
+    select_groups(
+        candidates,
+        target_population_size,
+        similarity,
+        options,
+        false,
+    )
+    .0
+}
+
+/// Like [`select`], but also returns the decision about each candidate: the groups it belonged to,
+/// and for candidates that were not selected, whether a constraint excluded them. A candidate is
+/// excluded by a constraint if it was passed over in favor of a worse-ranked candidate because
+/// selecting it would have exceeded `max_copies` or `max_similar` at that point, or if selection
+/// ended early because of the constraints. Otherwise, it was outranked.
+pub fn select_with_decisions(
+    candidates: &[Candidate],
+    target_population_size: usize,
+    similarity: FitnessSimilarity,
+    options: &Selection,
+) -> (Vec<usize>, Vec<Decision>) {
+    select_groups(
+        candidates,
+        target_population_size,
+        similarity,
+        options,
+        true,
+    )
+}
+
+/// Implements [`select`] and [`select_with_decisions`]. The decisions are only tracked if `report`
+/// is set, as finding the constraints that excluded candidates takes quadratic time, and they are
+/// empty otherwise.
+fn select_groups(
+    candidates: &[Candidate],
+    target_population_size: usize,
+    similarity: FitnessSimilarity,
+    options: &Selection,
+    report: bool,
+) -> (Vec<usize>, Vec<Decision>) {
+    let similarity = similarity.resolve(candidates.iter().map(|candidate| candidate.fitness));
+    let force_meet_population_size = options.force_meet_population_size;
 
     // A list of groups of networks that are structurally identical to each other
     // In groups with only one individual, that individual has no duplicates
@@ -232,100 +224,115 @@ pub fn select<T: FitnessFunction + Clone + Send>(
     // All networks are in this list, even if they are alone in their group
     let mut similar: Vec<NetworkGroup> = Vec::new();
 
-    // Place all networks into the correct groups in each category
-    for (id, individual) in &individuals {
-        let id = *id;
-
-        // Check whether the network falls into any existing Duplicate group
-        let mut added_to_existing_duplicate_group = false;
-        for g in &mut duplicate {
-            // Only the first network in each group needs to be checked because identicality is
-            // transitive
-            let existing_id = g.network_ids[0];
-            let existing = &individuals[&existing_id];
-
-            if let Similarity::Duplicate = check_similarity(&individual.network, &existing.network) {
-                g.push(id);
-                added_to_existing_duplicate_group = true;
-                // Networks cannot be duplicates in more than one group because each group is
-                // unique
-                break;
-            }
-        }
-
-        // Otherwise, place it in a new Duplicate group
-        if !added_to_existing_duplicate_group {
-            let mut new = NetworkGroup::new(GroupKind::Duplicate);
-            new.push(id);
-            duplicate.push(new);
-        }
+    // The structure of each network as compared by the similarity check
+    let structures = candidates
+        .iter()
+        .map(|candidate| Structure::new(candidate.network, options.similarity_check))
+        .collect::<Vec<_>>();
 
-        // Check whether the network falls into any existing Similar group
-        let mut added_to_existing_similar_group = false;
-        for g in &mut similar {
-            // Only the first network in each group needs to be checked because similarity or
-            // identicality is transitive
-            let existing_id = g.network_ids[0];
-            let existing = &individuals[&existing_id];
-
-            let category = check_similarity(&individual.network, &existing.network);
-
-            if let Similarity::Similar | Similarity::Duplicate = category {
-                g.push(id);
-                added_to_existing_similar_group = true;
-                // Networks cannot be similar in more than one group because each group is
-                // unique
-                break;
-            }
-        }
+    // Groups are bucketed by structural hashes, so that each network only needs to be compared
+    // to the groups in its bucket
+    let mut duplicate_buckets: HashMap<u64, Vec<usize>> = HashMap::new();
+    let mut similar_buckets: HashMap<u64, Vec<usize>> = HashMap::new();
+    // The index of the group containing each network in each category
+    let mut duplicate_of = Vec::with_capacity(candidates.len());
+    let mut similar_of = Vec::with_capacity(candidates.len());
 
-        // Otherwise, place it in a new Similar group
-        if !added_to_existing_similar_group {
-            let mut new = NetworkGroup::new(GroupKind::Similar);
-            new.push(id);
-            similar.push(new);
-        }
+    // Place all networks into the correct groups in each category
+    for (i, structure) in structures.iter().enumerate() {
+        let id = NetworkId(i);
+        let (similar_hash, duplicate_hash) = structure.hashes();
+
+        // Check whether the network falls into any existing Duplicate group, and otherwise place
+        // it in a new one
+        duplicate_of.push(add_to_group(
+            &mut duplicate,
+            GroupKind::Duplicate,
+            duplicate_buckets.entry(duplicate_hash).or_default(),
+            id,
+            &structures,
+            |similarity| matches!(similarity, Similarity::Duplicate),
+        ));
+
+        // Check whether the network falls into any existing Similar group, and otherwise place it
+        // in a new one
+        similar_of.push(add_to_group(
+            &mut similar,
+            GroupKind::Similar,
+            similar_buckets.entry(similar_hash).or_default(),
+            id,
+            &structures,
+            |similarity| matches!(similarity, Similarity::Similar | Similarity::Duplicate),
+        ));
     }
 
     // The total number of IDs across all groups should be double the number of individuals because
     // each individual is in exactly one group in each category (one in `similar`, one in
     // `duplicate`)
     let num_ids = similar.iter().chain(&duplicate).flat_map(|g| g.network_ids.iter()).count();
-    assert_eq!(individuals.len() * 2, num_ids);
+    assert_eq!(candidates.len() * 2, num_ids);
 
     // Sort groups internally by the criteria given by `compare`
     for g in &mut similar {
-        g.sort(&individuals, similar_fitness_threshold);
+        g.sort(candidates, &similarity);
     }
 
     for g in &mut duplicate {
-        g.sort(&individuals, similar_fitness_threshold);
+        g.sort(candidates, &similarity);
     }
 
     // Select networks for the next generation
     let mut selected_ids = Vec::with_capacity(target_population_size);
-    let mut max_similar = MAX_SIMILAR;
-    let mut max_copies = MAX_COPIES;
+    // At least one network must always be selectable
+    let mut max_similar = options.max_similar.max(1);
+    let mut max_copies = options.max_copies.max(1);
+    // The constraint that last kept each network from being selected, if reported
+    let mut blocked_by: Vec<Option<Exclusion>> = Vec::new();
+    if report {
+        blocked_by.resize(candidates.len(), None);
+    }
 
     // Loop until either the target population size is reached or the entire population has been
     // selected
-    while selected_ids.len() < target_population_size && selected_ids.len() < individuals.len() {
+    while selected_ids.len() < target_population_size && selected_ids.len() < candidates.len() {
         // The best individual that is valid to remove according to the similarity/uniqueness
         // constraints
         let best_constrained = similar
             .iter()
             .chain(&duplicate)
-            .filter_map(|g| g.best_constrained(&similar, max_similar, &duplicate, max_copies))
-            .min_by(|a, b| compare(&individuals[a], &individuals[b], similar_fitness_threshold));
+            .filter_map(|g| {
+                g.best_constrained(
+                    &similar,
+                    &similar_of,
+                    max_similar,
+                    &duplicate,
+                    &duplicate_of,
+                    max_copies,
+                )
+            })
+            .min_by(|a, b| compare(&candidates[a.0], &candidates[b.0], &similarity));
 
         if let Some(id) = best_constrained {
             // If a best individual was found, select it
 
-            // Remove selected IDs from all groups
-            for g in similar.iter_mut().chain(&mut duplicate) {
-                g.find_and_remove(id);
+            // Remaining networks that are ranked higher were passed over because of a constraint
+            if report {
+                for other in similar.iter().flat_map(|g| &g.network_ids) {
+                    if compare(&candidates[other.0], &candidates[id.0], &similarity).is_lt() {
+                        blocked_by[other.0] = constraint(
+                            &similar[similar_of[other.0]],
+                            max_similar,
+                            &duplicate[duplicate_of[other.0]],
+                            max_copies,
+                        );
+                    }
+                }
             }
 
+            // Remove selected IDs from the groups containing them
+            similar[similar_of[id.0]].find_and_remove(id);
+            duplicate[duplicate_of[id.0]].find_and_remove(id);
+
             selected_ids.push(id);
         } else if force_meet_population_size {
             // Otherwise, relax constraints to meet the population size if the option is set
@@ -349,6 +356,16 @@ pub fn select<T: FitnessFunction + Clone + Send>(
             }
         } else {
             // Otherwise, if the option is not set, end the selection process early
+            if report {
+                for other in similar.iter().flat_map(|g| &g.network_ids) {
+                    blocked_by[other.0] = constraint(
+                        &similar[similar_of[other.0]],
+                        max_similar,
+                        &duplicate[duplicate_of[other.0]],
+                        max_copies,
+                    );
+                }
+            }
             break;
         }
     }
@@ -358,21 +375,131 @@ pub fn select<T: FitnessFunction + Clone + Send>(
     // to meet it, in which case all individuals are selected
     assert!(
         !force_meet_population_size
-            || selected_ids.len() == individuals.len()
+            || selected_ids.len() == candidates.len()
             || selected_ids.len() == target_population_size
     );
 
+    let mut decisions = Vec::new();
+    if report {
+        decisions = (0..candidates.len())
+            .map(|i| Decision {
+                duplicate_group: Some(duplicate_of[i]),
+                similar_group: Some(similar_of[i]),
+                species: None,
+                exclusion: Some(blocked_by[i].unwrap_or(Exclusion::Outranked)),
+            })
+            .collect::<Vec<_>>();
+        for id in &selected_ids {
+            decisions[id.0].exclusion = None;
+        }
+    }
+
     // Return the selected networks
-    let selected = selected_ids
-        .into_iter()
-        .map(|id| individuals.remove(&id).unwrap())
-        .collect();
+    let selected = selected_ids.into_iter().map(|id| id.0).collect();
+    (selected, decisions)
+}
 
-    Generation {
-        individuals: selected,
+/// Returns the constraint that keeps a network in the given groups from being selected, if any.
+fn constraint(
+    similar: &NetworkGroup,
+    max_similar: usize,
+    duplicate: &NetworkGroup,
+    max_copies: usize,
+) -> Option<Exclusion> {
+    if duplicate.num_taken() >= max_copies {
+        Some(Exclusion::MaxCopies)
+    } else if similar.num_taken() >= max_similar {
+        Some(Exclusion::MaxSimilar)
+    } else {
+        None
     }
 }
 
+/// Adds the network to the first group in `bucket` (which contains indices into `groups`) whose
+/// first network has a relationship to it that `matches` accepts. If there is no such group, the
+/// network is placed in a new group of the given kind, which is added to `bucket`. Returns the index of the group.
+fn add_to_group(
+    groups: &mut Vec<NetworkGroup>,
+    kind: GroupKind,
+    bucket: &mut Vec<usize>,
+    id: NetworkId,
+    structures: &[Structure],
+    matches: impl Fn(Similarity) -> bool,
+) -> usize {
+    // Only the first network in each group needs to be checked because similarity and
+    // identicality are transitive
+    let existing = bucket.iter().copied().find(|g| {
+        let existing_id = groups[*g].network_ids[0];
+        matches(check_similarity(
+            &structures[id.0],
+            &structures[existing_id.0],
+        ))
+    });
+
+    let g = existing.unwrap_or_else(|| {
+        groups.push(NetworkGroup::new(kind));
+        bucket.push(groups.len() - 1);
+        groups.len() - 1
+    });
+
+    groups[g].push(id);
+    g
+}
+
+/// Returns, for each offspring, whether all parents and the preceding offspring already contain
+/// `max_copies` duplicates of it according to `check`.
+pub(crate) fn excess_offspring(
+    parents: &[&Network],
+    offspring: &[&Network],
+    max_copies: usize,
+    check: SimilarityCheck,
+) -> Vec<bool> {
+    // Count the parents first, so that offspring also count duplicates among later parents
+    let networks = parents.iter().chain(offspring).copied().collect::<Vec<_>>();
+    preceding_duplicates(&networks, check)
+        .into_iter()
+        .skip(parents.len())
+        .map(|copies| copies >= max_copies)
+        .collect()
+}
+
+/// Returns, for each network, the number of preceding networks that are duplicates of it according
+/// to `check`.
+fn preceding_duplicates(networks: &[&Network], check: SimilarityCheck) -> Vec<usize> {
+    let structures = networks
+        .iter()
+        .map(|network| Structure::new(network, check))
+        .collect::<Vec<_>>();
+    // For each structural hash, the first network of each distinct structure and the number of
+    // networks with that structure so far
+    let mut buckets: HashMap<u64, Vec<(usize, usize)>> = HashMap::new();
+
+    structures
+        .iter()
+        .enumerate()
+        .map(|(i, structure)| {
+            let bucket = buckets.entry(structure.hashes().1).or_default();
+            let existing = bucket.iter_mut().find(|(first, _)| {
+                matches!(
+                    check_similarity(structure, &structures[*first]),
+                    Similarity::Duplicate
+                )
+            });
+
+            match existing {
+                Some((_, count)) => {
+                    *count += 1;
+                    *count - 1
+                }
+                None => {
+                    bucket.push((i, 1));
+                    0
+                }
+            }
+        })
+        .collect()
+}
+
 /// The similarity relationship between two networks.
 #[derive(Debug)]
 enum Similarity {
@@ -385,11 +512,54 @@ enum Similarity {
     Unique,
 }
 
-/// Compares the two networks and returns their relationship to each other.
-fn check_similarity(a: &Network, b: &Network) -> Similarity {
-    let is_similar = is_similar(a, b);
-    // Similarity is a requirement for `is_duplicate`
-    let is_duplicate = is_similar && is_duplicate(a, b);
+/// The structure of a network as compared by a `SimilarityCheck`.
+enum Structure<'a> {
+    /// The network itself, compared by its genome
+    Genome(&'a Network),
+    /// The canonical forms of the network
+    Canonical(CanonicalForm),
+}
+
+impl<'a> Structure<'a> {
+    fn new(network: &'a Network, check: SimilarityCheck) -> Self {
+        match check {
+            SimilarityCheck::Genome => Structure::Genome(network),
+            SimilarityCheck::Canonical => Structure::Canonical(canonical_form(network, None)),
+            SimilarityCheck::CanonicalIgnoringWeightless { epsilon } => {
+                Structure::Canonical(canonical_form(network, Some(epsilon)))
+            }
+        }
+    }
+
+    /// Returns a hash of the neuron structure and a hash of the complete structure. Similar and
+    /// duplicate structures have equal hashes respectively, but equal hashes do not imply that
+    /// structures are similar or duplicates.
+    fn hashes(&self) -> (u64, u64) {
+        match self {
+            Structure::Genome(network) => {
+                let similar = hash(&neuron_structure(network));
+                // Duplicates also have the same length
+                (similar, hash(&(similar, network.len())))
+            }
+            Structure::Canonical(form) => (hash(&form.neurons), hash(&form.structure)),
+        }
+    }
+}
+
+/// Compares the two network structures and returns their relationship to each other.
+fn check_similarity(a: &Structure, b: &Structure) -> Similarity {
+    let (is_similar, is_duplicate) = match (a, b) {
+        (Structure::Genome(a), Structure::Genome(b)) => {
+            let is_similar = is_similar(a, b);
+            // Similarity is a requirement for `is_duplicate`
+            (is_similar, is_similar && is_duplicate(a, b))
+        }
+        (Structure::Canonical(a), Structure::Canonical(b)) => {
+            let is_similar = a.neurons == b.neurons;
+            (is_similar, is_similar && a.structure == b.structure)
+        }
+        _ => unreachable!("all structures are created by the same check"),
+    };
 
     if is_duplicate {
         Similarity::Duplicate
@@ -405,22 +575,31 @@ fn check_similarity(a: &Network, b: &Network) -> Similarity {
 /// general as feasible with regards to checking graph isomorphism instead of genome identicality,
 /// but compromises significantly for practicality.
 fn is_similar(a: &Network, b: &Network) -> bool {
-    let get_neuron_structure = |network: &Network| {
-        let mut implicit_connections = HashSet::new();
-
-        for (gene, parent) in network.genome().iter().zip(network.parents()) {
-            if let Some(neuron) = gene.as_neuron() {
-                if let Some(parent_id) = parent {
-                    // Insert a connection between each neuron and its parent
-                    implicit_connections.insert((*parent_id, neuron.id()));
-                }
+    neuron_structure(a) == neuron_structure(b)
+}
+
+/// Returns the connections between all neurons and their parents, sorted and without repetitions.
+fn neuron_structure(network: &Network) -> Vec<(usize, usize)> {
+    let mut implicit_connections = Vec::new();
+
+    for (gene, parent) in network.genome().iter().zip(network.parents()) {
+        if let Some(neuron) = gene.as_neuron() {
+            if let Some(parent_id) = parent {
+                // Add a connection between each neuron and its parent
+                implicit_connections.push((parent_id.as_usize(), neuron.id().as_usize()));
             }
         }
+    }
 
-        implicit_connections
-    };
+    implicit_connections.sort_unstable();
+    implicit_connections.dedup();
+    implicit_connections
+}
 
-    get_neuron_structure(a) == get_neuron_structure(b)
+fn hash<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
 }
 
 /// Returns whether the two networks are structurally identical. Requires that
@@ -470,32 +649,94 @@ fn is_duplicate(a: &Network, b: &Network) -> bool {
     true
 }
 
-/// Compares the two individuals for sorting. The individual with better fitness is ranked higher,
-/// unless the two individuals have fitness values within `similar_fitness_threshold` of each other,
-/// in which case the smaller individual is ranked higher instead.
-fn compare<T>(a: &Individual<T>, b: &Individual<T>, similar_fitness_threshold: f64) -> Ordering
-where
-    T: FitnessFunction + Clone,
-{
-    if similar_fitness(a, b, similar_fitness_threshold) {
-        a.network.len().cmp(&b.network.len())
+/// Compares the two candidates for sorting. The candidate with better fitness is ranked higher,
+/// unless the two candidates have similar fitness values according to `similarity`, in which case
+/// the less complex candidate is ranked higher instead.
+fn compare(a: &Candidate, b: &Candidate, similarity: &FitnessSimilarity) -> Ordering {
+    if similarity.is_similar(a.fitness, b.fitness) {
+        a.complexity.total_cmp(&b.complexity)
     } else {
-        a.fitness.partial_cmp(&b.fitness).unwrap()
+        a.fitness
+            .partial_cmp(&b.fitness)
+            .unwrap()
+            .then(a.complexity.total_cmp(&b.complexity))
     }
 }
 
-/// Returns whether the absolute difference between the two individuals' fitness values is below
-/// `threshold`.
-fn similar_fitness<T>(a: &Individual<T>, b: &Individual<T>, threshold: f64) -> bool
-where
-    T: FitnessFunction + Clone,
-{
-    if a.fitness.is_none() || b.fitness.is_none() {
-        return false;
+#[cfg(test)]
+mod tests {
+    use cge::gene::{Input, Neuron};
+    use cge::Activation;
+
+    use super::*;
+
+    fn duplicates(network: &Network, count: usize) -> Vec<Candidate<'_>> {
+        (0..count)
+            .map(|i| Candidate {
+                id: i,
+                network,
+                fitness: i as f64,
+                raw_fitness: i as f64,
+                complexity: 1.0,
+                age: 0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_selection_options() {
+        let genome = vec![
+            Neuron::new(NeuronId::new(0), 1, 1.0).into(),
+            Input::new(InputId::new(0), 1.0).into(),
+        ];
+        let network = Network::new(genome, Activation::Sigmoid).unwrap();
+        let candidates = duplicates(&network, 5);
+
+        let similarity = FitnessSimilarity::Absolute(0.15);
+
+        let default = Selection::builder().build();
+        assert_eq!(select(&candidates, 4, similarity, &default), vec![0]);
+
+        let copies = Selection::builder().max_copies(3).max_similar(3).build();
+        assert_eq!(select(&candidates, 4, similarity, &copies).len(), 3);
+
+        let forced = Selection::builder().force_meet_population_size().build();
+        assert_eq!(select(&candidates, 4, similarity, &forced).len(), 4);
+
+        let canonical = Selection::builder()
+            .similarity_check(SimilarityCheck::Canonical)
+            .build();
+        assert_eq!(select(&candidates, 4, similarity, &canonical), vec![0]);
+
+        let (_, decisions) = select_with_decisions(&candidates, 4, similarity, &copies);
+        assert!(decisions.iter().all(|d| d.duplicate_group == Some(0)));
+        assert_eq!(decisions[2].exclusion, None);
+        assert_eq!(decisions[3].exclusion, Some(Exclusion::MaxCopies));
+
+        let (_, decisions) = select_with_decisions(&candidates, 4, similarity, &forced);
+        assert_eq!(decisions[4].exclusion, Some(Exclusion::Outranked));
     }
 
-    let fitness_a = a.fitness.unwrap();
-    let fitness_b = b.fitness.unwrap();
+    #[test]
+    fn test_excess_offspring() {
+        let network = |input| {
+            let genome = vec![
+                Neuron::new(NeuronId::new(0), 1, 1.0).into(),
+                Input::new(InputId::new(input), 1.0).into(),
+            ];
+            Network::new(genome, Activation::Sigmoid).unwrap()
+        };
+        let (a, b, c) = (network(0), network(1), network(2));
+
+        let networks = [&a; 3];
+        let copies = preceding_duplicates(&networks, SimilarityCheck::Genome);
+        assert_eq!(copies, vec![0, 1, 2]);
 
-    (fitness_a - fitness_b).abs() < threshold
+        // Duplicates of any parent count, not only of the preceding ones
+        let excess = excess_offspring(&[&a, &b], &[&b, &c, &c, &a], 1, SimilarityCheck::Genome);
+        assert_eq!(excess, vec![true, false, true, true]);
+
+        let excess = excess_offspring(&[&a, &b], &[&b, &c, &c, &b], 2, SimilarityCheck::Genome);
+        assert_eq!(excess, vec![false, false, false, true]);
+    }
 }
@@ -0,0 +1,584 @@
+//! Strategies for selecting the individuals that survive to the next generation.
+//!
+//! After the offspring of a generation have been evaluated, a [`SelectionStrategy`] chooses which
+//! of all evaluated individuals (parents and offspring) make up the next generation. It is set with
+//! the `strategy` option of [`Selection`][crate::options::Selection]. The default is [`Grouping`],
+//! the selection scheme of the original paper. Custom strategies can be used by implementing the
+//! trait.
+
+use rand::seq::SliceRandom;
+use rand::{Rng, RngCore};
+
+use std::cmp::Ordering;
+
+use crate::cge_utils::Network;
+use crate::generation::Generation;
+use crate::options::{Exploration, FitnessSimilarity, ParsimonyPressure};
+use crate::select;
+use crate::speciation::Speciator;
+use crate::utils::Individual;
+use crate::FitnessFunction;
+
+/// An evaluated individual that may be selected.
+#[derive(Clone, Copy, Debug)]
+pub struct Candidate<'a> {
+    /// The unique ID of the individual (see [`Genealogy`][crate::genealogy::Genealogy]).
+    pub id: usize,
+    /// The network of the individual.
+    pub network: &'a Network,
+    /// The fitness of the individual, including the parsimony penalty if
+    /// `Exploration::parsimony` is set. Lower is better.
+    pub fitness: f64,
+    /// The fitness of the individual without any parsimony penalty.
+    pub raw_fitness: f64,
+    /// The complexity of the network according to `Exploration::complexity`. Lower is better.
+    pub complexity: f64,
+    /// The number of selections the individual has survived so far. Offspring that were just
+    /// produced have an age of zero.
+    pub age: usize,
+}
+
+/// Why a candidate did not survive selection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exclusion {
+    /// Enough better-ranked candidates were selected to fill the population. [`Grouping`] ranks
+    /// candidates with similar fitness by complexity, so they may have worse fitness.
+    Outranked,
+    /// The candidate was passed over while `max_copies` networks of its duplicate group were
+    /// already selected.
+    MaxCopies,
+    /// The candidate was passed over while `max_similar` networks of its similar group were
+    /// already selected.
+    MaxSimilar,
+    /// A strategy other than [`Grouping`] did not select the candidate.
+    Strategy,
+    /// The species of the candidate was removed because it stagnated.
+    Stagnation,
+    /// The species of the candidate was allocated no population slots.
+    NoSlots,
+}
+
+/// The decision of selection about a single candidate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Decision {
+    /// For [`Grouping`], the index of the group of networks that are duplicates of the candidate.
+    /// Group indices are only comparable between candidates selected together, i.e. within the
+    /// same species.
+    pub duplicate_group: Option<usize>,
+    /// For [`Grouping`], the index of the group of networks that are similar to the candidate.
+    pub similar_group: Option<usize>,
+    /// The ID of the species of the candidate if speciation is enabled (see
+    /// [`Species`][crate::speciation::Species]).
+    pub species: Option<usize>,
+    /// Why the candidate was excluded, or `None` if it was selected.
+    pub exclusion: Option<Exclusion>,
+}
+
+/// The outcome of selection for a single individual, as recorded in
+/// [`GenerationStatistics::selection`][crate::statistics::GenerationStatistics::selection].
+#[derive(Clone, Copy, Debug)]
+pub struct SelectionRecord {
+    /// The unique ID of the individual (see [`Genealogy`][crate::genealogy::Genealogy]).
+    pub id: usize,
+    /// The fitness of the individual, including the parsimony penalty.
+    pub fitness: f64,
+    /// The fitness of the individual without any parsimony penalty.
+    pub raw_fitness: f64,
+    /// The complexity of the network according to `Exploration::complexity`.
+    pub complexity: f64,
+    /// The rank of the individual among all candidates by fitness, and by complexity if their
+    /// fitness is equal, starting at zero for the best.
+    pub rank: usize,
+    /// The groups and species of the individual and whether it was selected.
+    pub decision: Decision,
+}
+
+impl SelectionRecord {
+    /// Returns whether the individual survived selection.
+    pub fn selected(&self) -> bool {
+        self.decision.exclusion.is_none()
+    }
+}
+
+/// A strategy for selecting the individuals that survive to the next generation.
+pub trait SelectionStrategy: Send + Sync {
+    /// Returns the indices into `candidates` of the individuals that survive, which should be no
+    /// more than `population`. Invalid and repeated indices are ignored, and at least one index
+    /// must be valid.
+    ///
+    /// `population` is `population`, unless speciation is enabled, in which case the
+    /// strategy selects within each species and `population` is the number of slots of the species.
+    fn select(
+        &self,
+        candidates: &[Candidate],
+        population: usize,
+        exploration: &Exploration,
+        rng: &mut dyn RngCore,
+    ) -> Vec<usize>;
+
+    /// Like [`select`][Self::select], but also returns the decision about each candidate in the
+    /// order of `candidates`. The default implementation only reports which candidates were
+    /// selected, and gives [`Exclusion::Strategy`] as the reason for all others.
+    fn select_with_decisions(
+        &self,
+        candidates: &[Candidate],
+        population: usize,
+        exploration: &Exploration,
+        rng: &mut dyn RngCore,
+    ) -> (Vec<usize>, Vec<Decision>) {
+        let selected = self.select(candidates, population, exploration, rng);
+        let mut decisions = vec![
+            Decision {
+                exclusion: Some(Exclusion::Strategy),
+                ..Decision::default()
+            };
+            candidates.len()
+        ];
+        for i in &selected {
+            if let Some(decision) = decisions.get_mut(*i) {
+                decision.exclusion = None;
+            }
+        }
+
+        (selected, decisions)
+    }
+}
+
+/// The selection scheme of the original paper. The best individuals survive, but the number of
+/// duplicate and structurally similar individuals is limited according to the
+/// [`Selection`][crate::options::Selection] options, and individuals with similar fitness are
+/// ranked by complexity (see `Exploration::similarity` and `Exploration::complexity`).
+#[derive(Clone, Copy, Debug)]
+pub struct Grouping;
+
+impl SelectionStrategy for Grouping {
+    fn select(
+        &self,
+        candidates: &[Candidate],
+        population: usize,
+        exploration: &Exploration,
+        _: &mut dyn RngCore,
+    ) -> Vec<usize> {
+        select::select(
+            candidates,
+            population,
+            grouping_similarity(exploration),
+            &exploration.selection,
+        )
+    }
+
+    fn select_with_decisions(
+        &self,
+        candidates: &[Candidate],
+        population: usize,
+        exploration: &Exploration,
+        _: &mut dyn RngCore,
+    ) -> (Vec<usize>, Vec<Decision>) {
+        select::select_with_decisions(
+            candidates,
+            population,
+            grouping_similarity(exploration),
+            &exploration.selection,
+        )
+    }
+}
+
+/// Returns the fitness similarity used by [`Grouping`]. Lexicographic parsimony never trades
+/// fitness for a smaller network.
+fn grouping_similarity(exploration: &Exploration) -> FitnessSimilarity {
+    match exploration.parsimony {
+        Some(parsimony) if matches!(parsimony.pressure, ParsimonyPressure::Lexicographic) => {
+            FitnessSimilarity::Absolute(0.0)
+        }
+        _ => exploration.similarity,
+    }
+}
+
+/// Tournament selection. Until the population is full, `size` random remaining candidates are
+/// drawn, and the best of them survives. Larger tournaments select more greedily.
+#[derive(Clone, Copy, Debug)]
+pub struct Tournament {
+    pub size: usize,
+}
+
+impl SelectionStrategy for Tournament {
+    fn select(
+        &self,
+        candidates: &[Candidate],
+        population: usize,
+        _: &Exploration,
+        rng: &mut dyn RngCore,
+    ) -> Vec<usize> {
+        let mut remaining = (0..candidates.len()).collect::<Vec<_>>();
+        let mut selected = Vec::with_capacity(population);
+
+        while selected.len() < population && !remaining.is_empty() {
+            let size = self.size.clamp(1, remaining.len());
+            let winner = remaining
+                .choose_multiple(rng, size)
+                .copied()
+                .min_by(|a, b| rank(&candidates[*a], &candidates[*b]))
+                .unwrap();
+
+            remaining.retain(|i| *i != winner);
+            selected.push(winner);
+        }
+
+        selected
+    }
+}
+
+/// Truncation selection with elitism. The best `elites` candidates always survive, and the rest of
+/// the population is drawn at random from the best `fraction` of the remaining candidates. With a
+/// fraction small enough to only contain as many candidates as there is space for, this simply
+/// keeps the best candidates.
+#[derive(Clone, Copy, Debug)]
+pub struct Truncation {
+    pub elites: usize,
+    pub fraction: f64,
+}
+
+impl SelectionStrategy for Truncation {
+    fn select(
+        &self,
+        candidates: &[Candidate],
+        population: usize,
+        _: &Exploration,
+        rng: &mut dyn RngCore,
+    ) -> Vec<usize> {
+        let ranked = ranked(candidates, 0..candidates.len());
+        let elites = self.elites.min(population).min(ranked.len());
+        let (elites, rest) = ranked.split_at(elites);
+
+        let free = population - elites.len();
+        let truncated = (rest.len() as f64 * self.fraction.clamp(0.0, 1.0)).ceil() as usize;
+        let mut pool = rest[..truncated.max(free).min(rest.len())].to_vec();
+        pool.shuffle(rng);
+        pool.truncate(free);
+
+        elites.iter().copied().chain(pool).collect()
+    }
+}
+
+/// Age-layered selection, which protects structurally new individuals so that they get several
+/// rounds of CMA-ES optimization before competing with established individuals.
+///
+/// Candidates younger than `protection` selections compete only with each other for the `reserved`
+/// fraction of the population. The rest of the population is filled with the best remaining
+/// candidates regardless of age.
+#[derive(Clone, Copy, Debug)]
+pub struct AgeLayered {
+    pub protection: usize,
+    pub reserved: f64,
+}
+
+impl SelectionStrategy for AgeLayered {
+    fn select(
+        &self,
+        candidates: &[Candidate],
+        population: usize,
+        _: &Exploration,
+        _: &mut dyn RngCore,
+    ) -> Vec<usize> {
+        let reserved = (population as f64 * self.reserved.clamp(0.0, 1.0)).round() as usize;
+        let young = (0..candidates.len()).filter(|i| candidates[*i].age < self.protection);
+
+        let mut selected = ranked(candidates, young);
+        selected.truncate(reserved);
+
+        let rest = (0..candidates.len()).filter(|i| !selected.contains(i));
+        let free = population.saturating_sub(selected.len());
+        selected.extend(ranked(candidates, rest).into_iter().take(free));
+
+        selected
+    }
+}
+
+/// Returns the given candidate indices sorted from best to worst fitness.
+fn ranked(candidates: &[Candidate], indices: impl Iterator<Item = usize>) -> Vec<usize> {
+    let mut ranked = indices.collect::<Vec<_>>();
+    ranked.sort_by(|a, b| rank(&candidates[*a], &candidates[*b]));
+    ranked
+}
+
+/// Compares two candidates by fitness, and by complexity if their fitness is equal.
+fn rank(a: &Candidate, b: &Candidate) -> Ordering {
+    a.fitness
+        .total_cmp(&b.fitness)
+        .then(a.complexity.total_cmp(&b.complexity))
+}
+
+/// Applies the selection strategy to the evaluated individuals of generation `g` (starting at zero)
+/// and returns the next generation, along with the parsimony coefficient that was used to penalize
+/// the fitness of the candidates and, if `Selection::report` is set, a record of the decision
+/// about each candidate. If speciation is enabled, the candidates are divided into the species
+/// tracked by `speciator` first.
+pub(crate) fn next_generation<T: FitnessFunction + Clone, R: Rng>(
+    individuals: Vec<Individual<T>>,
+    g: usize,
+    exploration: &Exploration,
+    speciator: &mut Speciator,
+    rng: &mut R,
+) -> (Generation<T>, f64, Vec<SelectionRecord>) {
+    let evaluated = individuals
+        .iter()
+        .map(|individual| {
+            let fitness = individual.fitness.unwrap();
+            (fitness, exploration.complexity.measure(&individual.network))
+        })
+        .collect::<Vec<_>>();
+    let coefficient = exploration
+        .parsimony
+        .map_or(0.0, |parsimony| parsimony.coefficient(&evaluated));
+
+    let candidates = individuals
+        .iter()
+        .zip(&evaluated)
+        .map(|(individual, &(raw_fitness, complexity))| Candidate {
+            id: individual.id,
+            network: &individual.network,
+            fitness: raw_fitness + coefficient * complexity,
+            raw_fitness,
+            complexity,
+            // The initial population is evaluated in the same generation as the first offspring
+            age: g + 1 - individual.birth_generation.max(1),
+        })
+        .collect::<Vec<_>>();
+
+    let strategy = &exploration.selection.strategy;
+    let population = exploration.population;
+    let (indices, decisions) = match (&exploration.speciation, exploration.selection.report) {
+        (Some(speciation), _) => speciator.select(&candidates, g, exploration, speciation, rng),
+        (None, true) => strategy.select_with_decisions(&candidates, population, exploration, rng),
+        (None, false) => (
+            strategy.select(&candidates, population, exploration, rng),
+            Vec::new(),
+        ),
+    };
+
+    let mut records = Vec::new();
+    if exploration.selection.report {
+        let mut ranks = vec![0; candidates.len()];
+        for (rank, i) in ranked(&candidates, 0..candidates.len())
+            .into_iter()
+            .enumerate()
+        {
+            ranks[i] = rank;
+        }
+
+        records = candidates
+            .iter()
+            .zip(ranks)
+            .zip(decisions)
+            .map(|((candidate, rank), decision)| SelectionRecord {
+                id: candidate.id,
+                fitness: candidate.fitness,
+                raw_fitness: candidate.raw_fitness,
+                complexity: candidate.complexity,
+                rank,
+                decision,
+            })
+            .collect();
+    }
+
+    let mut individuals = individuals.into_iter().map(Some).collect::<Vec<_>>();
+    let selected = indices
+        .into_iter()
+        .filter_map(|i| individuals.get_mut(i).and_then(Option::take))
+        .collect::<Vec<_>>();
+    assert!(
+        !selected.is_empty(),
+        "the selection strategy selected no individuals"
+    );
+
+    let generation = Generation {
+        individuals: selected,
+    };
+    (generation, coefficient, records)
+}
+
+#[cfg(test)]
+mod tests {
+    use cge::gene::{Bias, Gene, Input, InputId, Neuron, NeuronId};
+    use cge::Activation;
+
+    use super::*;
+    use crate::options::{Parsimony, Selection, Speciation};
+    use crate::utils::Zero;
+
+    /// Returns evaluated individuals with the given genomes and fitness values.
+    fn individuals(evaluated: Vec<(Vec<Gene<f64>>, f64)>) -> Vec<Individual<Zero>> {
+        evaluated
+            .into_iter()
+            .enumerate()
+            .map(|(id, (genome, fitness))| {
+                let mut individual = Individual::for_test(3, genome);
+                individual.id = id;
+                individual.fitness = Some(fitness);
+                individual
+            })
+            .collect()
+    }
+
+    fn genome(input: usize) -> Vec<Gene<f64>> {
+        vec![
+            Neuron::new(NeuronId::new(0), 1, 1.0).into(),
+            Input::new(InputId::new(input), 1.0).into(),
+        ]
+    }
+
+    fn network(input: usize) -> Network {
+        Network::new(genome(input), Activation::Sigmoid).unwrap()
+    }
+
+    #[test]
+    fn test_builtin_strategies() {
+        let genome = vec![
+            Neuron::new(NeuronId::new(0), 1, 1.0).into(),
+            Input::new(InputId::new(0), 1.0).into(),
+        ];
+        let network = Network::new(genome, Activation::Sigmoid).unwrap();
+        // The worst candidates are the youngest
+        let candidates = (0..6)
+            .map(|i| Candidate {
+                id: i,
+                network: &network,
+                fitness: i as f64,
+                raw_fitness: i as f64,
+                complexity: 1.0,
+                age: 5 - i,
+            })
+            .collect::<Vec<_>>();
+        let exploration = Exploration::builder().population(3).build();
+        let mut rng = rand::thread_rng();
+
+        let selected = Tournament { size: 6 }.select(&candidates, 3, &exploration, &mut rng);
+        assert_eq!(selected, vec![0, 1, 2]);
+
+        let truncation = Truncation {
+            elites: 1,
+            fraction: 0.0,
+        };
+        let selected = truncation.select(&candidates, 3, &exploration, &mut rng);
+        assert_eq!(selected[0], 0);
+        assert_eq!(selected.len(), 3);
+        assert!(selected[1..].iter().all(|i| (1..3).contains(i)));
+
+        let age_layered = AgeLayered {
+            protection: 2,
+            reserved: 0.34,
+        };
+        let selected = age_layered.select(&candidates, 3, &exploration, &mut rng);
+        assert_eq!(selected, vec![4, 0, 1]);
+    }
+
+    #[test]
+    fn test_parsimony_coefficient() {
+        let covariant = Parsimony::builder()
+            .pressure(ParsimonyPressure::Covariant)
+            .build();
+        // Larger networks are fitter, so they are penalized
+        let coefficient = covariant.coefficient(&[(3.0, 1.0), (2.0, 2.0), (1.0, 3.0)]);
+        assert!((coefficient - 1.0).abs() < 1e-12);
+        // Larger networks are worse, so they are not rewarded
+        assert_eq!(covariant.coefficient(&[(1.0, 1.0), (3.0, 3.0)]), 0.0);
+        assert_eq!(covariant.coefficient(&[(1.0, 2.0), (3.0, 2.0)]), 0.0);
+
+        let linear = Parsimony::builder()
+            .pressure(ParsimonyPressure::Linear(0.5))
+            .build();
+        assert_eq!(linear.coefficient(&[(1.0, 2.0)]), 0.5);
+    }
+
+    #[test]
+    fn test_default_decisions() {
+        let network = network(0);
+        let candidates = (0..5)
+            .map(|i| Candidate {
+                id: i,
+                network: &network,
+                fitness: i as f64,
+                raw_fitness: i as f64,
+                complexity: 1.0,
+                age: 0,
+            })
+            .collect::<Vec<_>>();
+        let exploration = Exploration::builder().build();
+
+        let strategy = Tournament { size: 5 };
+        let (selected, decisions) =
+            strategy.select_with_decisions(&candidates, 2, &exploration, &mut rand::thread_rng());
+        assert_eq!(selected, vec![0, 1]);
+        let exclusions = decisions.iter().map(|d| d.exclusion).collect::<Vec<_>>();
+        let strategy = Some(Exclusion::Strategy);
+        assert_eq!(exclusions, vec![None, None, strategy, strategy, strategy]);
+        assert!(decisions.iter().all(|d| d.duplicate_group.is_none()));
+    }
+
+    #[test]
+    fn test_next_generation() {
+        let mut rng = rand::thread_rng();
+        let evaluated = || vec![(genome(0), 3.0), (genome(1), 1.0), (genome(2), 2.0)];
+
+        let exploration = Exploration::builder()
+            .population(2)
+            .selection(Selection::builder().report().build())
+            .build();
+        let mut speciator = Speciator::default();
+        let (generation, coefficient, records) = next_generation(
+            individuals(evaluated()),
+            0,
+            &exploration,
+            &mut speciator,
+            &mut rng,
+        );
+        let ids = generation
+            .individuals
+            .iter()
+            .map(|i| i.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(coefficient, 0.0);
+        let ranks = records.iter().map(|r| r.rank).collect::<Vec<_>>();
+        assert_eq!(ranks, vec![2, 0, 1]);
+        let selected = records.iter().map(|r| r.selected()).collect::<Vec<_>>();
+        assert_eq!(selected, vec![false, true, true]);
+        assert_eq!(records[0].decision.exclusion, Some(Exclusion::Outranked));
+
+        // Without reporting, the same individuals are selected but nothing is recorded
+        let exploration = Exploration::builder().population(2).build();
+        let (generation, _, records) = next_generation(
+            individuals(evaluated()),
+            0,
+            &exploration,
+            &mut speciator,
+            &mut rng,
+        );
+        assert_eq!(generation.individuals.len(), 2);
+        assert!(records.is_empty());
+
+        // The species of the worse network gets no slots
+        let other = vec![
+            Neuron::new(NeuronId::new(0), 2, 1.0).into(),
+            Input::new(InputId::new(1), 1.0).into(),
+            Bias::new(1.0).into(),
+        ];
+        let exploration = Exploration::builder()
+            .population(1)
+            .speciation(Speciation::builder().threshold(0.5).build())
+            .selection(Selection::builder().report().build())
+            .build();
+        let evaluated = vec![(genome(0), 1.0), (other, 3.0)];
+        let (generation, _, records) = next_generation(
+            individuals(evaluated),
+            0,
+            &exploration,
+            &mut speciator,
+            &mut rng,
+        );
+        assert_eq!(generation.individuals[0].id, 0);
+        assert_eq!(records[0].decision.species, Some(0));
+        assert_eq!(records[1].decision.species, Some(1));
+        assert_eq!(records[1].decision.exclusion, Some(Exclusion::NoSlots));
+    }
+}
@@ -0,0 +1,466 @@
+//! Division of the population into species of structurally compatible networks.
+//!
+//! When [`Speciation`] is enabled, every evaluated individual is assigned to the first species
+//! whose representative is within the compatibility threshold of it (see
+//! [`compatibility_distance`]), and new species are created for individuals that fit none.
+//! Individuals share their fitness with the other members of their species, and each species is
+//! allocated population slots in proportion to the shared fitness of its members. The selection
+//! strategy then chooses the survivors within each species. With uniform reproduction, every
+//! survivor produces the same number of offspring, so this also allocates offspring to species by
+//! their shared fitness.
+
+use cge::gene::NeuronId;
+use rand::RngCore;
+
+use std::collections::HashMap;
+
+use crate::cge_utils::Network;
+use crate::genealogy::Source;
+use crate::options::{Exploration, Speciation};
+use crate::selection::{Candidate, Decision, Exclusion};
+
+/// A species of structurally compatible networks.
+#[derive(Clone, Debug)]
+pub struct Species {
+    /// The unique ID of the species within the run.
+    pub id: usize,
+    /// The best network of the species in the latest generation, which new networks are compared
+    /// to.
+    pub representative: Network,
+    /// The best (penalized) fitness any member of the species has had.
+    pub best_fitness: f64,
+    /// The generation in which `best_fitness` was last improved.
+    pub last_improvement: usize,
+}
+
+/// Keeps track of the species across generations.
+#[derive(Default)]
+pub(crate) struct Speciator {
+    species: Vec<Species>,
+    next_id: usize,
+}
+
+impl Speciator {
+    /// Returns the current species.
+    pub(crate) fn species(&self) -> &[Species] {
+        &self.species
+    }
+
+    /// Assigns the candidates of generation `g` to species, removes stagnant species and selects
+    /// the survivors within each remaining species with the selection strategy. Returns the indices
+    /// of the selected candidates and the decision about each candidate. Unless `Selection::report`
+    /// is set, the decisions only contain the species and the exclusions made by speciation.
+    pub(crate) fn select(
+        &mut self,
+        candidates: &[Candidate],
+        g: usize,
+        exploration: &Exploration,
+        options: &Speciation,
+        rng: &mut dyn RngCore,
+    ) -> (Vec<usize>, Vec<Decision>) {
+        if candidates.is_empty() {
+            return (Vec::new(), Vec::new());
+        }
+
+        // Assign each candidate to the first compatible species, or to a new one
+        let mut members: Vec<Vec<usize>> = vec![Vec::new(); self.species.len()];
+        for (i, candidate) in candidates.iter().enumerate() {
+            let compatible = self.species.iter().position(|species| {
+                compatibility_distance(candidate.network, &species.representative, options)
+                    < options.threshold
+            });
+
+            match compatible {
+                Some(s) => members[s].push(i),
+                None => {
+                    self.species.push(Species {
+                        id: self.next_id,
+                        representative: candidate.network.clone(),
+                        best_fitness: f64::INFINITY,
+                        last_improvement: g,
+                    });
+                    self.next_id += 1;
+                    members.push(vec![i]);
+                }
+            }
+        }
+
+        let mut decisions = vec![Decision::default(); candidates.len()];
+        for (species, m) in self.species.iter().zip(&members) {
+            for i in m {
+                decisions[*i].species = Some(species.id);
+            }
+        }
+
+        // Update the species, and remove empty and stagnant ones (except the best one)
+        let best = (0..candidates.len())
+            .min_by(|a, b| candidates[*a].fitness.total_cmp(&candidates[*b].fitness))
+            .unwrap();
+        let mut species = Vec::new();
+        let mut species_members = Vec::new();
+        for (mut s, mut m) in self.species.drain(..).zip(members) {
+            if m.is_empty() {
+                continue;
+            }
+
+            m.sort_by(|a, b| candidates[*a].fitness.total_cmp(&candidates[*b].fitness));
+            let leader = &candidates[m[0]];
+            s.representative = leader.network.clone();
+            if leader.fitness < s.best_fitness {
+                s.best_fitness = leader.fitness;
+                s.last_improvement = g;
+            }
+
+            if g - s.last_improvement < options.stagnation || m.contains(&best) {
+                species.push(s);
+                species_members.push(m);
+            } else {
+                for i in m {
+                    decisions[i].exclusion = Some(Exclusion::Stagnation);
+                }
+            }
+        }
+        self.species = species;
+
+        // Share the fitness within each species. Fitness is turned into a non-negative score to
+        // maximize first, so that it can be divided by the species size.
+        let worst = species_members
+            .iter()
+            .flatten()
+            .map(|i| candidates[*i].fitness)
+            .filter(|f| f.is_finite())
+            .fold(f64::NEG_INFINITY, f64::max);
+        let score = |i: &usize| {
+            let fitness = candidates[*i].fitness;
+            if fitness.is_finite() {
+                worst - fitness
+            } else {
+                0.0
+            }
+        };
+        let mut shares = species_members
+            .iter()
+            .map(|m| m.iter().map(score).sum::<f64>() / m.len() as f64)
+            .collect::<Vec<_>>();
+        if shares.iter().sum::<f64>() <= 0.0 {
+            // All candidates are equally fit, so each one gets the same share
+            shares = species_members.iter().map(|m| m.len() as f64).collect();
+        }
+
+        let mut slots = allocate(exploration.population, &shares, &species_members);
+
+        // The species with the best individual always survives
+        let best_species = species_members.iter().position(|m| m.contains(&best));
+        if let Some(s) = best_species.filter(|s| slots[*s] == 0) {
+            let largest = (0..slots.len()).max_by_key(|s| slots[*s]).unwrap();
+            slots[largest] = slots[largest].saturating_sub(1);
+            slots[s] = 1;
+        }
+
+        // Species without slots die out
+        let mut survived = slots.iter().map(|slots| *slots > 0);
+        self.species.retain(|_| survived.next().unwrap());
+
+        // Select the survivors within each species
+        let mut selected = Vec::with_capacity(exploration.population);
+        for (m, slots) in species_members.iter().zip(slots) {
+            if slots == 0 {
+                for i in m {
+                    decisions[*i].exclusion = Some(Exclusion::NoSlots);
+                }
+                continue;
+            }
+
+            let species_candidates = m.iter().map(|i| candidates[*i]).collect::<Vec<_>>();
+            let strategy = &exploration.selection.strategy;
+            let (indices, species_decisions) = if exploration.selection.report {
+                strategy.select_with_decisions(&species_candidates, slots, exploration, rng)
+            } else {
+                let indices = strategy.select(&species_candidates, slots, exploration, rng);
+                (indices, Vec::new())
+            };
+
+            for (i, decision) in m.iter().zip(species_decisions) {
+                decisions[*i] = Decision {
+                    species: decisions[*i].species,
+                    ..decision
+                };
+            }
+            selected.extend(indices.into_iter().filter_map(|i| m.get(i).copied()));
+        }
+
+        (selected, decisions)
+    }
+}
+
+/// Divides `population` slots among the species in proportion to their shares, with the largest
+/// remainders rounded up. No species gets more slots than it has members, and slots a species
+/// cannot take are given to the others in the order of their shares.
+fn allocate(population: usize, shares: &[f64], members: &[Vec<usize>]) -> Vec<usize> {
+    let total = shares.iter().sum::<f64>();
+    let exact = shares
+        .iter()
+        .map(|share| population as f64 * share / total)
+        .collect::<Vec<_>>();
+    let mut slots = exact
+        .iter()
+        .zip(members)
+        .map(|(exact, m)| (exact.floor() as usize).min(m.len()))
+        .collect::<Vec<_>>();
+
+    // Round up the species with the largest remainders
+    let mut order = (0..shares.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| {
+        let remainder = |s: usize| exact[s] - exact[s].floor();
+        remainder(*b).total_cmp(&remainder(*a))
+    });
+    let mut remaining = population.saturating_sub(slots.iter().sum());
+    for s in order {
+        if remaining > 0 && slots[s] < members[s].len() {
+            slots[s] += 1;
+            remaining -= 1;
+        }
+    }
+
+    // Give the slots that could not be taken to the species with the highest shares
+    let mut order = (0..shares.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| shares[*b].total_cmp(&shares[*a]));
+    for s in order {
+        let extra = remaining.min(members[s].len() - slots[s]);
+        slots[s] += extra;
+        remaining -= extra;
+    }
+
+    slots
+}
+
+/// Returns the compatibility distance between two networks according to the options (see
+/// [`Speciation`]).
+pub fn compatibility_distance(a: &Network, b: &Network, options: &Speciation) -> f64 {
+    let (a_neurons, a_connections) = genes(a);
+    let (b_neurons, b_connections) = genes(b);
+
+    let mut weight_difference = 0.0;
+    let mut matching = 0;
+
+    let mut differing_neurons = 0;
+    for (id, weight) in &a_neurons {
+        match b_neurons.get(id) {
+            Some(other) => {
+                weight_difference += (weight - other).abs();
+                matching += 1;
+            }
+            None => differing_neurons += 1,
+        }
+    }
+    differing_neurons += b_neurons
+        .keys()
+        .filter(|id| !a_neurons.contains_key(id))
+        .count();
+
+    // Connections with the same key are matched in genome order
+    let mut differing_connections = 0;
+    for (key, weights) in &a_connections {
+        let others = b_connections.get(key).map_or(&[][..], Vec::as_slice);
+        for (weight, other) in weights.iter().zip(others) {
+            weight_difference += (weight - other).abs();
+            matching += 1;
+        }
+        differing_connections += weights.len().abs_diff(others.len());
+    }
+    differing_connections += b_connections
+        .iter()
+        .filter(|(key, _)| !a_connections.contains_key(key))
+        .map(|(_, weights)| weights.len())
+        .sum::<usize>();
+
+    let length = a.len().max(b.len()).max(1) as f64;
+    let mean_weight_difference = if matching > 0 {
+        weight_difference / matching as f64
+    } else {
+        0.0
+    };
+
+    options.connections * differing_connections as f64 / length
+        + options.neurons * differing_neurons as f64 / length
+        + options.weights * mean_weight_difference
+}
+
+/// Connection genes keyed by the neuron they belong to and where they connect from.
+type Connections = HashMap<(NeuronId, Source), Vec<f64>>;
+
+/// Returns the weights of the neurons of the network by ID, and the weights of its connections in
+/// genome order.
+fn genes(network: &Network) -> (HashMap<NeuronId, f64>, Connections) {
+    let mut neurons = HashMap::new();
+    let mut connections: Connections = HashMap::new();
+
+    for (gene, parent) in network.genome().iter().zip(network.parents()) {
+        match (gene.as_neuron(), parent) {
+            (Some(neuron), _) => {
+                neurons.insert(neuron.id(), gene.weight());
+            }
+            (None, Some(parent)) => {
+                connections
+                    .entry((*parent, Source::of(gene)))
+                    .or_default()
+                    .push(gene.weight());
+            }
+            (None, None) => {}
+        }
+    }
+
+    (neurons, connections)
+}
+
+#[cfg(test)]
+mod tests {
+    use cge::gene::{Bias, Input, InputId, Neuron};
+    use cge::Activation;
+    use rand::thread_rng;
+
+    use super::*;
+    use crate::options::Selection;
+    use crate::selection::Truncation;
+
+    /// Returns two networks that belong to different species with the options of `options`.
+    fn networks() -> [Network; 2] {
+        let a = vec![
+            Neuron::new(NeuronId::new(0), 1, 1.0).into(),
+            Input::new(InputId::new(0), 1.0).into(),
+        ];
+        let b = vec![
+            Neuron::new(NeuronId::new(0), 2, 1.0).into(),
+            Input::new(InputId::new(1), 1.0).into(),
+            Bias::new(1.0).into(),
+        ];
+        [a, b].map(|genome| Network::new(genome, Activation::Tanh).unwrap())
+    }
+
+    fn candidates<'a>(members: &[(&'a Network, f64)]) -> Vec<Candidate<'a>> {
+        members
+            .iter()
+            .enumerate()
+            .map(|(id, &(network, fitness))| Candidate {
+                id,
+                network,
+                fitness,
+                raw_fitness: fitness,
+                complexity: network.len() as f64,
+                age: 0,
+            })
+            .collect()
+    }
+
+    /// Speciation options and exploration options that select the best candidates of each species.
+    fn options(population: usize) -> (Speciation, Exploration) {
+        let speciation = Speciation::builder().threshold(0.5).stagnation(2).build();
+        let selection = Selection::builder()
+            .strategy(Truncation {
+                elites: population,
+                fraction: 1.0,
+            })
+            .report()
+            .build();
+        let exploration = Exploration::builder()
+            .population(population)
+            .selection(selection)
+            .build();
+        (speciation, exploration)
+    }
+
+    #[test]
+    fn test_species_slots() {
+        let [a, b] = networks();
+        let (speciation, exploration) = options(2);
+        let mut speciator = Speciator::default();
+        let mut rng = thread_rng();
+
+        // The first species has the higher mean shared fitness and gets both slots
+        let candidates = candidates(&[(&a, 1.0), (&a, 2.0), (&b, 3.0), (&b, 4.0)]);
+        let (selected, decisions) =
+            speciator.select(&candidates, 0, &exploration, &speciation, &mut rng);
+        assert_eq!(selected, vec![0, 1]);
+        let species = decisions.iter().map(|d| d.species).collect::<Vec<_>>();
+        assert_eq!(species, vec![Some(0), Some(0), Some(1), Some(1)]);
+        assert_eq!(decisions[1].exclusion, None);
+        assert_eq!(decisions[2].exclusion, Some(Exclusion::NoSlots));
+        assert_eq!(decisions[3].exclusion, Some(Exclusion::NoSlots));
+
+        // The second species died out, so the same network founds a new one
+        assert_eq!(speciator.species().len(), 1);
+        let (_, decisions) = speciator.select(&candidates, 1, &exploration, &speciation, &mut rng);
+        assert_eq!(decisions[2].species, Some(2));
+    }
+
+    #[test]
+    fn test_best_species_keeps_a_slot() {
+        let [a, b] = networks();
+        let (speciation, exploration) = options(1);
+        let mut speciator = Speciator::default();
+
+        // The first species has the best candidate, but a lower mean shared fitness
+        let candidates = candidates(&[(&a, 1.0), (&a, 10.0), (&a, 10.0), (&b, 2.0), (&b, 2.0)]);
+        let (selected, decisions) =
+            speciator.select(&candidates, 0, &exploration, &speciation, &mut thread_rng());
+        assert_eq!(selected, vec![0]);
+        assert_eq!(decisions[3].exclusion, Some(Exclusion::NoSlots));
+        assert_eq!(speciator.species().len(), 1);
+        assert_eq!(speciator.species()[0].id, 0);
+    }
+
+    #[test]
+    fn test_stagnant_species_are_removed() {
+        let [a, b] = networks();
+        let (speciation, exploration) = options(2);
+        let mut speciator = Speciator::default();
+        let mut rng = thread_rng();
+
+        // Neither species improves after the first generation
+        let candidates = candidates(&[(&a, 1.0), (&b, 2.0)]);
+        for g in 0..2 {
+            let (selected, _) =
+                speciator.select(&candidates, g, &exploration, &speciation, &mut rng);
+            assert_eq!(selected, vec![0, 1]);
+        }
+
+        // Both species are stagnant now, but the one with the best candidate is kept
+        let (selected, decisions) =
+            speciator.select(&candidates, 2, &exploration, &speciation, &mut rng);
+        assert_eq!(selected, vec![0]);
+        assert_eq!(decisions[0].exclusion, None);
+        assert_eq!(decisions[1].exclusion, Some(Exclusion::Stagnation));
+        assert_eq!(speciator.species().len(), 1);
+        assert_eq!(speciator.species()[0].last_improvement, 0);
+    }
+
+    #[test]
+    fn test_compatibility_distance() {
+        let options = Speciation::builder().build();
+        let a = vec![
+            Neuron::new(NeuronId::new(0), 2, 1.0).into(),
+            Input::new(InputId::new(0), 1.0).into(),
+            Input::new(InputId::new(1), 1.0).into(),
+        ];
+        let b = vec![
+            Neuron::new(NeuronId::new(0), 3, 1.0).into(),
+            Input::new(InputId::new(1), 1.5).into(),
+            Input::new(InputId::new(0), 1.0).into(),
+            Bias::new(1.0).into(),
+        ];
+        let [a, b] = [a, b].map(|genome| Network::new(genome, Activation::Tanh).unwrap());
+
+        assert_eq!(compatibility_distance(&a, &a, &options), 0.0);
+        // One differing connection out of four genes, and a mean weight difference of 0.5 / 3
+        let expected = 1.0 / 4.0 + 0.4 * 0.5 / 3.0;
+        assert!((compatibility_distance(&a, &b, &options) - expected).abs() < 1e-12);
+        assert!((compatibility_distance(&b, &a, &options) - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_allocate() {
+        let members = vec![vec![0, 1, 2], vec![3], vec![4, 5, 6, 7]];
+        assert_eq!(allocate(6, &[3.0, 2.0, 1.0], &members), vec![3, 1, 2]);
+        assert_eq!(allocate(4, &[1.0, 1.0, 2.0], &members), vec![1, 1, 2]);
+    }
+}
@@ -0,0 +1,46 @@
+//! Statistics collected while running the algorithm.
+
+use crate::genealogy::Genealogy;
+use crate::mutation_probabilities::MutationType;
+use crate::selection::SelectionRecord;
+
+/// Information about a single EANT2 generation.
+#[derive(Clone, Debug)]
+pub struct GenerationStatistics {
+    /// The generation number, starting at one.
+    pub generation: usize,
+    /// The raw fitness of the best individual in the population after selection.
+    pub best_fitness: f64,
+    /// The ID of the individual with the best fitness, which can be used to look up its lineage in
+    /// the [`Genealogy`].
+    pub best_id: usize,
+    /// The complexity of the network with the best fitness, according to
+    /// `Exploration::complexity`.
+    pub best_complexity: f64,
+    /// The fitness of the best individual including the parsimony penalty. The best individual is
+    /// the one with the lowest penalized fitness. Without parsimony pressure, this is the same as
+    /// `best_fitness`.
+    pub best_penalized_fitness: f64,
+    /// The coefficient of the parsimony penalty used in the selection of this generation, or zero
+    /// without parsimony pressure.
+    pub parsimony_coefficient: f64,
+    /// The number of individuals that survived selection.
+    pub population: usize,
+    /// The number of species after selection, or zero if speciation is disabled.
+    pub species: usize,
+    /// The probability of each mutation type that was used to produce the offspring of this
+    /// generation. These only change during the run if adaptive mutation is enabled.
+    pub mutation_probabilities: [(MutationType, f64); 4],
+    /// The decision of selection about each candidate of this generation, if `Selection::report`
+    /// is set. Otherwise, this is empty.
+    pub selection: Vec<SelectionRecord>,
+}
+
+/// Statistics about a complete EANT2 run.
+#[derive(Clone, Debug, Default)]
+pub struct Statistics {
+    /// The statistics of each generation in order.
+    pub generations: Vec<GenerationStatistics>,
+    /// The origins of all individuals that were evaluated during the run.
+    pub genealogy: Genealogy,
+}
@@ -0,0 +1,273 @@
+//! Generators for the topologies of the initial networks.
+//!
+//! The topology of each network in the initial population is created by an [`InitialTopology`],
+//! which is set with the `initial_topology` option of [`EANT2`][crate::eant2::EANT2]. Custom
+//! generators can be used by implementing the trait.
+
+use cge::gene::{Bias, ForwardJumper, Gene, Input, InputId, Neuron, NeuronId};
+use rand::seq::IteratorRandom;
+use rand::{Rng, RngCore};
+
+use crate::cge_utils::INITIAL_WEIGHT_VALUE;
+use crate::options::Constraints;
+
+/// A generator for the topologies of the initial networks.
+pub trait InitialTopology: Send + Sync {
+    /// Returns the genome of a new initial network with `inputs` network inputs and `outputs`
+    /// output neurons.
+    ///
+    /// The genome must be valid, must have exactly `outputs` output neurons and may not use any
+    /// input IDs of `inputs` or greater. Its weights are replaced according to the configured
+    /// weight initialization strategy. Input connections forbidden by the connection mask in
+    /// `constraints` are removed afterwards, but generators may also avoid them themselves.
+    fn genome(
+        &self,
+        inputs: usize,
+        outputs: usize,
+        constraints: &Constraints,
+        rng: &mut dyn RngCore,
+    ) -> Vec<Gene<f64>>;
+}
+
+/// Each output neuron is connected to about 50% of the network inputs, and every network input
+/// that is left unconnected is connected to a random output neuron. This is the topology used in
+/// the original paper.
+#[derive(Clone, Copy, Debug)]
+pub struct Minimal;
+
+impl InitialTopology for Minimal {
+    fn genome(
+        &self,
+        inputs: usize,
+        outputs: usize,
+        constraints: &Constraints,
+        rng: &mut dyn RngCore,
+    ) -> Vec<Gene<f64>> {
+        let mut subgenomes = random_subgenomes(inputs, outputs, constraints, rng, 0.5);
+
+        // Ensure that all network inputs are connected to a neuron
+        for input in 0..inputs {
+            let connected = subgenomes
+                .iter()
+                .flatten()
+                .any(|g| matches!(g, Gene::Input(i) if i.id().as_usize() == input));
+            if connected {
+                continue;
+            }
+
+            // Add unconnected network inputs to a random output neuron that allows them
+            let output = (0..outputs)
+                .filter(|output| allows(constraints, input, *output))
+                .choose(rng);
+
+            if let Some(output) = output {
+                subgenomes[output].push(input_gene(input));
+            }
+        }
+
+        assemble(subgenomes)
+    }
+}
+
+/// Each output neuron is connected to every network input.
+#[derive(Clone, Copy, Debug)]
+pub struct FullyConnected;
+
+impl InitialTopology for FullyConnected {
+    fn genome(
+        &self,
+        inputs: usize,
+        outputs: usize,
+        constraints: &Constraints,
+        rng: &mut dyn RngCore,
+    ) -> Vec<Gene<f64>> {
+        assemble(random_subgenomes(inputs, outputs, constraints, rng, 1.0))
+    }
+}
+
+/// Each output neuron is connected to each network input with the given probability, and to at
+/// least one network input. Unlike [`Minimal`], network inputs may be left unconnected, which
+/// keeps the initial networks small for problems with many inputs.
+#[derive(Clone, Copy, Debug)]
+pub struct Sparse {
+    pub probability: f64,
+}
+
+impl InitialTopology for Sparse {
+    fn genome(
+        &self,
+        inputs: usize,
+        outputs: usize,
+        constraints: &Constraints,
+        rng: &mut dyn RngCore,
+    ) -> Vec<Gene<f64>> {
+        assemble(random_subgenomes(
+            inputs,
+            outputs,
+            constraints,
+            rng,
+            self.probability,
+        ))
+    }
+}
+
+/// A hidden layer of the given number of neurons, each connected to every network input and to
+/// every output neuron. Because a neuron only has a single parent in CGE, the hidden neurons are
+/// distributed among the output neurons, which are connected to the remaining hidden neurons with
+/// forward jumpers.
+///
+/// Hidden neurons feed into every output, so they are only connected to the network inputs that
+/// the connection mask allows for all outputs. If there are no hidden neurons, this is the same as
+/// [`FullyConnected`].
+#[derive(Clone, Copy, Debug)]
+pub struct HiddenLayer {
+    pub neurons: usize,
+}
+
+impl InitialTopology for HiddenLayer {
+    fn genome(
+        &self,
+        inputs: usize,
+        outputs: usize,
+        constraints: &Constraints,
+        rng: &mut dyn RngCore,
+    ) -> Vec<Gene<f64>> {
+        if self.neurons == 0 || outputs == 0 {
+            return FullyConnected.genome(inputs, outputs, constraints, rng);
+        }
+
+        let hidden_inputs = (0..inputs)
+            .filter(|input| (0..outputs).all(|output| allows(constraints, *input, output)))
+            .collect::<Vec<_>>();
+
+        let mut subgenomes = vec![Vec::new(); outputs];
+        for hidden in 0..self.neurons {
+            let id = NeuronId::new(outputs + hidden);
+            let owner = hidden % outputs;
+
+            let mut hidden_subgenome = hidden_inputs
+                .iter()
+                .map(|input| input_gene(*input))
+                .collect::<Vec<_>>();
+            if hidden_subgenome.is_empty() {
+                hidden_subgenome.push(Bias::new(INITIAL_WEIGHT_VALUE).into());
+            }
+
+            subgenomes[owner]
+                .push(Neuron::new(id, hidden_subgenome.len(), INITIAL_WEIGHT_VALUE).into());
+            subgenomes[owner].extend(hidden_subgenome);
+
+            // Connect the hidden neuron to all other outputs
+            for (output, subgenome) in subgenomes.iter_mut().enumerate() {
+                if output != owner {
+                    subgenome.push(ForwardJumper::new(id, INITIAL_WEIGHT_VALUE).into());
+                }
+            }
+        }
+
+        // Only the direct children of the output neurons count as their inputs
+        subgenomes
+            .into_iter()
+            .enumerate()
+            .flat_map(|(output, subgenome)| {
+                let num_inputs = subgenome
+                    .iter()
+                    .filter(|g| !matches!(g, Gene::Input(_) | Gene::Bias(_)))
+                    .count();
+                let neuron = Neuron::new(NeuronId::new(output), num_inputs, INITIAL_WEIGHT_VALUE);
+                std::iter::once(neuron.into()).chain(subgenome)
+            })
+            .collect()
+    }
+}
+
+/// Returns whether the connection mask in `constraints` allows the network input to reach the
+/// output.
+fn allows(constraints: &Constraints, input: usize, output: usize) -> bool {
+    constraints
+        .connections
+        .as_ref()
+        .is_none_or(|mask| mask.allows(input, output))
+}
+
+fn input_gene(input: usize) -> Gene<f64> {
+    Input::new(InputId::new(input), INITIAL_WEIGHT_VALUE).into()
+}
+
+/// Returns the input genes of each output neuron, where each allowed network input is connected
+/// with the given probability. Output neurons that are left without inputs are connected to a
+/// random allowed network input, or to a bias if there is none.
+fn random_subgenomes(
+    inputs: usize,
+    outputs: usize,
+    constraints: &Constraints,
+    rng: &mut dyn RngCore,
+    probability: f64,
+) -> Vec<Vec<Gene<f64>>> {
+    (0..outputs)
+        .map(|output| {
+            let mut subgenome = (0..inputs)
+                .filter(|input| allows(constraints, *input, output))
+                .filter(|_| rng.gen_bool(probability.clamp(0.0, 1.0)))
+                .map(input_gene)
+                .collect::<Vec<_>>();
+
+            if subgenome.is_empty() {
+                let input = (0..inputs)
+                    .filter(|input| allows(constraints, *input, output))
+                    .choose(rng);
+
+                match input {
+                    Some(input) => subgenome.push(input_gene(input)),
+                    None => subgenome.push(Bias::new(INITIAL_WEIGHT_VALUE).into()),
+                }
+            }
+
+            subgenome
+        })
+        .collect()
+}
+
+/// Adds a root neuron to each subgenome of non-neuron genes and concatenates them.
+fn assemble(subgenomes: Vec<Vec<Gene<f64>>>) -> Vec<Gene<f64>> {
+    subgenomes
+        .into_iter()
+        .enumerate()
+        .flat_map(|(output, subgenome)| {
+            let neuron = Neuron::new(NeuronId::new(output), subgenome.len(), INITIAL_WEIGHT_VALUE);
+            std::iter::once(neuron.into()).chain(subgenome)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cge_utils::Network;
+    use crate::constraints::Checker;
+    use crate::options::ConnectionMask;
+
+    #[test]
+    fn test_builtin_topologies_are_valid() {
+        let mut rng = rand::thread_rng();
+        let mask = ConnectionMask::allow_all(5, 3).forbid(0, 1).forbid(2, 1);
+        let constraints = Constraints::builder().connections(mask).build();
+        let topologies: [&dyn InitialTopology; 5] = [
+            &Minimal,
+            &FullyConnected,
+            &Sparse { probability: 0.1 },
+            &HiddenLayer { neurons: 0 },
+            &HiddenLayer { neurons: 4 },
+        ];
+
+        for topology in topologies {
+            for constraints in [&Constraints::default(), &constraints] {
+                let genome = topology.genome(5, 3, constraints, &mut rng);
+                let network = Network::new(genome, cge::Activation::Tanh).unwrap();
+                assert_eq!(network.num_outputs(), 3);
+                assert!(network.num_inputs() <= 5);
+                assert!(Checker::new(constraints, &network).is_satisfied());
+            }
+        }
+    }
+}
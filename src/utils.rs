@@ -1,9 +1,12 @@
 use cge::gene::{Gene, NeuronId};
-use cmaes::{DVector, ObjectiveFunction};
+use cmaes::ObjectiveFunction;
 
+use std::iter;
 use std::sync::Arc;
 
 use crate::cge_utils::{Network, NetworkView};
+use crate::cmaes_utils::SearchDistribution;
+use crate::genealogy::MutationRecord;
 use crate::FitnessFunction;
 
 // Stores additional information about a neural network, useful for mutation operators and
@@ -22,6 +25,28 @@ pub struct Individual<T: FitnessFunction + Clone> {
     pub object: Arc<T>,
     pub duplicates: usize,
     pub similar: usize,
+    /// The unique ID of this individual within the run
+    pub id: usize,
+    /// The ID of the individual this one was produced from
+    pub parent_id: Option<usize>,
+    /// The ID of the individual the parent was combined with by crossover
+    pub mate_id: Option<usize>,
+    /// The generation this individual was produced in
+    pub birth_generation: usize,
+    /// The mutations applied to the parent to produce this individual, empty if it was carried
+    /// over unchanged
+    pub mutations: Vec<MutationRecord>,
+    /// The fitness of the parent this individual was produced from
+    pub parent_fitness: Option<f64>,
+    /// The number of generations in a row in which the re-optimization of this individual was
+    /// skipped
+    pub skipped: usize,
+    /// How much the last re-optimization of this individual improved its fitness, if it was
+    /// re-optimized before
+    pub improvement: Option<f64>,
+    /// The final search distribution of the last optimization if warm starting is enabled, kept in
+    /// sync with the genome like `ages`
+    pub search: Option<SearchDistribution>,
 }
 
 impl<T: FitnessFunction + Clone> Individual<T> {
@@ -36,29 +61,93 @@ impl<T: FitnessFunction + Clone> Individual<T> {
             object,
             duplicates: 0,
             similar: 0,
+            id: 0,
+            parent_id: None,
+            mate_id: None,
+            birth_generation: 0,
+            mutations: Vec::new(),
+            parent_fitness: None,
+            skipped: 0,
+            improvement: None,
+            search: None,
+        }
+    }
+
+    /// Returns a copy of this `Individual` to be modified into an offspring. Its ID and birth
+    /// generation must be assigned by the caller.
+    pub fn offspring(&self) -> Individual<T> {
+        let mut offspring = self.clone();
+        offspring.parent_id = Some(self.id);
+        offspring.mate_id = None;
+        offspring.mutations.clear();
+        offspring.parent_fitness = self.fitness;
+        offspring.skipped = 0;
+        offspring.improvement = None;
+        offspring
+    }
+
+    /// Inserts age counters and search distribution entries for `count` new genes at `index` of
+    /// the genome.
+    pub fn insert_genes(&mut self, index: usize, count: usize) {
+        self.ages.splice(index..index, iter::repeat_n(0, count));
+        if let Some(search) = &mut self.search {
+            search
+                .genes
+                .splice(index..index, iter::repeat_n(None, count));
+        }
+    }
+
+    /// Removes the age counter and search distribution entry of the gene at `index` of the genome.
+    pub fn remove_gene(&mut self, index: usize) {
+        self.ages.remove(index);
+        if let Some(search) = &mut self.search {
+            search.genes.remove(index);
         }
     }
 
     /// Evaluates the `Individual` on the given set of weight parameters.
-    fn eval(&mut self, x: &DVector<f64>) -> f64 {
-        self.network.set_weights(x.as_slice()).unwrap();
+    pub fn eval(&mut self, x: &[f64]) -> f64 {
+        self.network.set_weights(x).unwrap();
         let view = NetworkView::new(&mut self.network);
         self.object.fitness(view)
     }
 }
 
+/// A fitness function for tests that rates every network as optimal.
+#[cfg(test)]
+#[derive(Clone)]
+pub(crate) struct Zero;
+
+#[cfg(test)]
+impl FitnessFunction for Zero {
+    fn fitness(&self, _: NetworkView) -> f64 {
+        0.0
+    }
+}
+
+#[cfg(test)]
+impl Individual<Zero> {
+    /// Returns an unevaluated `Individual` for tests with the given number of inputs and a sigmoid
+    /// network with the given genome.
+    pub(crate) fn for_test(inputs: usize, genome: Vec<Gene<f64>>) -> Self {
+        let network = Network::new(genome, cge::Activation::Sigmoid).unwrap();
+        let outputs = network.num_outputs();
+        Individual::new(inputs, outputs, network, Arc::new(Zero))
+    }
+}
+
 // Implements the CMA-ES fitness function for Individual to make the library easier to use
 // Sets the parameters of the neural network, calls the EANT2 fitness function, and resets the
 // internal state
 impl<T: FitnessFunction + Clone> ObjectiveFunction for Individual<T> {
     fn evaluate(&mut self, x: &cmaes::DVector<f64>) -> f64 {
-        self.eval(x)
+        self.eval(x.as_slice())
     }
 }
 
-impl<'a, T: FitnessFunction + Clone> ObjectiveFunction for &'a mut Individual<T> {
+impl<T: FitnessFunction + Clone> ObjectiveFunction for &mut Individual<T> {
     fn evaluate(&mut self, x: &cmaes::DVector<f64>) -> f64 {
-        self.eval(x)
+        self.eval(x.as_slice())
     }
 }
 